@@ -0,0 +1,27 @@
+-- The schema the service started with, before it was managed by migrations. Databases created back
+-- then already have it, so nothing is done there.
+CREATE TABLE IF NOT EXISTS users (
+  id TEXT PRIMARY KEY,
+  username TEXT UNIQUE,
+  password TEXT,
+  email TEXT NOT NULL UNIQUE,
+  verification_token TEXT,
+  access_token TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  access_token_expire TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS devices (
+  id TEXT PRIMARY KEY,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  access_token TEXT NOT NULL UNIQUE,
+  device_name TEXT NOT NULL,
+  description TEXT,
+  status BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS connections (
+  id TEXT PRIMARY KEY,
+  user_id TEXT NOT NULL REFERENCES users(id),
+  device_id TEXT NOT NULL REFERENCES devices(id)
+);
@@ -0,0 +1,9 @@
+-- Track when the current access token was issued so it can be bounded by an absolute lifetime
+ALTER TABLE users ADD COLUMN access_token_created_at TIMESTAMP;
+
+-- Tokens issued before expiry was enforced get a fresh lifetime instead of being revoked
+UPDATE users
+SET
+  access_token_created_at = NOW() AT TIME ZONE 'UTC',
+  access_token_expire = COALESCE(access_token_expire, NOW() AT TIME ZONE 'UTC' + INTERVAL '14 days')
+WHERE access_token IS NOT NULL;
@@ -0,0 +1,30 @@
+-- Every login gets its own session instead of overwriting a single token on the user
+CREATE TABLE sessions (
+  id TEXT PRIMARY KEY,
+  user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  access_token TEXT NOT NULL UNIQUE,
+  created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'UTC'),
+  last_used_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'UTC'),
+  expire_at TIMESTAMP NOT NULL,
+  user_agent TEXT,
+  ip_address TEXT
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions(user_id);
+
+-- Keep the users that are currently logged in
+INSERT INTO sessions(id, user_id, access_token, created_at, last_used_at, expire_at)
+SELECT
+  substr(md5(random()::text || id), 1, 10),
+  id,
+  access_token,
+  COALESCE(access_token_created_at, NOW() AT TIME ZONE 'UTC'),
+  NOW() AT TIME ZONE 'UTC',
+  COALESCE(access_token_expire, NOW() AT TIME ZONE 'UTC' + INTERVAL '14 days')
+FROM users
+WHERE access_token IS NOT NULL;
+
+ALTER TABLE users
+  DROP COLUMN access_token,
+  DROP COLUMN access_token_expire,
+  DROP COLUMN access_token_created_at;
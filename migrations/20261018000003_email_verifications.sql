@@ -0,0 +1,13 @@
+-- Verification codes are bound to the email they were sent to, expire and count failed attempts
+CREATE TABLE email_verifications (
+  id TEXT PRIMARY KEY,
+  email TEXT NOT NULL UNIQUE,
+  token TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'UTC'),
+  expire_at TIMESTAMP NOT NULL,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  last_sent_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'UTC')
+);
+
+-- Pending codes can't be carried over since they were never bound to an expiry, users just request a new one
+ALTER TABLE users DROP COLUMN verification_token;
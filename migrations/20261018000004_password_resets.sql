@@ -0,0 +1,10 @@
+-- Single use tokens emailed to users who forgot their password
+CREATE TABLE password_resets (
+  id TEXT PRIMARY KEY,
+  user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  token TEXT NOT NULL UNIQUE,
+  created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'UTC'),
+  expire_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX password_resets_user_id_idx ON password_resets(user_id);
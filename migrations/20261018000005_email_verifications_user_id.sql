@@ -0,0 +1,2 @@
+-- Codes sent to confirm a new address for an existing account are bound to that account
+ALTER TABLE email_verifications ADD COLUMN user_id TEXT REFERENCES users(id) ON DELETE CASCADE;
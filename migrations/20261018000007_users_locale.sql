@@ -0,0 +1,2 @@
+-- The language emails are sent to the user in, e.g. "en" or "id"
+ALTER TABLE users ADD COLUMN locale TEXT;
@@ -0,0 +1,2 @@
+-- The HTML alternative of the plain text body, if the email has one
+ALTER TABLE email_outbox ADD COLUMN html_body TEXT;
@@ -0,0 +1,7 @@
+-- Short lived codes an unclaimed device shows, so whoever has it can claim it
+CREATE TABLE device_claims (
+  device_id TEXT PRIMARY KEY REFERENCES devices(id) ON DELETE CASCADE,
+  code TEXT NOT NULL UNIQUE,
+  created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'UTC'),
+  expire_at TIMESTAMP NOT NULL
+);
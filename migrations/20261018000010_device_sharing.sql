@@ -0,0 +1,20 @@
+-- What a user can do with a device: owner, controller or viewer
+ALTER TABLE connections ADD COLUMN role TEXT NOT NULL DEFAULT 'owner' CHECK (role IN ('owner', 'controller', 'viewer'));
+
+DELETE FROM connections a USING connections b WHERE a.user_id = b.user_id AND a.device_id = b.device_id AND a.id > b.id;
+CREATE UNIQUE INDEX connections_user_id_device_id_idx ON connections(user_id, device_id);
+CREATE INDEX connections_device_id_idx ON connections(device_id);
+
+-- Devices shared with an email, waiting for its user to accept
+CREATE TABLE device_invitations (
+  id TEXT PRIMARY KEY,
+  device_id TEXT NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  email TEXT NOT NULL,
+  role TEXT NOT NULL CHECK (role IN ('owner', 'controller', 'viewer')),
+  invited_by TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'UTC'),
+  expire_at TIMESTAMP NOT NULL,
+  UNIQUE (device_id, email)
+);
+
+CREATE INDEX device_invitations_email_idx ON device_invitations(email);
@@ -0,0 +1,4 @@
+-- Free form labels users can group and filter their devices by
+ALTER TABLE devices ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+CREATE INDEX devices_tags_idx ON devices USING GIN (tags);
+CREATE INDEX devices_created_at_id_idx ON devices(created_at, id);
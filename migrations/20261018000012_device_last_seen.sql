@@ -0,0 +1,2 @@
+-- When a device was last heard from, kept while it's connected and set when it disconnects
+ALTER TABLE devices ADD COLUMN last_seen_at TIMESTAMP;
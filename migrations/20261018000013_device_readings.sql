@@ -0,0 +1,15 @@
+-- Every reading a device has sent, so its history can be shown after the fact.
+-- Readings come in far more often than anything else, so they're keyed by a sequence instead of a token
+CREATE TABLE device_readings (
+  id BIGSERIAL PRIMARY KEY,
+  device_id TEXT NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  sensor TEXT NOT NULL,
+  numeric_value DOUBLE PRECISION,
+  text_value TEXT,
+  -- When the device says it took the reading, if it has sent one
+  device_time TIMESTAMP,
+  received_at TIMESTAMP NOT NULL,
+  CHECK (numeric_value IS NOT NULL OR text_value IS NOT NULL)
+);
+CREATE INDEX device_readings_device_id_sensor_received_at_idx ON device_readings(device_id, sensor, received_at);
+CREATE INDEX device_readings_device_id_received_at_idx ON device_readings(device_id, received_at);
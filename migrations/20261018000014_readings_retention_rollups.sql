@@ -0,0 +1,34 @@
+-- How many days raw readings are kept, falling back to the owners' setting and then to the server default
+ALTER TABLE users ADD COLUMN readings_retention_days INTEGER CHECK (readings_retention_days > 0);
+ALTER TABLE devices ADD COLUMN readings_retention_days INTEGER CHECK (readings_retention_days > 0);
+
+-- Numeric readings summarized per hour and per day, kept after the raw readings are gone
+CREATE TABLE device_reading_rollups_hourly (
+  device_id TEXT NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  sensor TEXT NOT NULL,
+  bucket TIMESTAMP NOT NULL,
+  min DOUBLE PRECISION NOT NULL,
+  max DOUBLE PRECISION NOT NULL,
+  avg DOUBLE PRECISION NOT NULL,
+  count BIGINT NOT NULL,
+  last DOUBLE PRECISION NOT NULL,
+  PRIMARY KEY (device_id, sensor, bucket)
+);
+
+CREATE TABLE device_reading_rollups_daily (
+  device_id TEXT NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  sensor TEXT NOT NULL,
+  bucket TIMESTAMP NOT NULL,
+  min DOUBLE PRECISION NOT NULL,
+  max DOUBLE PRECISION NOT NULL,
+  avg DOUBLE PRECISION NOT NULL,
+  count BIGINT NOT NULL,
+  last DOUBLE PRECISION NOT NULL,
+  PRIMARY KEY (device_id, sensor, bucket)
+);
+
+-- Everything before this has been rolled up
+CREATE TABLE telemetry_rollup_state (
+  name TEXT PRIMARY KEY,
+  rolled_up_to TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Expired readings are looked up by when they've been received across every device
+CREATE INDEX device_readings_received_at_idx ON device_readings(received_at);
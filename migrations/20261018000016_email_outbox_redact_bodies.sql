@@ -0,0 +1,2 @@
+-- Bodies may hold tokens, so they're only kept while the email can still be sent
+UPDATE email_outbox SET body = '', html_body = NULL WHERE status IN ('sent', 'dead');
@@ -0,0 +1,39 @@
+-- Hours and days that have to be rolled up again. Readings can be stored well after they've been
+-- received, e.g. when the ingestion retries a batch, so an hour is marked whenever a reading lands in it
+-- rather than being rolled up once its time has passed.
+CREATE TABLE telemetry_rollup_dirty_buckets (
+  name TEXT NOT NULL,
+  bucket TIMESTAMP NOT NULL,
+  PRIMARY KEY (name, bucket)
+);
+
+-- The marker is updated rather than left alone when it's already there, so it stays locked until the
+-- readings are committed and can't be taken by a rollup that doesn't see them yet
+CREATE FUNCTION mark_device_readings_rollup_dirty() RETURNS TRIGGER AS $$
+BEGIN
+  INSERT INTO telemetry_rollup_dirty_buckets(name, bucket)
+  SELECT DISTINCT 'hourly', date_bin(INTERVAL '1 hour', received_at, TIMESTAMP 'epoch')
+  FROM new_readings
+  WHERE numeric_value IS NOT NULL
+  ON CONFLICT (name, bucket) DO UPDATE SET bucket = EXCLUDED.bucket;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER device_readings_rollup_dirty
+AFTER INSERT ON device_readings
+REFERENCING NEW TABLE AS new_readings
+FOR EACH STATEMENT EXECUTE FUNCTION mark_device_readings_rollup_dirty();
+
+-- Whatever hasn't been rolled up yet is picked up through the markers from now on
+INSERT INTO telemetry_rollup_dirty_buckets(name, bucket)
+SELECT DISTINCT 'hourly', date_bin(INTERVAL '1 hour', received_at, TIMESTAMP 'epoch')
+FROM device_readings
+WHERE numeric_value IS NOT NULL
+AND received_at >= COALESCE((SELECT rolled_up_to FROM telemetry_rollup_state WHERE name = 'hourly'), '-infinity');
+
+INSERT INTO telemetry_rollup_dirty_buckets(name, bucket)
+SELECT DISTINCT 'daily', date_bin(INTERVAL '1 day', bucket, TIMESTAMP 'epoch')
+FROM device_reading_rollups_hourly
+WHERE bucket >= COALESCE((SELECT rolled_up_to FROM telemetry_rollup_state WHERE name = 'daily'), '-infinity');
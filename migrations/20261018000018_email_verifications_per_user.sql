@@ -0,0 +1,4 @@
+-- Registration codes and the codes of every account moving to an address are kept apart, so
+-- requesting one doesn't replace another
+ALTER TABLE email_verifications DROP CONSTRAINT email_verifications_email_key;
+CREATE UNIQUE INDEX email_verifications_email_user_id_idx ON email_verifications(email, user_id) NULLS NOT DISTINCT;
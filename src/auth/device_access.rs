@@ -0,0 +1,80 @@
+use std::{env, sync::OnceLock};
+use rocket::{http::Status, time::Duration};
+use sqlx::{Pool, Postgres};
+
+
+/// What a user can do with a device they're connected to, from the least to the most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceRole {
+  /// Receives the telemetry of the device
+  Viewer,
+  /// Can also send commands to the device
+  Controller,
+  /// Can also manage the device and who it's shared with
+  Owner
+}
+
+impl DeviceRole {
+  pub fn parse(role: &str) -> Option<Self> {
+    match role {
+      "viewer" => Some(DeviceRole::Viewer),
+      "controller" => Some(DeviceRole::Controller),
+      "owner" => Some(DeviceRole::Owner),
+      _ => None
+    }
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      DeviceRole::Viewer => "viewer",
+      DeviceRole::Controller => "controller",
+      DeviceRole::Owner => "owner"
+    }
+  }
+
+  pub fn can_control(&self) -> bool {
+    *self >= DeviceRole::Controller
+  }
+}
+
+
+static DEVICE_INVITATION_LIFETIME: OnceLock<Duration> = OnceLock::new();
+
+/// How long an invitation to a device can be accepted, read once from `DEVICE_INVITATION_LIFETIME_SECS`.
+pub fn device_invitation_lifetime() -> Duration {
+  *DEVICE_INVITATION_LIFETIME.get_or_init(|| {
+    match env::var("DEVICE_INVITATION_LIFETIME_SECS") {
+      Ok(value) => Duration::seconds(value.parse::<i64>().expect("DEVICE_INVITATION_LIFETIME_SECS must be a valid number")),
+      Err(_) => Duration::days(7)
+    }
+  })
+}
+
+
+/// The role the user has on the device, if they're connected to it.
+pub async fn device_role(db: &Pool<Postgres>, user_id: &str, device_id: &str) -> Result<Option<DeviceRole>, sqlx::Error> {
+  let role: Option<String> = sqlx::query_scalar!(
+    "SELECT role FROM connections WHERE user_id = $1 AND device_id = $2",
+    user_id,
+    device_id
+  )
+  .fetch_optional(db)
+  .await?;
+
+  Ok(role.as_deref().and_then(DeviceRole::parse))
+}
+
+/// Check that the user has at least the `required` role on the device. Answers with 404 if
+/// they aren't connected to it at all, so the device's existence isn't given away, and 403
+/// if their role isn't enough.
+pub async fn require_device_role(db: &Pool<Postgres>, user_id: &str, device_id: &str, required: DeviceRole) -> Result<DeviceRole, Status> {
+  match device_role(db, user_id, device_id).await {
+    Ok(Some(role)) if role >= required => Ok(role),
+    Ok(Some(_)) => Err(Status::Forbidden),
+    Ok(None) => Err(Status::NotFound),
+    Err(err) => {
+      log::error!("There's an error when trying to get the device role. Error: {}", err);
+      Err(Status::InternalServerError)
+    }
+  }
+}
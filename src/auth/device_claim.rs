@@ -0,0 +1,97 @@
+use std::{env, sync::OnceLock};
+use rand::Rng;
+use rocket::time::{Duration, PrimitiveDateTime};
+use sqlx::{PgExecutor, Pool, Postgres};
+use crate::auth::now_utc;
+
+
+/// The prefix of the QR payload of a claim code, e.g. `WMS-CLAIM:ABCD-2345`.
+pub const CLAIM_QR_PREFIX: &str = "WMS-CLAIM:";
+
+/// Letters and digits that can't be mistaken for each other when read off a screen.
+const CLAIM_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CLAIM_CODE_LENGTH: usize = 8;
+
+static DEVICE_CLAIM_LIFETIME: OnceLock<Duration> = OnceLock::new();
+
+/// How long a claim code can be used after the device asked for it, read once from `DEVICE_CLAIM_LIFETIME_SECS`.
+pub fn device_claim_lifetime() -> Duration {
+  *DEVICE_CLAIM_LIFETIME.get_or_init(|| {
+    match env::var("DEVICE_CLAIM_LIFETIME_SECS") {
+      Ok(value) => Duration::seconds(value.parse::<i64>().expect("DEVICE_CLAIM_LIFETIME_SECS must be a valid number")),
+      Err(_) => Duration::minutes(10)
+    }
+  })
+}
+
+fn generate_claim_code() -> String {
+  let mut rng = rand::rng();
+
+  (0..CLAIM_CODE_LENGTH)
+    .map(|_| CLAIM_CODE_ALPHABET[rng.random_range(0..CLAIM_CODE_ALPHABET.len())] as char)
+    .collect()
+}
+
+/// Show a stored code in two halves, e.g. `ABCD-2345`.
+pub fn format_claim_code(code: &str) -> String {
+  let (first, second) = code.split_at(code.len() / 2);
+  format!("{}-{}", first, second)
+}
+
+/// Turn whatever the user has typed or scanned into the stored form of the code.
+pub fn normalize_claim_code(input: &str) -> String {
+  let input: &str = input.trim();
+  let input: &str = input.strip_prefix(CLAIM_QR_PREFIX).unwrap_or(input);
+
+  input
+    .chars()
+    .filter(|c| c.is_ascii_alphanumeric())
+    .map(|c| c.to_ascii_uppercase())
+    .collect()
+}
+
+
+/// Create a claim code for a device that nobody has claimed yet, replacing the one it had before.
+/// Returns none if the device already belongs to someone.
+pub async fn issue_device_claim(db: &Pool<Postgres>, device_id: &str) -> Result<Option<(String, PrimitiveDateTime)>, sqlx::Error> {
+  let now: PrimitiveDateTime = now_utc();
+  let expire_at: PrimitiveDateTime = now + device_claim_lifetime();
+
+  let code: Option<String> = sqlx::query_scalar!(
+    "INSERT INTO device_claims(device_id, code, created_at, expire_at)
+    SELECT $1, $2, $3, $4 WHERE NOT EXISTS (SELECT 1 FROM connections WHERE device_id = $1)
+    ON CONFLICT (device_id) DO UPDATE SET code = EXCLUDED.code, created_at = EXCLUDED.created_at, expire_at = EXCLUDED.expire_at
+    RETURNING code",
+    device_id,
+    generate_claim_code(),
+    now,
+    expire_at
+  )
+  .fetch_optional(db)
+  .await?;
+
+  Ok(code.map(|code| (code, expire_at)))
+}
+
+/// Whether the device belongs to someone.
+pub async fn is_device_claimed(db: &Pool<Postgres>, device_id: &str) -> Result<bool, sqlx::Error> {
+  let claimed: Option<bool> = sqlx::query_scalar!(
+    "SELECT EXISTS (SELECT 1 FROM connections WHERE device_id = $1)",
+    device_id
+  )
+  .fetch_one(db)
+  .await?;
+
+  Ok(claimed.unwrap_or(false))
+}
+
+/// Use up a claim code and return the device it belongs to, if it's valid and hasn't expired.
+pub async fn consume_device_claim<'e, E: PgExecutor<'e>>(executor: E, code: &str) -> Result<Option<String>, sqlx::Error> {
+  sqlx::query_scalar!(
+    "DELETE FROM device_claims WHERE code = $1 AND expire_at > $2 RETURNING device_id",
+    code,
+    now_utc()
+  )
+  .fetch_optional(executor)
+  .await
+}
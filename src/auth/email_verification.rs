@@ -0,0 +1,164 @@
+use std::{env, sync::OnceLock};
+use rocket::{http::Status, response::{self, Responder}, time::{Duration, PrimitiveDateTime}, Request, Response};
+use sqlx::{PgConnection, PgExecutor, Pool, Postgres};
+use crate::{auth::now_utc, model::EmailVerification, util::{constant_time_eq, generate_token}};
+
+
+/// How verification codes sent by email behave.
+pub struct EmailVerificationConfig {
+  /// How long a code can be used after it has been sent
+  pub lifetime: Duration,
+  /// How many times a code can be tried before a new one has to be requested
+  pub max_attempts: i32,
+  /// How long to wait before another code can be sent to the same email
+  pub resend_cooldown: Duration
+}
+
+static EMAIL_VERIFICATION_CONFIG: OnceLock<EmailVerificationConfig> = OnceLock::new();
+
+fn read_number(name: &str, default: i64) -> i64 {
+  match env::var(name) {
+    Ok(value) => value.parse::<i64>().unwrap_or_else(|_| panic!("{} must be a valid number", name)),
+    Err(_) => default
+  }
+}
+
+/// Verification settings, read once from `EMAIL_VERIFICATION_LIFETIME_SECS`,
+/// `EMAIL_VERIFICATION_MAX_ATTEMPTS` and `EMAIL_VERIFICATION_RESEND_COOLDOWN_SECS`.
+pub fn email_verification_config() -> &'static EmailVerificationConfig {
+  EMAIL_VERIFICATION_CONFIG.get_or_init(|| {
+    EmailVerificationConfig {
+      lifetime: Duration::seconds(read_number("EMAIL_VERIFICATION_LIFETIME_SECS", 15 * 60)),
+      max_attempts: read_number("EMAIL_VERIFICATION_MAX_ATTEMPTS", 5) as i32,
+      resend_cooldown: Duration::seconds(read_number("EMAIL_VERIFICATION_RESEND_COOLDOWN_SECS", 60))
+    }
+  })
+}
+
+
+pub enum IssueResult {
+  /// A new code has been stored and should be sent to the email
+  Issued(String),
+  /// A code has been sent too recently, try again after this long
+  Cooldown(Duration)
+}
+
+/// Why a verification code couldn't be sent.
+pub enum IssueError {
+  Status(Status),
+  /// A code has been sent too recently, answered with how long to wait in `Retry-After`
+  Cooldown(Duration)
+}
+
+impl From<Status> for IssueError {
+  fn from(status: Status) -> Self {
+    IssueError::Status(status)
+  }
+}
+
+impl<'r> Responder<'r, 'static> for IssueError {
+  fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+    match self {
+      IssueError::Status(status) => status.respond_to(request),
+      IssueError::Cooldown(remaining) => Response::build()
+        .status(Status::TooManyRequests)
+        .raw_header("Retry-After", remaining.whole_seconds().max(1).to_string())
+        .ok()
+    }
+  }
+}
+
+/// Create a new verification code for `email`, replacing the previous one unless
+/// it was sent within the resend cooldown. `user_id` is the account that wants to
+/// move to this email, or none when registering, and each of them has its own code.
+/// Run it in the transaction that queues the email, so the code is only kept if
+/// it's going to be sent.
+pub async fn issue_email_verification(conn: &mut PgConnection, email: &str, user_id: Option<&str>) -> Result<IssueResult, sqlx::Error> {
+  let now: PrimitiveDateTime = now_utc();
+  let config: &EmailVerificationConfig = email_verification_config();
+  let token: String = generate_token(8);
+
+  // Only replace the existing code if its cooldown has passed
+  let issued = sqlx::query!(
+    "INSERT INTO email_verifications(id, email, token, created_at, expire_at, attempts, last_sent_at, user_id) VALUES ($1, $2, $3, $4, $5, 0, $4, $7)
+    ON CONFLICT (email, user_id) DO UPDATE SET token = EXCLUDED.token, created_at = EXCLUDED.created_at, expire_at = EXCLUDED.expire_at, attempts = 0, last_sent_at = EXCLUDED.last_sent_at
+    WHERE email_verifications.last_sent_at <= $6
+    RETURNING id",
+    generate_token(10),
+    email,
+    token,
+    now,
+    now + config.lifetime,
+    now - config.resend_cooldown,
+    user_id
+  )
+  .fetch_optional(&mut *conn)
+  .await?;
+
+  if issued.is_some() {
+    return Ok(IssueResult::Issued(token));
+  }
+
+  let last_sent_at: PrimitiveDateTime = sqlx::query_scalar!(
+    "SELECT last_sent_at FROM email_verifications WHERE email = $1 AND user_id IS NOT DISTINCT FROM $2",
+    email,
+    user_id
+  )
+  .fetch_one(&mut *conn)
+  .await?;
+
+  Ok(IssueResult::Cooldown(last_sent_at + config.resend_cooldown - now))
+}
+
+pub enum VerificationCheck {
+  /// The code matches, consume it with [`consume_email_verification`]
+  Valid(EmailVerification),
+  /// There's no pending code for the email, it has expired or it doesn't match
+  Invalid,
+  /// The code has been tried too many times and a new one has to be requested
+  TooManyAttempts
+}
+
+/// Check `token` against the code sent to `email` for `user_id`. Every check counts as
+/// an attempt, so this has to run outside of any transaction that might be rolled back.
+pub async fn check_email_verification(db: &Pool<Postgres>, email: &str, user_id: Option<&str>, token: &str) -> Result<VerificationCheck, sqlx::Error> {
+  let verification: Option<EmailVerification> = sqlx::query_as!(
+    EmailVerification,
+    "UPDATE email_verifications SET attempts = attempts + 1 WHERE email = $1 AND user_id IS NOT DISTINCT FROM $2 AND expire_at > $3 RETURNING *",
+    email,
+    user_id,
+    now_utc()
+  )
+  .fetch_optional(db)
+  .await?;
+
+  let verification: EmailVerification = match verification {
+    Some(data) => data,
+    None => {
+      return Ok(VerificationCheck::Invalid);
+    }
+  };
+
+  if verification.attempts > email_verification_config().max_attempts {
+    return Ok(VerificationCheck::TooManyAttempts);
+  }
+
+  if !constant_time_eq(&verification.token, token) {
+    return Ok(VerificationCheck::Invalid);
+  }
+
+  Ok(VerificationCheck::Valid(verification))
+}
+
+/// Delete a verified code so it can't be used again. Returns false if it has
+/// already been consumed by a concurrent request.
+pub async fn consume_email_verification<'e, E: PgExecutor<'e>>(executor: E, verification: &EmailVerification) -> Result<bool, sqlx::Error> {
+  let result = sqlx::query!(
+    "DELETE FROM email_verifications WHERE id = $1",
+    verification.id
+  )
+  .execute(executor)
+  .await?;
+
+  Ok(result.rows_affected() > 0)
+}
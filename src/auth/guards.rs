@@ -0,0 +1,185 @@
+use std::env;
+use rocket::{http::Status, request::{FromRequest, Outcome}, Request, State};
+use sqlx::{Pool, Postgres};
+use crate::{auth::{authenticate_device, authenticate_user, parse_bearer_token, set_access_token_cookie, TokenValidation}, model::{Device, Session, User}, util::client_ip::client_ip};
+
+
+/// Where a request comes from, recorded on the sessions it creates.
+pub struct ClientInfo {
+  pub user_agent: Option<String>,
+  pub ip_address: Option<String>
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    Outcome::Success(ClientInfo {
+      user_agent: request.headers().get_one("User-Agent").map(|value| value.to_string()),
+      ip_address: client_ip(request).map(|ip| ip.to_string())
+    })
+  }
+}
+
+
+/// Where the access token of a request has been read from.
+#[derive(PartialEq, Eq)]
+enum TokenSource {
+  Cookie,
+  Bearer
+}
+
+/// Get the access token the request has been sent with. The `Authorization` header
+/// takes precedence over the `access_token` cookie.
+fn request_access_token(request: &Request<'_>) -> Option<(String, TokenSource)> {
+  if let Some(header_value) = request.headers().get_one("Authorization") {
+    return parse_bearer_token(header_value).map(|token| (token.to_string(), TokenSource::Bearer));
+  }
+
+  request.cookies().get("access_token").map(|cookie| (cookie.value().to_string(), TokenSource::Cookie))
+}
+
+/// Get the database pool managed by Rocket.
+async fn request_db<'r>(request: &'r Request<'_>) -> Option<&'r Pool<Postgres>> {
+  match request.guard::<&State<Pool<Postgres>>>().await {
+    Outcome::Success(db) => Some(db.inner()),
+    _ => {
+      log::error!("There's no database pool managed by rocket!");
+      None
+    }
+  }
+}
+
+
+/// A user with a valid session. Responds with 401 if the request doesn't carry one.
+pub struct AuthenticatedUser {
+  pub user: User,
+  pub session: Session
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    // Get the access token
+    let (access_token, token_source) = match request_access_token(request) {
+      Some(token) => token,
+      None => {
+        return Outcome::Error((Status::Unauthorized, ()));
+      }
+    };
+
+    let db: &Pool<Postgres> = match request_db(request).await {
+      Some(db) => db,
+      None => {
+        return Outcome::Error((Status::InternalServerError, ()));
+      }
+    };
+
+    // Verify access token
+    let token_validation: TokenValidation = match authenticate_user(db, &access_token).await {
+      Ok(res) => match res {
+        Some(data) => data,
+        None => {
+          return Outcome::Error((Status::Unauthorized, ()));
+        }
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to get user data for access verification. Error: {}", err);
+        return Outcome::Error((Status::InternalServerError, ()));
+      }
+    };
+
+    // Refresh the cookie if the token has been renewed
+    if let Some(expire) = token_validation.renewed_expire && token_source == TokenSource::Cookie {
+      set_access_token_cookie(request.cookies(), access_token, expire);
+    }
+
+    Outcome::Success(AuthenticatedUser {
+      user: token_validation.user,
+      session: token_validation.session
+    })
+  }
+}
+
+
+/// A signed in user whose email is listed in `ADMIN_EMAILS` (comma separated).
+/// Responds with 401 without a session and 403 for everyone else.
+pub struct AuthenticatedAdmin {
+  pub user: User,
+  pub session: Session
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedAdmin {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    let authenticated_user: AuthenticatedUser = match request.guard::<AuthenticatedUser>().await {
+      Outcome::Success(res) => res,
+      Outcome::Error(err) => {
+        return Outcome::Error(err);
+      },
+      Outcome::Forward(status) => {
+        return Outcome::Forward(status);
+      }
+    };
+
+    let admin_emails: String = env::var("ADMIN_EMAILS").unwrap_or_default();
+    let is_admin: bool = admin_emails
+      .split(',')
+      .any(|email| email.trim().eq_ignore_ascii_case(&authenticated_user.user.email));
+
+    if !is_admin {
+      log::warn!("User {} has tried to reach an admin route", authenticated_user.user.id);
+      return Outcome::Error((Status::Forbidden, ()));
+    }
+
+    Outcome::Success(AuthenticatedAdmin {
+      user: authenticated_user.user,
+      session: authenticated_user.session
+    })
+  }
+}
+
+
+/// A device with a valid access token. Responds with 401 if the request doesn't carry one.
+pub struct AuthenticatedDevice {
+  pub device: Device
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedDevice {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    // Get the access token
+    let (access_token, _) = match request_access_token(request) {
+      Some(token) => token,
+      None => {
+        return Outcome::Error((Status::Unauthorized, ()));
+      }
+    };
+
+    let db: &Pool<Postgres> = match request_db(request).await {
+      Some(db) => db,
+      None => {
+        return Outcome::Error((Status::InternalServerError, ()));
+      }
+    };
+
+    // Verify access token
+    match authenticate_device(db, &access_token).await {
+      Ok(res) => match res {
+        Some(device) => Outcome::Success(AuthenticatedDevice { device }),
+        None => Outcome::Error((Status::Unauthorized, ()))
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to get device data for access verification. Error: {}", err);
+        Outcome::Error((Status::InternalServerError, ()))
+      }
+    }
+  }
+}
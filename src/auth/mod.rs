@@ -0,0 +1,211 @@
+use std::{env, sync::OnceLock};
+use rocket::{http::{Cookie, CookieJar}, time::{Duration, OffsetDateTime, PrimitiveDateTime}};
+use sqlx::{PgExecutor, Pool, Postgres};
+use crate::{model::{Device, Session, User}, util::generate_token};
+pub mod guards;
+pub mod email_verification;
+pub mod password_reset;
+pub mod device_claim;
+pub mod device_access;
+use guards::ClientInfo;
+
+
+/// How long access tokens stay valid.
+pub struct AccessTokenConfig {
+  /// How long a token stays valid without being used. Using the token slides this window forward.
+  pub idle_lifetime: Duration,
+  /// How long a token stays valid since it was issued, no matter how often it's used.
+  pub absolute_lifetime: Duration
+}
+
+static ACCESS_TOKEN_CONFIG: OnceLock<AccessTokenConfig> = OnceLock::new();
+
+fn read_lifetime(name: &str, default: Duration) -> Duration {
+  match env::var(name) {
+    Ok(value) => Duration::seconds(value.parse::<i64>().unwrap_or_else(|_| panic!("{} must be a valid number of seconds", name))),
+    Err(_) => default
+  }
+}
+
+/// Access token lifetimes, read once from `ACCESS_TOKEN_IDLE_LIFETIME_SECS` and
+/// `ACCESS_TOKEN_ABSOLUTE_LIFETIME_SECS`.
+pub fn access_token_config() -> &'static AccessTokenConfig {
+  ACCESS_TOKEN_CONFIG.get_or_init(|| {
+    AccessTokenConfig {
+      idle_lifetime: read_lifetime("ACCESS_TOKEN_IDLE_LIFETIME_SECS", Duration::days(14)),
+      absolute_lifetime: read_lifetime("ACCESS_TOKEN_ABSOLUTE_LIFETIME_SECS", Duration::days(60))
+    }
+  })
+}
+
+
+/// The current UTC time in the form stored in the database.
+pub fn now_utc() -> PrimitiveDateTime {
+  let now: OffsetDateTime = OffsetDateTime::now_utc();
+  PrimitiveDateTime::new(now.date(), now.time())
+}
+
+/// The expiry for a token issued at `created_at`, seen at `now`. The idle window
+/// never extends past the absolute lifetime.
+pub fn access_token_expiry(created_at: PrimitiveDateTime, now: PrimitiveDateTime) -> PrimitiveDateTime {
+  let config: &AccessTokenConfig = access_token_config();
+  (now + config.idle_lifetime).min(created_at + config.absolute_lifetime)
+}
+
+/// Store the access token in a cookie that lives as long as its session.
+pub fn set_access_token_cookie(cookies: &CookieJar<'_>, access_token: String, expires_at: PrimitiveDateTime) {
+  cookies.add(
+    Cookie::build(("access_token", access_token))
+    .path("/")
+    .secure(true)
+    .http_only(true)
+    .max_age(expires_at - now_utc())
+  );
+}
+
+/// Get the token out of an `Authorization: Bearer <token>` header value.
+pub fn parse_bearer_token(header_value: &str) -> Option<&str> {
+  let (scheme, token) = header_value.trim().split_once(' ')?;
+  let token: &str = token.trim();
+
+  if !scheme.eq_ignore_ascii_case("bearer") || token.is_empty() {
+    return None;
+  }
+
+  Some(token)
+}
+
+/// Remove the access token cookie from the client.
+pub fn remove_access_token_cookie(cookies: &CookieJar<'_>) {
+  cookies.remove(Cookie::build("access_token").path("/"));
+}
+
+
+/// Start a new session for the user. The returned session holds its fresh access token.
+pub async fn create_session<'e, E: PgExecutor<'e>>(executor: E, user_id: &str, client_info: &ClientInfo) -> Result<Session, sqlx::Error> {
+  let now: PrimitiveDateTime = now_utc();
+
+  sqlx::query_as!(
+    Session,
+    "INSERT INTO sessions(id, user_id, access_token, created_at, last_used_at, expire_at, user_agent, ip_address) VALUES ($1, $2, $3, $4, $4, $5, $6, $7) RETURNING *",
+    generate_token(10),
+    user_id,
+    generate_token(20),
+    now,
+    access_token_expiry(now, now),
+    client_info.user_agent,
+    client_info.ip_address
+  )
+  .fetch_one(executor)
+  .await
+}
+
+
+/// A user whose access token has been verified.
+pub struct TokenValidation {
+  pub user: User,
+  pub session: Session,
+  /// The new expiry if the token has been renewed during this validation.
+  pub renewed_expire: Option<PrimitiveDateTime>
+}
+
+/// Look up the session that owns `access_token` and its user, rejecting sessions
+/// that are past their idle or absolute lifetime. Sessions with less than half of
+/// their idle lifetime left are renewed.
+pub async fn authenticate_user(db: &Pool<Postgres>, access_token: &str) -> Result<Option<TokenValidation>, sqlx::Error> {
+  let now: PrimitiveDateTime = now_utc();
+  let config: &AccessTokenConfig = access_token_config();
+
+  let session_data: Option<Session> = sqlx::query_as!(
+    Session,
+    "SELECT * FROM sessions WHERE access_token = $1 AND expire_at > $2 AND created_at > $3",
+    access_token,
+    now,
+    now - config.absolute_lifetime
+  )
+  .fetch_optional(db)
+  .await?;
+
+  let mut session_data: Session = match session_data {
+    Some(data) => data,
+    None => {
+      return Ok(None);
+    }
+  };
+
+  let user_data: Option<User> = sqlx::query_as!(
+    User,
+    "SELECT * FROM users WHERE id = $1",
+    session_data.user_id
+  )
+  .fetch_optional(db)
+  .await?;
+
+  let user_data: User = match user_data {
+    Some(data) => data,
+    None => {
+      return Ok(None);
+    }
+  };
+
+  // Slide the expiry forward if the session is getting close to the end of its lifetime
+  let mut renewed_expire: Option<PrimitiveDateTime> = None;
+  if session_data.expire_at - now < config.idle_lifetime / 2 {
+    let new_expire: PrimitiveDateTime = access_token_expiry(session_data.created_at, now);
+
+    if new_expire > session_data.expire_at {
+      renewed_expire = Some(new_expire);
+    }
+  }
+
+  // Only touch the session once in a while so every request doesn't turn into a write
+  if renewed_expire.is_some() || now - session_data.last_used_at > Duration::minutes(1) {
+    session_data.expire_at = renewed_expire.unwrap_or(session_data.expire_at);
+    session_data.last_used_at = now;
+
+    sqlx::query!(
+      "UPDATE sessions SET last_used_at = $1, expire_at = $2 WHERE id = $3",
+      session_data.last_used_at,
+      session_data.expire_at,
+      session_data.id
+    )
+    .execute(db)
+    .await?;
+  }
+
+  Ok(Some(TokenValidation {
+    user: user_data,
+    session: session_data,
+    renewed_expire
+  }))
+}
+
+/// When a session stops being valid, whichever of its idle and absolute lifetime runs out first.
+pub fn session_valid_until(session: &Session) -> PrimitiveDateTime {
+  session.expire_at.min(session.created_at + access_token_config().absolute_lifetime)
+}
+
+/// Check that a session is still valid, e.g. for a connection that has outlived the lifetime it was
+/// opened with. Returns when it stops being valid, none if it has expired or been revoked.
+pub async fn check_session(db: &Pool<Postgres>, session_id: &str) -> Result<Option<PrimitiveDateTime>, sqlx::Error> {
+  let session_data: Option<Session> = sqlx::query_as!(
+    Session,
+    "SELECT * FROM sessions WHERE id = $1",
+    session_id
+  )
+  .fetch_optional(db)
+  .await?;
+
+  Ok(session_data.map(|session| session_valid_until(&session)).filter(|valid_until| *valid_until > now_utc()))
+}
+
+/// Look up the device that owns `access_token`.
+pub async fn authenticate_device(db: &Pool<Postgres>, access_token: &str) -> Result<Option<Device>, sqlx::Error> {
+  sqlx::query_as!(
+    Device,
+    "SELECT * FROM devices WHERE access_token = $1",
+    access_token
+  )
+  .fetch_optional(db)
+  .await
+}
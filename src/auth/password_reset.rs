@@ -0,0 +1,48 @@
+use std::{env, sync::OnceLock};
+use rocket::time::{Duration, PrimitiveDateTime};
+use sqlx::PgExecutor;
+use crate::{auth::now_utc, util::generate_token};
+
+
+static PASSWORD_RESET_LIFETIME: OnceLock<Duration> = OnceLock::new();
+
+/// How long a reset token can be used after it has been sent, read once from `PASSWORD_RESET_LIFETIME_SECS`.
+pub fn password_reset_lifetime() -> Duration {
+  *PASSWORD_RESET_LIFETIME.get_or_init(|| {
+    match env::var("PASSWORD_RESET_LIFETIME_SECS") {
+      Ok(value) => Duration::seconds(value.parse::<i64>().expect("PASSWORD_RESET_LIFETIME_SECS must be a valid number")),
+      Err(_) => Duration::hours(1)
+    }
+  })
+}
+
+/// Create a reset token for the user, replacing any token they've been sent before.
+pub async fn issue_password_reset<'e, E: PgExecutor<'e>>(executor: E, user_id: &str) -> Result<String, sqlx::Error> {
+  let now: PrimitiveDateTime = now_utc();
+  let token: String = generate_token(32);
+
+  sqlx::query!(
+    "WITH previous AS (DELETE FROM password_resets WHERE user_id = $2)
+    INSERT INTO password_resets(id, user_id, token, created_at, expire_at) VALUES ($1, $2, $3, $4, $5)",
+    generate_token(10),
+    user_id,
+    token,
+    now,
+    now + password_reset_lifetime()
+  )
+  .execute(executor)
+  .await?;
+
+  Ok(token)
+}
+
+/// Use up a reset token and return the user it belongs to, if it's valid and hasn't expired.
+pub async fn consume_password_reset<'e, E: PgExecutor<'e>>(executor: E, token: &str) -> Result<Option<String>, sqlx::Error> {
+  sqlx::query_scalar!(
+    "DELETE FROM password_resets WHERE token = $1 AND expire_at > $2 RETURNING user_id",
+    token,
+    now_utc()
+  )
+  .fetch_optional(executor)
+  .await
+}
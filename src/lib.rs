@@ -3,4 +3,8 @@ pub mod model;
 pub mod db;
 pub mod util;
 pub mod types;
-pub mod websocket;
\ No newline at end of file
+pub mod websocket;
+pub mod auth;
+pub mod rate_limit;
+pub mod mail;
+pub mod telemetry;
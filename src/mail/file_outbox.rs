@@ -0,0 +1,47 @@
+use std::{env, path::PathBuf};
+use lettre::Message;
+use crate::{auth::now_utc, util::generate_token};
+use super::Mailer;
+
+
+/// Writes every email as an `.eml` file into a directory instead of sending it.
+pub struct FileOutboxMailer {
+    directory: PathBuf,
+}
+
+impl FileOutboxMailer {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+
+    /// Read the directory from `MAIL_OUTBOX_DIR`, `outbox` by default.
+    pub fn from_env() -> Result<Self, String> {
+        let directory: PathBuf = PathBuf::from(env::var("MAIL_OUTBOX_DIR").unwrap_or(String::from("outbox")));
+
+        std::fs::create_dir_all(&directory)
+            .map_err(|err| format!("There's an error when trying to create the mail outbox directory. Error: {}", err))?;
+
+        Ok(Self::new(directory))
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for FileOutboxMailer {
+    async fn send(&self, message: Message) -> Result<(), String> {
+        // Name the files by time so they list in the order they were sent
+        let file_name: String = format!(
+            "{}-{}.eml",
+            now_utc().assume_utc().unix_timestamp_nanos(),
+            generate_token(6)
+        );
+        let path: PathBuf = self.directory.join(file_name);
+
+        match tokio::fs::write(&path, message.formatted()).await {
+            Ok(_) => {
+                log::info!("Email has been written to {}", path.display());
+                Ok(())
+            },
+            Err(err) => Err(format!("There's an error when trying to write email to the outbox. Error: {}", err)),
+        }
+    }
+}
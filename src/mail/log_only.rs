@@ -0,0 +1,14 @@
+use lettre::Message;
+use super::Mailer;
+
+
+/// Only logs the emails, for development where nothing should leave the machine.
+pub struct LogOnlyMailer;
+
+#[rocket::async_trait]
+impl Mailer for LogOnlyMailer {
+    async fn send(&self, message: Message) -> Result<(), String> {
+        log::info!("Email has only been logged, not sent:\n{}", String::from_utf8_lossy(&message.formatted()));
+        Ok(())
+    }
+}
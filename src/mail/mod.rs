@@ -0,0 +1,138 @@
+use std::{env, sync::Arc};
+use lettre::{
+    message::{header::ContentType, Mailbox, MultiPart},
+    Message,
+};
+
+pub mod smtp;
+pub mod file_outbox;
+pub mod log_only;
+pub mod outbox;
+pub mod templates;
+
+use rocket::time::{format_description::FormatItem, macros::format_description};
+use sqlx::PgExecutor;
+use tokio::sync::Notify;
+use crate::{auth::now_utc, model::User};
+use self::{file_outbox::FileOutboxMailer, log_only::LogOnlyMailer, outbox::enqueue_email, smtp::SmtpMailer, templates::{EmailTemplates, RenderedEmail}};
+
+
+/// When a change happened, as written in security alerts.
+const ALERT_TIME_FORMAT: &[FormatItem<'static>] = format_description!("[year]-[month]-[day] [hour]:[minute] UTC");
+
+
+/// Something that can deliver a fully built email message.
+#[rocket::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, message: Message) -> Result<(), String>;
+}
+
+
+/// Builds the emails sent by the server and hands them to the configured [`Mailer`].
+#[derive(Clone)]
+pub struct MailService {
+    from: Mailbox,
+    mailer: Arc<dyn Mailer>,
+    templates: Arc<EmailTemplates>,
+    outbox_wakeup: Arc<Notify>,
+}
+
+impl MailService {
+    pub fn new(from: Mailbox, mailer: Arc<dyn Mailer>, templates: EmailTemplates) -> Self {
+        Self { from, mailer, templates: Arc::new(templates), outbox_wakeup: Arc::new(Notify::new()) }
+    }
+
+    /// Pick the mailer from `MAIL_TRANSPORT` (`smtp`, `file` or `log`) and the sender from `MAIL_FROM`.
+    /// Without `MAIL_TRANSPORT`, SMTP is used when credentials are set and it's an error otherwise, so
+    /// emails are only logged when asked for.
+    /// The templates are read with [`EmailTemplates::from_env`].
+    pub fn from_env() -> Result<Self, String> {
+        let has_smtp_credentials: bool = env::var("SMTP_USERNAME").or(env::var("EMAIL_APP_ACCOUNT")).is_ok();
+
+        let transport: String = match env::var("MAIL_TRANSPORT") {
+            Ok(transport) => transport.to_lowercase(),
+            Err(_) if has_smtp_credentials => String::from("smtp"),
+            Err(_) => {
+                return Err(String::from("MAIL_TRANSPORT isn't set and there are no SMTP credentials, set MAIL_TRANSPORT=log to only log emails"));
+            }
+        };
+
+        let mailer: Arc<dyn Mailer> = match transport.as_str() {
+            "smtp" => Arc::new(SmtpMailer::from_env()?),
+            "file" => Arc::new(FileOutboxMailer::from_env()?),
+            "log" => Arc::new(LogOnlyMailer),
+            other => {
+                return Err(format!("MAIL_TRANSPORT must be one of smtp, file or log, got: {}", other));
+            }
+        };
+
+        // Fall back to the SMTP account as the sender
+        let from: String = match env::var("MAIL_FROM") {
+            Ok(from) => from,
+            Err(_) => env::var("SMTP_USERNAME")
+                .or(env::var("EMAIL_APP_ACCOUNT"))
+                .unwrap_or(String::from("WMS <no-reply@localhost>")),
+        };
+
+        let from: Mailbox = match from.parse() {
+            Ok(from) => from,
+            Err(err) => {
+                return Err(format!("MAIL_FROM must be a valid email address. Error: {}", err));
+            }
+        };
+
+        Ok(Self::new(from, mailer, EmailTemplates::from_env()?))
+    }
+
+    /// Let the outbox worker know that new emails have been queued, see [`outbox::enqueue_email`].
+    pub fn wake_outbox(&self) {
+        self.outbox_wakeup.notify_one();
+    }
+
+    /// Render an email template in the first of `locales` that has it, see [`EmailTemplates::render`].
+    pub fn render(&self, name: &str, locales: &[String], variables: &[(&str, &str)]) -> Result<RenderedEmail, String> {
+        self.templates.render(name, locales, variables)
+    }
+
+    /// Queue an alert about a change to the account of `user`, like `password_changed_alert`, sent to
+    /// `to` in the user's locale. Run it in the transaction that makes the change, then call
+    /// [`Self::wake_outbox`] once it has been committed.
+    pub async fn enqueue_security_alert<'e, E: PgExecutor<'e>>(&self, executor: E, user: &User, to: &str, name: &str, variables: &[(&str, &str)]) -> Result<(), String> {
+        let to_address: Mailbox = to.parse().map_err(|err| format!("The email address of the security alert isn't valid. Error: {}", err))?;
+
+        let username: &str = user.username.as_deref().unwrap_or(&user.email);
+        let time: String = now_utc().format(ALERT_TIME_FORMAT).unwrap_or_default();
+        let variables: Vec<(&str, &str)> = [("username", username), ("time", time.as_str())].into_iter().chain(variables.iter().copied()).collect();
+
+        let email: RenderedEmail = self.render(name, &user.locale.iter().cloned().collect::<Vec<String>>(), &variables)?;
+        enqueue_email(executor, &to_address, &email)
+            .await
+            .map_err(|err| format!("There's an error when trying to queue the security alert. Error: {}", err))?;
+
+        Ok(())
+    }
+
+    /// Send an email from the configured sender right away. Routes should
+    /// queue their emails with [`outbox::enqueue_email`] instead.
+    pub async fn send_email(&self, to: Mailbox, email: RenderedEmail) -> Result<(), String> {
+        // Setting up email message
+        let builder = Message::builder()
+            // From which email account?
+            .from(self.from.clone())
+            // Send to where?
+            .to(to)
+            // Subject
+            .subject(email.subject);
+
+        // Put the HTML next to the text, so clients that can't show HTML still have something to show
+        let mail: Result<Message, lettre::error::Error> = match email.html {
+            Some(html) => builder.multipart(MultiPart::alternative_plain_html(email.text, html)),
+            None => builder.header(ContentType::TEXT_PLAIN).body(email.text),
+        };
+
+        // Check if there's an error
+        let mail: Message = mail.map_err(|err| format!("There's an error when building email message. Error: {}", err))?;
+
+        self.mailer.send(mail).await
+    }
+}
@@ -0,0 +1,179 @@
+use std::{env, sync::OnceLock};
+use lettre::message::Mailbox;
+use rocket::time::{Duration, PrimitiveDateTime};
+use sqlx::{PgExecutor, Pool, Postgres};
+use crate::{auth::now_utc, model::EmailOutbox, util::generate_token};
+use super::{templates::RenderedEmail, MailService};
+
+
+/// How the outbox worker delivers the queued emails.
+pub struct OutboxConfig {
+    /// How often to look for due emails when nobody has woken the worker up
+    pub poll_interval: std::time::Duration,
+    /// How many emails are delivered in one go
+    pub batch_size: i64,
+    /// How many times delivery is tried before the email is marked as dead
+    pub max_attempts: i32,
+    /// How long to wait after the first failure, doubled on every failure after that
+    pub backoff_base: Duration,
+    /// The longest wait between two attempts
+    pub backoff_max: Duration,
+    /// How long an email stays claimed by a worker before another one may try it
+    pub claim_timeout: Duration,
+}
+
+static OUTBOX_CONFIG: OnceLock<OutboxConfig> = OnceLock::new();
+
+fn read_number(name: &str, default: i64) -> i64 {
+    match env::var(name) {
+        Ok(value) => value.parse::<i64>().unwrap_or_else(|_| panic!("{} must be a valid number", name)),
+        Err(_) => default
+    }
+}
+
+/// Outbox settings, read once from `MAIL_OUTBOX_POLL_INTERVAL_SECS`, `MAIL_OUTBOX_BATCH_SIZE`,
+/// `MAIL_OUTBOX_MAX_ATTEMPTS`, `MAIL_OUTBOX_BACKOFF_BASE_SECS` and `MAIL_OUTBOX_BACKOFF_MAX_SECS`.
+pub fn outbox_config() -> &'static OutboxConfig {
+    OUTBOX_CONFIG.get_or_init(|| {
+        OutboxConfig {
+            poll_interval: std::time::Duration::from_secs(read_number("MAIL_OUTBOX_POLL_INTERVAL_SECS", 10) as u64),
+            batch_size: read_number("MAIL_OUTBOX_BATCH_SIZE", 20),
+            max_attempts: read_number("MAIL_OUTBOX_MAX_ATTEMPTS", 8) as i32,
+            backoff_base: Duration::seconds(read_number("MAIL_OUTBOX_BACKOFF_BASE_SECS", 30)),
+            backoff_max: Duration::seconds(read_number("MAIL_OUTBOX_BACKOFF_MAX_SECS", 6 * 60 * 60)),
+            claim_timeout: Duration::minutes(5),
+        }
+    })
+}
+
+/// How long to wait before the next attempt, after `attempts` failed ones.
+fn backoff(attempts: i32) -> Duration {
+    let config: &OutboxConfig = outbox_config();
+    let factor: i32 = 1 << (attempts - 1).clamp(0, 20);
+
+    (config.backoff_base * factor).min(config.backoff_max)
+}
+
+
+/// Queue a rendered email. Run it in the same transaction as the change the email is about,
+/// then call [`MailService::wake_outbox`] once it has been committed. The body is only kept until
+/// the email has been sent or given up on, as it may hold tokens.
+pub async fn enqueue_email<'e, E: PgExecutor<'e>>(executor: E, to: &Mailbox, email: &RenderedEmail) -> Result<String, sqlx::Error> {
+    let id: String = generate_token(16);
+
+    sqlx::query!(
+        "INSERT INTO email_outbox(id, recipient, subject, body, html_body, next_attempt_at, created_at) VALUES ($1, $2, $3, $4, $5, $6, $6)",
+        id,
+        to.to_string(),
+        email.subject,
+        email.text,
+        email.html,
+        now_utc()
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(id)
+}
+
+
+/// Claim the emails that are due, pushing their next attempt back so no other worker picks them up meanwhile.
+async fn claim_due_emails(db: &Pool<Postgres>) -> Result<Vec<EmailOutbox>, sqlx::Error> {
+    let config: &OutboxConfig = outbox_config();
+    let now: PrimitiveDateTime = now_utc();
+
+    sqlx::query_as!(
+        EmailOutbox,
+        "UPDATE email_outbox SET next_attempt_at = $1 WHERE id IN (
+            SELECT id FROM email_outbox WHERE status = 'pending' AND next_attempt_at <= $2
+            ORDER BY next_attempt_at LIMIT $3 FOR UPDATE SKIP LOCKED
+        ) RETURNING *",
+        now + config.claim_timeout,
+        now,
+        config.batch_size
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Try to deliver one email and record how it went.
+async fn deliver(db: &Pool<Postgres>, mail: &MailService, email: EmailOutbox) -> Result<(), sqlx::Error> {
+    let result: Result<(), String> = match email.recipient.parse::<Mailbox>() {
+        Ok(to) => mail.send_email(to, RenderedEmail { subject: email.subject, text: email.body, html: email.html_body }).await,
+        Err(err) => Err(format!("The recipient isn't a valid email address. Error: {}", err))
+    };
+
+    match result {
+        Ok(_) => {
+            sqlx::query!(
+                "UPDATE email_outbox SET status = 'sent', attempts = attempts + 1, last_error = NULL, sent_at = $1, body = '', html_body = NULL WHERE id = $2",
+                now_utc(),
+                email.id
+            )
+            .execute(db)
+            .await?;
+        },
+        Err(err) => {
+            let attempts: i32 = email.attempts + 1;
+            let is_dead: bool = attempts >= outbox_config().max_attempts;
+
+            if is_dead {
+                log::error!("Email {} has been moved to the dead letters after {} attempts. {}", email.id, attempts, err);
+            }
+            else {
+                log::warn!("Email {} couldn't be sent, it will be tried again. {}", email.id, err);
+            }
+
+            // Dead emails aren't tried again, so their body has no use anymore
+            sqlx::query!(
+                "UPDATE email_outbox SET status = $1, attempts = $2, last_error = $3, next_attempt_at = $4,
+                body = CASE WHEN $1 = 'dead' THEN '' ELSE body END, html_body = CASE WHEN $1 = 'dead' THEN NULL ELSE html_body END
+                WHERE id = $5",
+                if is_dead { "dead" } else { "pending" },
+                attempts,
+                err,
+                now_utc() + backoff(attempts),
+                email.id
+            )
+            .execute(db)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Deliver the queued emails until the server shuts down.
+pub async fn run_outbox_worker(db: Pool<Postgres>, mail: MailService) {
+    let config: &OutboxConfig = outbox_config();
+
+    loop {
+        // Keep going while there are full batches waiting
+        loop {
+            let emails: Vec<EmailOutbox> = match claim_due_emails(&db).await {
+                Ok(res) => res,
+                Err(err) => {
+                    log::error!("There's an error when trying to get the queued emails. Error: {}", err);
+                    break;
+                }
+            };
+
+            let is_full_batch: bool = emails.len() as i64 >= config.batch_size;
+
+            for email in emails {
+                if let Err(err) = deliver(&db, &mail, email).await {
+                    log::error!("There's an error when trying to update a queued email. Error: {}", err);
+                }
+            }
+
+            if !is_full_batch {
+                break;
+            }
+        }
+
+        tokio::select! {
+            _ = mail.outbox_wakeup.notified() => (),
+            _ = tokio::time::sleep(config.poll_interval) => ()
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::env;
+use lettre::{
+    Message, Transport,
+    transport::smtp::{
+        SmtpTransport,
+        authentication::{Credentials, Mechanism},
+        client::{Tls, TlsParameters},
+    },
+};
+use super::Mailer;
+
+
+/// How the connection to the SMTP server is secured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpTls {
+    /// Connect in plain text, then upgrade with STARTTLS
+    StartTls,
+    /// Connect with TLS right away
+    Tls,
+    /// Never use TLS, only for local test servers
+    None,
+}
+
+impl SmtpTls {
+    fn default_port(&self) -> u16 {
+        match self {
+            SmtpTls::StartTls => 587,
+            SmtpTls::Tls => 465,
+            SmtpTls::None => 25,
+        }
+    }
+}
+
+
+/// Sends emails through an SMTP relay.
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    /// Read the relay from `SMTP_HOST`, `SMTP_PORT`, `SMTP_TLS` (`starttls`, `tls` or `none`),
+    /// `SMTP_USERNAME`, `SMTP_PASSWORD` and `SMTP_AUTH` (`plain` or `login`).
+    /// `EMAIL_APP_ACCOUNT` and `EMAIL_APP_PASSWORD` are still accepted as the credentials.
+    pub fn from_env() -> Result<Self, String> {
+        let host: String = env::var("SMTP_HOST").unwrap_or(String::from("smtp.gmail.com"));
+
+        let tls: SmtpTls = match env::var("SMTP_TLS").unwrap_or(String::from("starttls")).to_lowercase().as_str() {
+            "starttls" => SmtpTls::StartTls,
+            "tls" => SmtpTls::Tls,
+            "none" => SmtpTls::None,
+            other => {
+                return Err(format!("SMTP_TLS must be one of starttls, tls or none, got: {}", other));
+            }
+        };
+
+        let port: u16 = match env::var("SMTP_PORT") {
+            Ok(port) => port.parse::<u16>().map_err(|_| String::from("SMTP_PORT must be a valid number"))?,
+            Err(_) => tls.default_port(),
+        };
+
+        let mechanism: Mechanism = match env::var("SMTP_AUTH").unwrap_or(String::from("plain")).to_lowercase().as_str() {
+            "plain" => Mechanism::Plain,
+            "login" => Mechanism::Login,
+            other => {
+                return Err(format!("SMTP_AUTH must be one of plain or login, got: {}", other));
+            }
+        };
+
+        let username: Option<String> = env::var("SMTP_USERNAME").or(env::var("EMAIL_APP_ACCOUNT")).ok();
+        let password: Option<String> = env::var("SMTP_PASSWORD").or(env::var("EMAIL_APP_PASSWORD")).ok();
+
+
+        // Setting up email sender
+        let tls: Tls = match tls {
+            SmtpTls::None => Tls::None,
+            SmtpTls::StartTls | SmtpTls::Tls => {
+                let parameters: TlsParameters = TlsParameters::new(host.clone())
+                    .map_err(|err| format!("There's an error when trying to set up SMTP TLS. Error: {}", err))?;
+
+                if tls == SmtpTls::Tls { Tls::Wrapper(parameters) } else { Tls::Required(parameters) }
+            }
+        };
+
+        let mut builder = SmtpTransport::builder_dangerous(host)
+            .port(port)
+            .tls(tls);
+
+        match (username, password) {
+            (Some(username), Some(password)) => {
+                builder = builder
+                    // Put the credentials
+                    .credentials(Credentials::new(username, password))
+                    // Set the authentication mechanism
+                    .authentication(vec![mechanism]);
+            },
+            (None, None) => (),
+            _ => {
+                return Err(String::from("SMTP_USERNAME and SMTP_PASSWORD have to be set together"));
+            }
+        }
+
+        Ok(Self { transport: builder.build() })
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, message: Message) -> Result<(), String> {
+        let transport: SmtpTransport = self.transport.clone();
+
+        // The SMTP transport blocks, so keep it off the async workers
+        match tokio::task::spawn_blocking(move || transport.send(&message)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(err)) => Err(format!("There's an error when trying to send email. Error: {}", err)),
+            Err(err) => Err(format!("The email sending task has failed. Error: {}", err)),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::{collections::HashMap, env, fs, path::{Path, PathBuf}};
+use serde::Serialize;
+
+
+/// The templates the server sends, which have to exist in the default locale.
+pub const TEMPLATE_NAMES: &[&str] = &["verification", "email_change", "password_reset", "password_reset_link", "device_invitation", "password_changed_alert", "email_changed_alert"];
+
+/// An email ready to be sent, with an optional HTML alternative of its text.
+#[derive(Serialize, Clone, Debug)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+struct EmailTemplate {
+    subject: String,
+    text: String,
+    html: Option<String>,
+}
+
+
+/// Email templates read from `<directory>/<locale>/<name>.subject.txt`, `<name>.txt` and optionally `<name>.html`.
+/// Variables are written as `{{ name }}` and are HTML escaped in the HTML part.
+pub struct EmailTemplates {
+    default_locale: String,
+    templates: HashMap<(String, String), EmailTemplate>,
+}
+
+impl EmailTemplates {
+    /// Read the templates from `MAIL_TEMPLATES_DIR` (`templates/email` by default), falling back to
+    /// `MAIL_DEFAULT_LOCALE` (`en` by default) when none of the wanted locales has a template.
+    pub fn from_env() -> Result<Self, String> {
+        let directory: PathBuf = PathBuf::from(env::var("MAIL_TEMPLATES_DIR").unwrap_or(String::from("templates/email")));
+        let default_locale: String = env::var("MAIL_DEFAULT_LOCALE").unwrap_or(String::from("en")).to_lowercase();
+
+        Self::load(&directory, default_locale)
+    }
+
+    pub fn load(directory: &Path, default_locale: String) -> Result<Self, String> {
+        let read_error = |path: &Path, err: std::io::Error| format!("There's an error when trying to read {}. Error: {}", path.display(), err);
+        let mut templates: HashMap<(String, String), EmailTemplate> = HashMap::new();
+
+        for locale_entry in fs::read_dir(directory).map_err(|err| read_error(directory, err))? {
+            let locale_path: PathBuf = locale_entry.map_err(|err| read_error(directory, err))?.path();
+            if !locale_path.is_dir() {
+                continue;
+            }
+
+            let locale: String = locale_path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+
+            for entry in fs::read_dir(&locale_path).map_err(|err| read_error(&locale_path, err))? {
+                let subject_path: PathBuf = entry.map_err(|err| read_error(&locale_path, err))?.path();
+                let file_name: String = subject_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+
+                // Every template starts with its subject
+                let Some(name) = file_name.strip_suffix(".subject.txt") else {
+                    continue;
+                };
+
+                let text_path: PathBuf = locale_path.join(format!("{}.txt", name));
+                let html_path: PathBuf = locale_path.join(format!("{}.html", name));
+
+                let template: EmailTemplate = EmailTemplate {
+                    subject: fs::read_to_string(&subject_path).map_err(|err| read_error(&subject_path, err))?.trim().to_string(),
+                    text: fs::read_to_string(&text_path).map_err(|err| read_error(&text_path, err))?,
+                    html: if html_path.exists() { Some(fs::read_to_string(&html_path).map_err(|err| read_error(&html_path, err))?) } else { None },
+                };
+
+                templates.insert((locale.clone(), name.to_string()), template);
+            }
+        }
+
+        let email_templates: Self = Self { default_locale, templates };
+
+        for name in TEMPLATE_NAMES {
+            if !email_templates.templates.contains_key(&(email_templates.default_locale.clone(), name.to_string())) {
+                return Err(format!("The {} email template is missing for the default locale {}", name, email_templates.default_locale));
+            }
+        }
+
+        Ok(email_templates)
+    }
+
+    /// Find the template in the first of `locales` that has it. Both the full tag and
+    /// its language are tried, so `en-us` falls back to `en`.
+    fn find(&self, name: &str, locales: &[String]) -> Option<&EmailTemplate> {
+        locales
+            .iter()
+            .flat_map(|locale| [locale.to_lowercase(), locale.split('-').next().unwrap_or_default().to_lowercase()])
+            .chain([self.default_locale.clone()])
+            .find_map(|locale| self.templates.get(&(locale, name.to_string())))
+    }
+
+    /// Render a template in the first of `locales` that has it, or in the default locale.
+    pub fn render(&self, name: &str, locales: &[String], variables: &[(&str, &str)]) -> Result<RenderedEmail, String> {
+        self.render_with(name, locales, variables, true)
+    }
+
+    /// Like [`Self::render`], but variables that aren't given are left as they are.
+    pub fn preview(&self, name: &str, locales: &[String], variables: &[(&str, &str)]) -> Result<RenderedEmail, String> {
+        self.render_with(name, locales, variables, false)
+    }
+
+    fn render_with(&self, name: &str, locales: &[String], variables: &[(&str, &str)], strict: bool) -> Result<RenderedEmail, String> {
+        let template: &EmailTemplate = match self.find(name, locales) {
+            Some(template) => template,
+            None => {
+                return Err(format!("There's no {} email template", name));
+            }
+        };
+
+        Ok(RenderedEmail {
+            subject: substitute(&template.subject, variables, false, strict)?,
+            text: substitute(&template.text, variables, false, strict)?,
+            html: match &template.html {
+                Some(html) => Some(substitute(html, variables, true, strict)?),
+                None => None,
+            },
+        })
+    }
+}
+
+
+fn escape_html(value: &str) -> String {
+    let mut escaped: String = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Replace every `{{ name }}` in `template` with its value.
+fn substitute(template: &str, variables: &[(&str, &str)], is_html: bool, strict: bool) -> Result<String, String> {
+    let mut rendered: String = String::with_capacity(template.len());
+    let mut rest: &str = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(length) = rest[start..].find("}}") else {
+            break;
+        };
+
+        let placeholder: &str = &rest[start..start + length + 2];
+        let name: &str = placeholder[2..placeholder.len() - 2].trim();
+        rendered.push_str(&rest[..start]);
+
+        match variables.iter().find(|(variable, _)| *variable == name) {
+            Some((_, value)) if is_html => rendered.push_str(&escape_html(value)),
+            Some((_, value)) => rendered.push_str(value),
+            None if strict => {
+                return Err(format!("The email template needs a value for {}", name));
+            },
+            None => rendered.push_str(placeholder),
+        }
+
+        rest = &rest[start + length + 2..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates() -> EmailTemplates {
+        let template = |subject: &str, text: &str| EmailTemplate { subject: subject.to_string(), text: text.to_string(), html: Some(format!("<p>{}</p>", text)) };
+
+        EmailTemplates {
+            default_locale: String::from("en"),
+            templates: HashMap::from([
+                ((String::from("en"), String::from("greeting")), template("Hi", "Hi {{ name }}")),
+                ((String::from("id"), String::from("greeting")), template("Hai", "Hai {{ name }}")),
+            ]),
+        }
+    }
+
+    #[test]
+    fn substituted_values_are_escaped_in_html_only() {
+        let value: &str = r#"<b>"Tom" & 'Jerry'</b>"#;
+
+        assert_eq!(substitute("{{ name }}", &[("name", value)], false, true).unwrap(), value);
+        assert_eq!(
+            substitute("<p>{{name}}</p>", &[("name", value)], true, true).unwrap(),
+            "<p>&lt;b&gt;&quot;Tom&quot; &amp; &#39;Jerry&#39;&lt;/b&gt;</p>"
+        );
+
+        // The template itself isn't escaped
+        assert_eq!(escape_html("a<b"), "a&lt;b");
+        assert_eq!(substitute("<b>{{ name }}</b>", &[("name", "a")], true, true).unwrap(), "<b>a</b>");
+    }
+
+    #[test]
+    fn missing_variables() {
+        assert!(substitute("Hi {{ name }}", &[], false, true).is_err());
+        assert_eq!(substitute("Hi {{ name }}", &[], false, false).unwrap(), "Hi {{ name }}");
+        assert_eq!(substitute("Hi {{ name", &[("name", "a")], false, true).unwrap(), "Hi {{ name");
+    }
+
+    #[test]
+    fn missing_locale_falls_back_to_the_default() {
+        let templates: EmailTemplates = templates();
+        let variables: &[(&str, &str)] = &[("name", "Ana")];
+
+        assert_eq!(templates.render("greeting", &[String::from("id")], variables).unwrap().text, "Hai Ana");
+        // The language of a full tag is tried too
+        assert_eq!(templates.render("greeting", &[String::from("id-ID")], variables).unwrap().text, "Hai Ana");
+        assert_eq!(templates.render("greeting", &[String::from("fr"), String::from("id")], variables).unwrap().text, "Hai Ana");
+
+        let email: RenderedEmail = templates.render("greeting", &[String::from("fr")], variables).unwrap();
+        assert_eq!(email.subject, "Hi");
+        assert_eq!(email.html.as_deref(), Some("<p>Hi Ana</p>"));
+        assert_eq!(templates.render("greeting", &[], variables).unwrap().text, "Hi Ana");
+
+        assert!(templates.render("farewell", &[String::from("en")], variables).is_err());
+    }
+
+    #[test]
+    fn security_alerts_render_in_every_locale() {
+        let templates: EmailTemplates = EmailTemplates::load(Path::new("templates/email"), String::from("en")).unwrap();
+        let variables: &[(&str, &str)] = &[("username", "ana"), ("time", "2026-10-18 08:30 UTC"), ("new_email", "ana@example.com")];
+
+        for locale in ["en", "id"] {
+            for name in ["password_changed_alert", "email_changed_alert"] {
+                let email: RenderedEmail = templates.render(name, &[String::from(locale)], variables).unwrap();
+                assert!(email.text.contains("2026-10-18 08:30 UTC"), "{} in {}", name, locale);
+                assert!(email.html.is_some(), "{} in {}", name, locale);
+            }
+        }
+    }
+}
@@ -1,9 +1,10 @@
 #[macro_use] extern crate rocket;
 
 use std::{env, net::IpAddr};
-use wms_api::{routes, types::WebSocketManager, websocket};
+use wms_api::{mail::{outbox::run_outbox_worker, MailService}, rate_limit::{self, RateLimiter}, routes, telemetry::{export::ExportSlots, ingest::ReadingIngest, retention::run_telemetry_maintenance}, types::WebSocketManager, websocket::{self, presence::reconcile_device_status}};
 use dotenvy::dotenv;
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use rocket::fairing::AdHoc;
 use tokio::spawn;
 
  
@@ -45,25 +46,63 @@ async fn rocket() -> _ {
     let pool: Pool<Postgres> = match pool {
         Ok(res) => res,
         Err(err) => {
-            log::error!("Error when setting up database connection. Error: {}", err.to_string());
+            log::error!("Error when setting up database connection. Error: {}", err);
             panic!("There's an error when setting up database connection.");
         }
     };
 
+    // Apply pending database migrations
+    match sqlx::migrate!().run(&pool).await {
+        Ok(_) => (),
+        Err(err) => {
+            log::error!("Error when running database migrations. Error: {}", err);
+            panic!("There's an error when running database migrations.");
+        }
+    }
+
+    // Devices left online by a crash have to reconnect to be online again
+    match reconcile_device_status(&pool).await {
+        Ok(0) => (),
+        Ok(count) => log::warn!("{} devices were still marked as online and have been set offline", count),
+        Err(err) => {
+            log::error!("Error when reconciling device status. Error: {}", err);
+            panic!("There's an error when reconciling device status.");
+        }
+    }
+
+    // Setting up the mailer, so a bad mail configuration fails at startup instead of mid-request
+    let mail_service: MailService = match MailService::from_env() {
+        Ok(res) => res,
+        Err(err) => {
+            log::error!("Error when setting up the mailer. Error: {}", err);
+            panic!("There's an error when setting up the mailer.");
+        }
+    };
+
+    // Deliver the queued emails in the background
+    spawn(run_outbox_worker(pool.clone(), mail_service.clone()));
+
+    // Roll up the device readings and delete the expired ones in the background
+    spawn(run_telemetry_maintenance(pool.clone()));
+
+    // Store the readings devices send in batches, away from the sockets
+    let reading_ingest: ReadingIngest = ReadingIngest::start(pool.clone());
+
     let ws_manager: WebSocketManager = WebSocketManager::new();
 
     let ws_manager_instance: WebSocketManager = ws_manager.clone();
     let pool_instance: Pool<Postgres> = pool.clone();
     let ws_manager_instance_2: WebSocketManager = ws_manager.clone();
+    let reading_ingest_instance: ReadingIngest = reading_ingest.clone();
     spawn(async move {
         tokio::select! {
-            _ = websocket::core::run_websocket_server(ws_manager_instance, pool_instance) => (),
+            _ = websocket::core::run_websocket_server(ws_manager_instance, pool_instance, reading_ingest_instance) => (),
             _ = tokio::signal::ctrl_c() => {
                 let shutdown_result = ws_manager_instance_2.shutdown().await;
                 match shutdown_result {
                     Ok(_) => (),
                     Err(err) => {
-                        log::error!("There's an error when shutting down all of the websocket connection. Error: {}", err.to_string());
+                        log::error!("There's an error when shutting down all of the websocket connection. Error: {}", err);
                     }
                 }
             }
@@ -75,6 +114,20 @@ async fn rocket() -> _ {
         .manage(pool)
         // Setting up web socket manager for web socket connection
         .manage(ws_manager)
+        // Setting up the mailer for outgoing emails
+        .manage(mail_service)
+        // Setting up the readings ingestion, for its metrics
+        .manage(reading_ingest)
+        // Setting up the slots readings exports run in, so they can't take every database connection
+        .manage(ExportSlots::new())
+        // Setting up rate limiting for the routes that are prone to abuse
+        .attach(RateLimiter::new(rate_limit::default_policies()))
+        // Store the queued readings before the server stops
+        .attach(AdHoc::on_shutdown("Readings ingestion", |rocket| Box::pin(async move {
+            if let Some(reading_ingest) = rocket.state::<ReadingIngest>() {
+                reading_ingest.shutdown().await;
+            }
+        })))
         // Konfigurasi rocket
         .configure(
             rocket::Config::figment()
@@ -108,12 +161,44 @@ async fn rocket() -> _ {
             routes::user::register_email::post,
             routes::user::verify_email::post,
             routes::user::create_user::post,
-            routes::devices::this::get
+            routes::user::sessions::get,
+            routes::user::sessions::delete,
+            routes::user::sessions::delete_all,
+            routes::user::forgot_password::post,
+            routes::user::reset_password::post,
+            routes::user::update_user::patch,
+            routes::user::change_password::post,
+            routes::user::change_email::post,
+            routes::user::verify_email_change::post,
+            routes::devices::this::get,
+            routes::devices::this::get_one,
+            routes::devices::this::patch,
+            routes::devices::this::delete,
+            routes::devices::this::rotate_token,
+            routes::devices::create_device::post,
+            routes::devices::claim_device::post,
+            routes::devices::members::get,
+            routes::devices::members::patch,
+            routes::devices::members::delete,
+            routes::devices::invitations::post,
+            routes::devices::invitations::get,
+            routes::devices::invitations::get_sent,
+            routes::devices::invitations::accept,
+            routes::devices::invitations::delete,
+            routes::devices::invitations::revoke,
+            routes::devices::readings::get,
+            routes::devices::readings::export,
+            routes::devices::readings::export_all,
+            routes::admin::email_outbox::get,
+            routes::admin::email_templates::preview,
+            routes::admin::devices::post,
+            routes::admin::telemetry::ingest
         ])
         // Register catchers
         .register("/", catchers![
             routes::catchers::not_found,
             routes::catchers::unauthorized,
+            routes::catchers::forbidden,
             routes::catchers::too_many_requests
         ])
 }
\ No newline at end of file
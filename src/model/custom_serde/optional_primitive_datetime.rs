@@ -30,14 +30,14 @@ impl<'de> de::Visitor<'de> for OptionalPrimitiveDateTimeVisitor {
   fn visit_none<E>(self) -> Result<Self::Value, E>
       where
         E: de::Error, {
-    return Ok(None);
+    Ok(None)
   }
   
   fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
     where
       E: de::Error, {
 
-    if v == "" {
+    if v.is_empty() {
       return Ok(None);
     }
     
@@ -9,13 +9,11 @@ pub struct User {
   pub username: Option<String>,
   pub password: Option<String>,
   pub email: String,
-  pub verification_token: Option<String>,
-  pub access_token: Option<String>,
 
   #[serde(with = "custom_serde::primitive_datetime")]
   pub created_at: PrimitiveDateTime,
-  #[serde(with = "custom_serde::optional_primitive_datetime")]
-  pub access_token_expire: Option<PrimitiveDateTime>
+  pub locale: Option<String>,
+  pub readings_retention_days: Option<i32>
 }
 
 #[derive(FromRow, Serialize, Deserialize, Clone, Debug)]
@@ -26,12 +24,113 @@ pub struct Device {
   pub access_token: String,
   pub device_name: String,
   pub description: Option<String>,
-  pub status: bool
+  pub status: bool,
+  pub tags: Vec<String>,
+  #[serde(with = "custom_serde::optional_primitive_datetime")]
+  pub last_seen_at: Option<PrimitiveDateTime>,
+  pub readings_retention_days: Option<i32>
 }
 
 #[derive(FromRow, Serialize, Deserialize, Clone, Debug)]
 pub struct Connection {
   pub id: String,
   pub user_id: String,
-  pub device_id: String
-}
\ No newline at end of file
+  pub device_id: String,
+  pub role: String
+}
+
+#[derive(FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct Session {
+  pub id: String,
+  pub user_id: String,
+  pub access_token: String,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub created_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub last_used_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub expire_at: PrimitiveDateTime,
+  pub user_agent: Option<String>,
+  pub ip_address: Option<String>
+}
+
+#[derive(FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct EmailVerification {
+  pub id: String,
+  pub email: String,
+  pub token: String,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub created_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub expire_at: PrimitiveDateTime,
+  pub attempts: i32,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub last_sent_at: PrimitiveDateTime,
+  /// The account changing its address to `email`, or none for a new registration
+  pub user_id: Option<String>
+}
+
+#[derive(FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct PasswordReset {
+  pub id: String,
+  pub user_id: String,
+  pub token: String,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub created_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub expire_at: PrimitiveDateTime
+}
+
+#[derive(FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct EmailOutbox {
+  pub id: String,
+  pub recipient: String,
+  pub subject: String,
+  pub body: String,
+  pub status: String,
+  pub attempts: i32,
+  pub last_error: Option<String>,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub next_attempt_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub created_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::optional_primitive_datetime")]
+  pub sent_at: Option<PrimitiveDateTime>,
+  pub html_body: Option<String>
+}
+
+#[derive(FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct DeviceClaim {
+  pub device_id: String,
+  pub code: String,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub created_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub expire_at: PrimitiveDateTime
+}
+
+#[derive(FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct DeviceInvitation {
+  pub id: String,
+  pub device_id: String,
+  pub email: String,
+  pub role: String,
+  pub invited_by: String,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub created_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub expire_at: PrimitiveDateTime
+}
+
+#[derive(FromRow, Serialize, Deserialize, Clone, Debug)]
+pub struct DeviceReading {
+  pub id: i64,
+  pub device_id: String,
+  pub sensor: String,
+  pub numeric_value: Option<f64>,
+  pub text_value: Option<String>,
+  #[serde(with = "custom_serde::optional_primitive_datetime")]
+  pub device_time: Option<PrimitiveDateTime>,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub received_at: PrimitiveDateTime
+}
@@ -0,0 +1,320 @@
+use std::{collections::HashMap, env, sync::Mutex, time::{Duration, Instant}};
+use rocket::{fairing::{Fairing, Info, Kind}, http::{uri::Origin, Method, Status}, response::Responder, Data, Request, Response};
+use crate::{routes::catchers::too_many_requests, util::client_ip::client_ip};
+
+
+/// Where the requests counted by a policy are grouped by.
+#[derive(Clone, Debug)]
+pub enum RateLimitKey {
+  /// The address of the client
+  Ip,
+  /// A string field of the JSON body, e.g. the username someone is trying to log in as
+  BodyField(&'static str)
+}
+
+/// A token bucket applied to every request matching `method` and `path`.
+#[derive(Clone, Debug)]
+pub struct RateLimitPolicy {
+  pub name: &'static str,
+  pub method: Method,
+  pub path: &'static str,
+  pub key: RateLimitKey,
+  /// How many requests can be made in a burst
+  pub capacity: u32,
+  /// How long it takes to refill an empty bucket
+  pub period: Duration
+}
+
+impl RateLimitPolicy {
+  /// Create a policy, overridable with an environment variable in the form of `<capacity>/<period in seconds>`.
+  pub fn new(name: &'static str, method: Method, path: &'static str, key: RateLimitKey, env_name: &str, capacity: u32, period_secs: u64) -> Self {
+    let (capacity, period_secs) = match env::var(env_name) {
+      Ok(value) => {
+        let parsed = value
+          .split_once('/')
+          .and_then(|(capacity, period)| Some((capacity.trim().parse::<u32>().ok()?, period.trim().parse::<u64>().ok()?)));
+
+        match parsed {
+          Some(res) => res,
+          None => panic!("{} must be in the form of <capacity>/<period in seconds>", env_name)
+        }
+      },
+      Err(_) => (capacity, period_secs)
+    };
+
+    Self {
+      name,
+      method,
+      path,
+      key,
+      capacity,
+      period: Duration::from_secs(period_secs)
+    }
+  }
+}
+
+/// The default policies, each of them can be tuned through its environment variable.
+pub fn default_policies() -> Vec<RateLimitPolicy> {
+  vec![
+    RateLimitPolicy::new("login_ip", Method::Post, "/user/login", RateLimitKey::Ip, "RATE_LIMIT_LOGIN_IP", 20, 60),
+    RateLimitPolicy::new("login_username", Method::Post, "/user/login", RateLimitKey::BodyField("username"), "RATE_LIMIT_LOGIN_USERNAME", 5, 300),
+    RateLimitPolicy::new("register_ip", Method::Post, "/user/register/1", RateLimitKey::Ip, "RATE_LIMIT_REGISTER_IP", 10, 3600),
+    RateLimitPolicy::new("register_email", Method::Post, "/user/register/1", RateLimitKey::BodyField("email"), "RATE_LIMIT_REGISTER_EMAIL", 3, 600),
+    RateLimitPolicy::new("verify_ip", Method::Post, "/user/register/2", RateLimitKey::Ip, "RATE_LIMIT_VERIFY_IP", 10, 60),
+    RateLimitPolicy::new("forgot_password_ip", Method::Post, "/user/password/forgot", RateLimitKey::Ip, "RATE_LIMIT_FORGOT_PASSWORD_IP", 10, 3600),
+    RateLimitPolicy::new("forgot_password_email", Method::Post, "/user/password/forgot", RateLimitKey::BodyField("email"), "RATE_LIMIT_FORGOT_PASSWORD_EMAIL", 3, 600),
+    RateLimitPolicy::new("reset_password_ip", Method::Post, "/user/password/reset", RateLimitKey::Ip, "RATE_LIMIT_RESET_PASSWORD_IP", 10, 60),
+    RateLimitPolicy::new("change_password_ip", Method::Post, "/user/password", RateLimitKey::Ip, "RATE_LIMIT_CHANGE_PASSWORD_IP", 10, 300),
+    RateLimitPolicy::new("change_email_ip", Method::Post, "/user/email", RateLimitKey::Ip, "RATE_LIMIT_CHANGE_EMAIL_IP", 10, 3600),
+    RateLimitPolicy::new("verify_email_change_ip", Method::Post, "/user/email/verify", RateLimitKey::Ip, "RATE_LIMIT_VERIFY_EMAIL_CHANGE_IP", 10, 60),
+    RateLimitPolicy::new("claim_device_ip", Method::Post, "/device/claim", RateLimitKey::Ip, "RATE_LIMIT_CLAIM_DEVICE_IP", 10, 60)
+  ]
+}
+
+
+struct TokenBucket {
+  tokens: f64,
+  last_refill: Instant
+}
+
+/// Set on requests that have been rejected, so their response can be replaced with a 429 that tells
+/// when to retry.
+struct RateLimitExceeded(Option<Duration>);
+
+/// The path limited requests are rerouted to so they never reach their handler. Nothing is mounted
+/// there, the response is replaced once it's built.
+const RATE_LIMITED_PATH: &str = "/.rate-limited";
+
+/// When there are more buckets than this, the ones that have refilled are dropped.
+const MAX_BUCKETS_BEFORE_PRUNE: usize = 10_000;
+
+/// Only the start of a body can be looked at before the route reads it, this is as much as Rocket allows.
+const BODY_PEEK_SIZE: usize = 512;
+/// The key requests whose body field can't be read are counted under, all together. Otherwise a
+/// body too long to look at, or without the field, wouldn't be limited by the field at all.
+const UNREADABLE_BODY_KEY: &str = "<unreadable body>";
+
+
+/// A fairing that rejects requests with 429 once one of its policies runs out of tokens.
+pub struct RateLimiter {
+  enabled: bool,
+  policies: Vec<RateLimitPolicy>,
+  buckets: Mutex<HashMap<(&'static str, String), TokenBucket>>
+}
+
+impl RateLimiter {
+  pub fn new(policies: Vec<RateLimitPolicy>) -> Self {
+    Self {
+      enabled: env::var("RATE_LIMIT_ENABLED").unwrap_or(String::from("true")) != "false",
+      policies,
+      buckets: Mutex::new(HashMap::new())
+    }
+  }
+
+  /// Take a token from the bucket of `policy` for `key`. Returns how long to wait if the bucket is empty.
+  fn take(&self, policy: &RateLimitPolicy, key: String) -> Result<(), Duration> {
+    let now: Instant = Instant::now();
+    let refill_per_sec: f64 = policy.capacity as f64 / policy.period.as_secs_f64().max(f64::MIN_POSITIVE);
+    let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+
+    if buckets.len() > MAX_BUCKETS_BEFORE_PRUNE {
+      let max_period: Duration = self.policies.iter().map(|policy| policy.period).max().unwrap_or_default();
+      buckets.retain(|_, bucket| now.duration_since(bucket.last_refill) < max_period);
+    }
+
+    let bucket: &mut TokenBucket = buckets
+      .entry((policy.name, key))
+      .or_insert(TokenBucket { tokens: policy.capacity as f64, last_refill: now });
+
+    // Refill the tokens for the time that has passed
+    let elapsed: f64 = now.duration_since(bucket.last_refill).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(policy.capacity as f64);
+    bucket.last_refill = now;
+
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      return Ok(());
+    }
+
+    Err(Duration::from_secs_f64(((1.0 - bucket.tokens) / refill_per_sec).ceil()))
+  }
+
+  /// Find the key a policy groups this request by, if there's one.
+  async fn request_key(policy: &RateLimitPolicy, request: &Request<'_>, data: &mut Data<'_>) -> Option<String> {
+    match policy.key {
+      RateLimitKey::Ip => client_ip(request).map(|ip| ip.to_string()),
+      RateLimitKey::BodyField(field) => {
+        let body = data.peek(BODY_PEEK_SIZE).await;
+        let value: Option<String> = serde_json::from_slice::<serde_json::Value>(body)
+          .ok()
+          .and_then(|body| body.get(field)?.as_str().map(|value| value.trim().to_lowercase()));
+
+        Some(value.unwrap_or(String::from(UNREADABLE_BODY_KEY)))
+      }
+    }
+  }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+  fn info(&self) -> Info {
+    Info {
+      name: "Rate Limiter",
+      kind: Kind::Request | Kind::Response
+    }
+  }
+
+  async fn on_request(&self, request: &mut Request<'_>, data: &mut Data<'_>) {
+    if !self.enabled {
+      return;
+    }
+
+    // Check the policies that apply until one of them rejects the request, so requests that are
+    // rejected anyway don't use up the tokens of the others
+    let mut retry_after: Option<Duration> = None;
+    for policy in &self.policies {
+      if policy.method != request.method() || policy.path != request.uri().path().as_str() {
+        continue;
+      }
+
+      let Some(key) = Self::request_key(policy, request, data).await else {
+        continue;
+      };
+
+      if let Err(wait) = self.take(policy, key) {
+        log::warn!("Request to {} has been rate limited by policy: {}", policy.path, policy.name);
+        retry_after = Some(wait);
+        break;
+      }
+    }
+
+    // Reroute the request so it never reaches the handler
+    if let Some(wait) = retry_after {
+      request.local_cache(|| RateLimitExceeded(Some(wait)));
+      request.set_method(Method::Get);
+      request.set_uri(Origin::parse(RATE_LIMITED_PATH).unwrap());
+    }
+  }
+
+  async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+    let RateLimitExceeded(Some(wait)) = request.local_cache(|| RateLimitExceeded(None)) else {
+      return;
+    };
+
+    // Answer with the 429 catcher instead of whatever the rerouted request has got
+    if let Ok(limited_response) = too_many_requests(request).respond_to(request) {
+      response.merge(limited_response);
+    }
+    response.set_status(Status::TooManyRequests);
+    response.set_raw_header("Retry-After", wait.as_secs().max(1).to_string());
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use rocket::{http::ContentType, local::blocking::Client, post, routes, catchers};
+  use super::*;
+
+  fn policy(name: &'static str, key: RateLimitKey, capacity: u32, period_secs: u64) -> RateLimitPolicy {
+    RateLimitPolicy::new(name, Method::Post, "/user/login", key, "RATE_LIMIT_TEST_UNSET", capacity, period_secs)
+  }
+
+  /// Pretend `elapsed` has passed since the bucket was last touched.
+  fn rewind(limiter: &RateLimiter, name: &'static str, key: &str, elapsed: Duration) {
+    let mut buckets = limiter.buckets.lock().unwrap();
+    let bucket: &mut TokenBucket = buckets.get_mut(&(name, key.to_string())).unwrap();
+    bucket.last_refill -= elapsed;
+  }
+
+  #[test]
+  fn bucket_refills_over_time() {
+    let login: RateLimitPolicy = policy("login", RateLimitKey::Ip, 2, 60);
+    let limiter: RateLimiter = RateLimiter::new(vec![login.clone()]);
+
+    assert_eq!(limiter.take(&login, String::from("a")), Ok(()));
+    assert_eq!(limiter.take(&login, String::from("a")), Ok(()));
+    assert_eq!(limiter.take(&login, String::from("a")), Err(Duration::from_secs(30)));
+
+    // Every key has its own bucket
+    assert_eq!(limiter.take(&login, String::from("b")), Ok(()));
+
+    // A token comes back every 30 seconds
+    rewind(&limiter, "login", "a", Duration::from_secs(15));
+    assert_eq!(limiter.take(&login, String::from("a")), Err(Duration::from_secs(15)));
+    rewind(&limiter, "login", "a", Duration::from_secs(15));
+    assert_eq!(limiter.take(&login, String::from("a")), Ok(()));
+    assert!(limiter.take(&login, String::from("a")).is_err());
+
+    // The bucket never holds more than its capacity
+    rewind(&limiter, "login", "a", Duration::from_secs(3600));
+    assert_eq!(limiter.take(&login, String::from("a")), Ok(()));
+    assert_eq!(limiter.take(&login, String::from("a")), Ok(()));
+    assert!(limiter.take(&login, String::from("a")).is_err());
+  }
+
+
+  #[post("/user/login")]
+  fn login() -> &'static str {
+    "logged in"
+  }
+
+  fn client(policies: Vec<RateLimitPolicy>) -> Client {
+    let rocket = rocket::build()
+      .attach(RateLimiter::new(policies))
+      .mount("/", routes![login])
+      .register("/", catchers![too_many_requests]);
+
+    Client::untracked(rocket).unwrap()
+  }
+
+  fn log_in(client: &Client, username: &str) -> (Status, Option<String>, String) {
+    let response = client
+      .post("/user/login")
+      .remote(([203, 0, 113, 7], 443).into())
+      .header(ContentType::JSON)
+      .body(format!("{{\"username\": \"{}\"}}", username))
+      .dispatch();
+
+    let retry_after: Option<String> = response.headers().get_one("Retry-After").map(String::from);
+    (response.status(), retry_after, response.into_string().unwrap_or_default())
+  }
+
+  #[test]
+  fn limited_requests_get_429_with_retry_after() {
+    let client: Client = client(vec![policy("login_ip", RateLimitKey::Ip, 1, 60)]);
+
+    assert_eq!(log_in(&client, "alice"), (Status::Ok, None, String::from("logged in")));
+
+    let (status, retry_after, body) = log_in(&client, "alice");
+    assert_eq!(status, Status::TooManyRequests);
+    assert_eq!(retry_after.as_deref(), Some("60"));
+    assert!(body.contains("You're limited!"));
+
+    // Requests without a known address can't be grouped by it
+    let response = client.post("/user/login").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+  }
+
+  #[test]
+  fn first_failing_policy_stops_the_rest() {
+    let client: Client = client(vec![
+      policy("login_username", RateLimitKey::BodyField("username"), 1, 300),
+      policy("login_ip", RateLimitKey::Ip, 2, 60)
+    ]);
+
+    assert_eq!(log_in(&client, "alice").0, Status::Ok);
+
+    // Rejected by the username policy before the IP policy takes a token
+    let (status, retry_after, _) = log_in(&client, "Alice");
+    assert_eq!(status, Status::TooManyRequests);
+    assert_eq!(retry_after.as_deref(), Some("300"));
+
+    // So the IP still has one left
+    assert_eq!(log_in(&client, "bob").0, Status::Ok);
+
+    let (status, retry_after, _) = log_in(&client, "carol");
+    assert_eq!(status, Status::TooManyRequests);
+    assert_eq!(retry_after.as_deref(), Some("30"));
+  }
+}
@@ -0,0 +1,32 @@
+use rocket::{http::Status, post, response::status::Created, serde::json::Json, State};
+use sqlx::{Pool, Postgres};
+use crate::{auth::guards::AuthenticatedAdmin, routes::devices::create_device::{insert_device, CreateDeviceRequestType, CreateDeviceReturnType}};
+
+
+/// Create a device that doesn't belong to anyone yet, to be flashed with the returned access token.
+/// Whoever gets the device claims it with the code it asks for, see `/device/claim`.
+#[post("/admin/device", data = "<create_device_data>")]
+pub async fn post(create_device_data: Json<CreateDeviceRequestType>, admin: AuthenticatedAdmin, db: &State<Pool<Postgres>>) -> Result<Created<Json<CreateDeviceReturnType>>, Status> {
+  let (device_name, description) = match create_device_data.validated() {
+    Some(res) => res,
+    None => {
+      return Err(Status::BadRequest);
+    }
+  };
+
+  let (device_data, access_token) = match insert_device(db.inner(), device_name, description).await {
+    Ok(res) => res,
+    Err(err) => {
+      log::error!("There's an error when trying to create an unclaimed device. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+
+  log::info!("Unclaimed device {} has been created by {}", device_data.id, admin.user.id);
+
+  let location: String = format!("/device/{}", device_data.id);
+  Ok(Created::new(location).body(Json(CreateDeviceReturnType {
+    device_data: device_data.into(),
+    access_token
+  })))
+}
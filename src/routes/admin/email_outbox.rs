@@ -0,0 +1,58 @@
+use rocket::{get, http::Status, serde::json::Json, State, time::PrimitiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::{auth::guards::AuthenticatedAdmin, model::custom_serde};
+
+/// A queued email without its body, which may hold tokens. Bodies are cleared once an email has
+/// been sent or is dead, so dead emails can be looked into but not sent again.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExposedEmailOutbox {
+  pub id: String,
+  pub recipient: String,
+  pub subject: String,
+  pub status: String,
+  pub attempts: i32,
+  pub last_error: Option<String>,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub next_attempt_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub created_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::optional_primitive_datetime")]
+  pub sent_at: Option<PrimitiveDateTime>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetEmailOutboxReturnType {
+  pub emails: Vec<ExposedEmailOutbox>
+}
+
+
+/// List the queued emails with the given status, the dead letters by default, newest first.
+#[get("/admin/email-outbox?<status>&<limit>")]
+pub async fn get(status: Option<String>, limit: Option<i64>, _admin: AuthenticatedAdmin, db: &State<Pool<Postgres>>) -> Result<Json<GetEmailOutboxReturnType>, Status> {
+  let status: String = status.unwrap_or(String::from("dead"));
+  if !["pending", "sent", "dead"].contains(&status.as_str()) {
+    return Err(Status::BadRequest);
+  }
+
+  let raw_emails_data: Result<Vec<ExposedEmailOutbox>, sqlx::Error> = sqlx::query_as!(
+    ExposedEmailOutbox,
+    "SELECT id, recipient, subject, status, attempts, last_error, next_attempt_at, created_at, sent_at FROM email_outbox
+    WHERE status = $1 ORDER BY created_at DESC LIMIT $2",
+    status,
+    limit.unwrap_or(50).clamp(1, 500)
+  )
+  .fetch_all(db.inner())
+  .await;
+
+  let emails_data: Vec<ExposedEmailOutbox> = match raw_emails_data {
+    Ok(data) => data,
+    Err(err) => {
+      log::error!("There's an error when trying to get the queued emails. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+
+  Ok(Json(GetEmailOutboxReturnType { emails: emails_data }))
+}
+
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+use rocket::{get, http::Status, response::{content::RawHtml, Responder}, serde::json::Json};
+use crate::{auth::guards::AuthenticatedAdmin, mail::templates::{EmailTemplates, RenderedEmail}};
+
+#[derive(Responder)]
+pub enum PreviewReturnType {
+  Html(RawHtml<String>),
+  Text(String),
+  Json(Json<RenderedEmail>)
+}
+
+
+/// Render an email template for designers, read fresh from the disk so changes show up without a restart.
+/// The other query fields are used as the variables, the ones that aren't given are left in place.
+/// `format` is `html`, `text` or JSON with both parts by default.
+#[get("/admin/email-templates/<name>/preview?<locale>&<format>&<variables..>")]
+pub async fn preview(name: &str, locale: Option<String>, format: Option<String>, variables: HashMap<String, String>, _admin: AuthenticatedAdmin) -> Result<PreviewReturnType, Status> {
+  let templates: EmailTemplates = match EmailTemplates::from_env() {
+    Ok(res) => res,
+    Err(err) => {
+      log::error!("There's an error when trying to load the email templates. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+
+  let locales: Vec<String> = locale.into_iter().collect();
+  let variables: Vec<(&str, &str)> = variables.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+
+  let email: RenderedEmail = match templates.preview(name, &locales, &variables) {
+    Ok(email) => email,
+    Err(err) => {
+      log::warn!("Email template preview has failed. {}", err);
+      return Err(Status::NotFound);
+    }
+  };
+
+  match format.as_deref() {
+    Some("html") => match email.html {
+      Some(html) => Ok(PreviewReturnType::Html(RawHtml(html))),
+      None => Err(Status::NotFound)
+    },
+    Some("text") => Ok(PreviewReturnType::Text(email.text)),
+    _ => Ok(PreviewReturnType::Json(Json(email)))
+  }
+}
@@ -0,0 +1,4 @@
+pub mod email_outbox;
+pub mod email_templates;
+pub mod devices;
+pub mod telemetry;
\ No newline at end of file
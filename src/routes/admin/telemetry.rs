@@ -0,0 +1,10 @@
+use rocket::{get, serde::json::Json, State};
+use crate::{auth::guards::AuthenticatedAdmin, telemetry::ingest::{IngestMetrics, ReadingIngest}};
+
+
+/// How the readings ingestion is keeping up: how full its queue is, how many readings have been
+/// stored or dropped, and how long the last batch took to store.
+#[get("/admin/telemetry/ingest")]
+pub async fn ingest(_admin: AuthenticatedAdmin, reading_ingest: &State<ReadingIngest>) -> Json<IngestMetrics> {
+  Json(reading_ingest.metrics())
+}
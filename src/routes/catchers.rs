@@ -9,27 +9,36 @@ pub struct DefaultReturnType {
 // Catchers
 #[catch(404)]
 pub fn not_found(_: &Request) -> Json<DefaultReturnType> {
-    return Json(
+    Json(
         DefaultReturnType {
             message: String::from("Not found")
         }
-    );
+    )
 }
 
 #[catch(401)]
 pub fn unauthorized(_: &Request) -> Json<DefaultReturnType> {
-    return Json(
+    Json(
         DefaultReturnType {
             message: String::from("You're not authorized!")
         }
-    );
+    )
 }
 
 #[catch(429)]
 pub fn too_many_requests(_: &Request) -> Json<DefaultReturnType> {
-    return Json(
+    Json(
         DefaultReturnType {
             message: String::from("You're limited!")
         }
-    );
+    )
+}
+
+#[catch(403)]
+pub fn forbidden(_: &Request) -> Json<DefaultReturnType> {
+    Json(
+        DefaultReturnType {
+            message: String::from("You're not allowed to do that!")
+        }
+    )
 }
@@ -0,0 +1,120 @@
+use rocket::{http::Status, post, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::{auth::{device_claim::{consume_device_claim, normalize_claim_code}, guards::AuthenticatedUser, now_utc}, model::Device, routes::devices::this::ExposedDevice, types::WebSocketManager, util::generate_token, websocket::protocol::{ClaimedPayload, Envelope, Payload}};
+
+#[derive(Serialize, Deserialize)]
+pub struct ClaimDeviceRequestType {
+  /// The code shown by the device, either typed in or scanned from its QR code
+  code: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClaimDeviceReturnType {
+  device_data: ExposedDevice
+}
+
+
+/// Connect an unclaimed device to the user with the claim code it has shown.
+#[post("/device/claim", data = "<claim_device_data>")]
+pub async fn post(claim_device_data: Json<ClaimDeviceRequestType>, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>, ws_manager: &State<WebSocketManager>) -> Result<Json<ClaimDeviceReturnType>, Status> {
+  let code: String = normalize_claim_code(&claim_device_data.code);
+  if code.is_empty() {
+    return Err(Status::BadRequest);
+  }
+
+  // Users who haven't finished registering have to go through /user/register/3
+  if authenticated_user.user.username.is_none() {
+    return Err(Status::Forbidden);
+  }
+
+
+  // Use up the code and connect the device to the user
+  let device_data: Device;
+  {
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+      Ok(res) => res,
+      Err(err) => {
+        log::error!("There's an error when trying to start a transaction in device claim. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    let device_id: String = match consume_device_claim(&mut *transaction, &code).await {
+      Ok(Some(device_id)) => device_id,
+      Ok(None) => {
+        log::warn!("There's a failed attempt to claim a device by user: {}", authenticated_user.user.id);
+        return Err(Status::NotFound);
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to consume the claim code. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    // The code may have been issued right before someone else claimed the device
+    let connection_result = sqlx::query!(
+      "INSERT INTO connections(id, user_id, device_id, role) SELECT $1, $2, $3, 'owner' WHERE NOT EXISTS (SELECT 1 FROM connections WHERE device_id = $3)",
+      generate_token(10),
+      authenticated_user.user.id,
+      device_id
+    )
+    .execute(&mut *transaction)
+    .await;
+
+    match connection_result {
+      Ok(res) if res.rows_affected() > 0 => (),
+      Ok(_) => {
+        return Err(Status::Conflict);
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to connect the claimed device to its user. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+
+    let raw_device_data = sqlx::query_as!(
+      Device,
+      "SELECT * FROM devices WHERE id = $1",
+      device_id
+    )
+    .fetch_one(&mut *transaction)
+    .await;
+
+    device_data = match raw_device_data {
+      Ok(data) => data,
+      Err(err) => {
+        log::error!("There's an error when trying to get the claimed device data. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    match transaction.commit().await {
+      Ok(_) => (),
+      Err(err) => {
+        log::error!("There's an error when trying to commit the device claim. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+  }
+
+
+  // Let the user's live connections listen to the device right away
+  ws_manager.subscribe_user_to_device(&authenticated_user.user.id, &device_data.id).await;
+
+  let is_online: bool = ws_manager.is_device_online(&device_data.id).await;
+  if let Err(err) = ws_manager.send_user_message(&device_data.id, &Envelope::status(&device_data.id, is_online)).await {
+    log::error!("There's an error when trying to send the claimed device status to the user. Error: {}", err);
+  }
+
+  if is_online && let Err(err) = ws_manager.send_device_message(&device_data.id, &Envelope::new(Payload::Claimed(ClaimedPayload {})).with_ts(now_utc())).await {
+    log::error!("There's an error when trying to tell the device it has been claimed. Error: {}", err);
+  }
+
+  log::info!("Device {} has been claimed by user {}", device_data.id, authenticated_user.user.id);
+
+  Ok(Json(ClaimDeviceReturnType {
+    device_data: device_data.into()
+  }))
+}
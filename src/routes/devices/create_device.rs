@@ -0,0 +1,126 @@
+use rocket::{http::Status, post, response::status::Created, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, Pool, Postgres};
+use crate::{auth::{guards::AuthenticatedUser, now_utc}, model::Device, routes::devices::this::ExposedDevice, util::generate_token};
+
+/// The longest name a device can be given.
+pub const MAX_DEVICE_NAME_LENGTH: usize = 100;
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateDeviceRequestType {
+  pub device_name: String,
+  pub description: Option<String>
+}
+
+impl CreateDeviceRequestType {
+  /// The trimmed name and description, or none if the name is empty or too long.
+  pub fn validated(&self) -> Option<(&str, Option<&str>)> {
+    let device_name: &str = self.device_name.trim();
+    if device_name.is_empty() || device_name.chars().count() > MAX_DEVICE_NAME_LENGTH {
+      return None;
+    }
+
+    let description: Option<&str> = self.description.as_deref().map(|description| description.trim()).filter(|description| !description.is_empty());
+
+    Some((device_name, description))
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateDeviceReturnType {
+  pub device_data: ExposedDevice,
+  /// The token the device authenticates with. It's only shown here, so it has to be put on the device right away.
+  pub access_token: String
+}
+
+
+/// Insert a new device with a fresh access token, returned along with it.
+pub async fn insert_device<'e, E: PgExecutor<'e>>(executor: E, device_name: &str, description: Option<&str>) -> Result<(Device, String), sqlx::Error> {
+  let access_token: String = generate_token(48);
+
+  let device_data: Device = sqlx::query_as!(
+    Device,
+    "INSERT INTO devices(id, created_at, access_token, device_name, description) VALUES ($1, $2, $3, $4, $5) RETURNING *",
+    generate_token(10),
+    now_utc(),
+    access_token,
+    device_name,
+    description
+  )
+  .fetch_one(executor)
+  .await?;
+
+  Ok((device_data, access_token))
+}
+
+
+/// Create a device owned by the user and give back its access token.
+#[post("/device", data = "<create_device_data>")]
+pub async fn post(create_device_data: Json<CreateDeviceRequestType>, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<Created<Json<CreateDeviceReturnType>>, Status> {
+  let (device_name, description) = match create_device_data.validated() {
+    Some(res) => res,
+    None => {
+      return Err(Status::BadRequest);
+    }
+  };
+
+  // Users who haven't finished registering have to go through /user/register/3
+  if authenticated_user.user.username.is_none() {
+    return Err(Status::Forbidden);
+  }
+
+
+  // Create the device and connect it to the user
+  let device_data: Device;
+  let access_token: String;
+  {
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+      Ok(res) => res,
+      Err(err) => {
+        log::error!("There's an error when trying to start a transaction in device creation. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    let raw_device_data = insert_device(&mut *transaction, device_name, description).await;
+
+    (device_data, access_token) = match raw_device_data {
+      Ok(data) => data,
+      Err(err) => {
+        log::error!("There's an error when trying to create a device. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    let connection_result = sqlx::query!(
+      "INSERT INTO connections(id, user_id, device_id, role) VALUES ($1, $2, $3, 'owner')",
+      generate_token(10),
+      authenticated_user.user.id,
+      device_data.id
+    )
+    .execute(&mut *transaction)
+    .await;
+
+    if let Err(err) = connection_result {
+      log::error!("There's an error when trying to connect the new device to its user. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+
+    match transaction.commit().await {
+      Ok(_) => (),
+      Err(err) => {
+        log::error!("There's an error when trying to commit the device creation. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+  }
+
+
+  // Return the device with its access token
+  let location: String = format!("/device/{}", device_data.id);
+  Ok(Created::new(location).body(Json(CreateDeviceReturnType {
+    device_data: device_data.into(),
+    access_token
+  })))
+}
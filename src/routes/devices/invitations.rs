@@ -0,0 +1,392 @@
+use lettre::message::Mailbox;
+use rocket::{delete, get, http::Status, post, response::status::Created, serde::json::Json, State, time::PrimitiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::{auth::{device_access::{device_invitation_lifetime, require_device_role, DeviceRole}, guards::AuthenticatedUser, now_utc}, mail::{outbox::enqueue_email, templates::RenderedEmail, MailService}, model::{custom_serde, Device, DeviceInvitation}, routes::devices::this::ExposedDevice, types::WebSocketManager, util::generate_token};
+
+#[derive(Serialize, Deserialize)]
+pub struct InviteRequestType {
+  email: String,
+  role: String
+}
+
+/// An invitation as seen by the user it has been sent to.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExposedDeviceInvitation {
+  pub id: String,
+  pub device_id: String,
+  pub device_name: String,
+  pub role: String,
+  pub invited_by: Option<String>,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub expire_at: PrimitiveDateTime
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetInvitationsReturnType {
+  pub invitations: Vec<ExposedDeviceInvitation>
+}
+
+/// An invitation as seen by the owners of the device it's for.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExposedSentDeviceInvitation {
+  pub id: String,
+  pub email: String,
+  pub role: String,
+  pub invited_by: Option<String>,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub expire_at: PrimitiveDateTime
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetSentInvitationsReturnType {
+  pub invitations: Vec<ExposedSentDeviceInvitation>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AcceptInvitationReturnType {
+  pub device_data: ExposedDevice,
+  pub role: String
+}
+
+
+/// Invite someone by email to use the device with a role. Only owners can invite, and
+/// inviting the same email again replaces the previous invitation.
+#[post("/device/<device_id>/invitations", data = "<invite_data>")]
+pub async fn post(device_id: &str, invite_data: Json<InviteRequestType>, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>, mail: &State<MailService>) -> Result<Created<()>, Status> {
+  let role: DeviceRole = match DeviceRole::parse(&invite_data.role) {
+    Some(role) => role,
+    None => {
+      return Err(Status::BadRequest);
+    }
+  };
+
+  // Emails are matched regardless of case, so the same person isn't invited twice
+  let email: String = invite_data.email.trim().to_lowercase();
+  let to_address: Mailbox = match email.parse() {
+    Ok(address) => address,
+    Err(_) => {
+      return Err(Status::BadRequest);
+    }
+  };
+
+  require_device_role(db.inner(), &authenticated_user.user.id, device_id, DeviceRole::Owner).await?;
+
+
+  // Get the device, and the invited user if they already have an account
+  let device_data: Device;
+  let invitee_locale: Option<String>;
+  {
+    let raw_device_data = sqlx::query_as!(
+      Device,
+      "SELECT * FROM devices WHERE id = $1",
+      device_id
+    )
+    .fetch_one(db.inner())
+    .await;
+
+    device_data = match raw_device_data {
+      Ok(data) => data,
+      Err(err) => {
+        log::error!("There's an error when trying to get the device data. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    let raw_invitee_data = sqlx::query!(
+      "SELECT users.locale, EXISTS (SELECT 1 FROM connections WHERE connections.user_id = users.id AND connections.device_id = $2) AS \"is_connected!\"
+      FROM users WHERE lower(users.email) = lower($1)",
+      email,
+      device_id
+    )
+    .fetch_optional(db.inner())
+    .await;
+
+    invitee_locale = match raw_invitee_data {
+      Ok(Some(data)) if data.is_connected => {
+        // They can already use the device, change their role instead
+        return Err(Status::Conflict);
+      },
+      Ok(Some(data)) => data.locale,
+      Ok(None) => None,
+      Err(err) => {
+        log::error!("There's an error when trying to get the invited user data. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+  }
+
+
+  // Render the invitation email
+  let inviter: String = authenticated_user.user.username.clone().unwrap_or(authenticated_user.user.email.clone());
+  let expire_days: String = device_invitation_lifetime().whole_days().to_string();
+
+  let rendered_email: RenderedEmail = match mail.render(
+    "device_invitation",
+    &invitee_locale.into_iter().collect::<Vec<String>>(),
+    &[("inviter", &inviter), ("device_name", &device_data.device_name), ("role", role.as_str()), ("expire_days", &expire_days)]
+  ) {
+    Ok(rendered_email) => rendered_email,
+    Err(err) => {
+      log::error!("There's an error when trying to render the device invitation email. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+
+
+  // Store the invitation and queue its email
+  let invitation_id: String;
+  {
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+      Ok(res) => res,
+      Err(err) => {
+        log::error!("There's an error when trying to start a transaction in device invitation. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    let now: PrimitiveDateTime = now_utc();
+    let raw_invitation_id = sqlx::query_scalar!(
+      "INSERT INTO device_invitations(id, device_id, email, role, invited_by, created_at, expire_at) VALUES ($1, $2, $3, $4, $5, $6, $7)
+      ON CONFLICT (device_id, email) DO UPDATE SET role = EXCLUDED.role, invited_by = EXCLUDED.invited_by, created_at = EXCLUDED.created_at, expire_at = EXCLUDED.expire_at
+      RETURNING id",
+      generate_token(10),
+      device_id,
+      email,
+      role.as_str(),
+      authenticated_user.user.id,
+      now,
+      now + device_invitation_lifetime()
+    )
+    .fetch_one(&mut *transaction)
+    .await;
+
+    invitation_id = match raw_invitation_id {
+      Ok(id) => id,
+      Err(err) => {
+        log::error!("There's an error when trying to create the device invitation. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    if let Err(err) = enqueue_email(&mut *transaction, &to_address, &rendered_email).await {
+      log::error!("There's an error when trying to queue the device invitation email. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+
+    match transaction.commit().await {
+      Ok(_) => (),
+      Err(err) => {
+        log::error!("There's an error when trying to commit the device invitation. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+  }
+
+  mail.wake_outbox();
+
+  Ok(Created::new(format!("/device/invitations/{}", invitation_id)))
+}
+
+
+/// List the invitations sent to the user's email that can still be accepted.
+#[get("/device/invitations")]
+pub async fn get(authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<Json<GetInvitationsReturnType>, Status> {
+  let raw_invitations_data: Result<Vec<ExposedDeviceInvitation>, sqlx::Error> = sqlx::query_as!(
+    ExposedDeviceInvitation,
+    "SELECT device_invitations.id, device_invitations.device_id, devices.device_name, device_invitations.role,
+    COALESCE(users.username, users.email) AS invited_by, device_invitations.expire_at
+    FROM device_invitations
+    JOIN devices ON devices.id = device_invitations.device_id
+    JOIN users ON users.id = device_invitations.invited_by
+    WHERE lower(device_invitations.email) = lower($1) AND device_invitations.expire_at > $2
+    ORDER BY device_invitations.created_at DESC",
+    authenticated_user.user.email,
+    now_utc()
+  )
+  .fetch_all(db.inner())
+  .await;
+
+  match raw_invitations_data {
+    Ok(invitations) => Ok(Json(GetInvitationsReturnType { invitations })),
+    Err(err) => {
+      log::error!("There's an error when trying to get the device invitations. Error: {}", err);
+      Err(Status::InternalServerError)
+    }
+  }
+}
+
+
+/// List the invitations of the device that can still be accepted. Only owners can see them.
+#[get("/device/<device_id>/invitations")]
+pub async fn get_sent(device_id: &str, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<Json<GetSentInvitationsReturnType>, Status> {
+  require_device_role(db.inner(), &authenticated_user.user.id, device_id, DeviceRole::Owner).await?;
+
+  let raw_invitations_data: Result<Vec<ExposedSentDeviceInvitation>, sqlx::Error> = sqlx::query_as!(
+    ExposedSentDeviceInvitation,
+    "SELECT device_invitations.id, device_invitations.email, device_invitations.role,
+    COALESCE(users.username, users.email) AS invited_by, device_invitations.expire_at
+    FROM device_invitations
+    JOIN users ON users.id = device_invitations.invited_by
+    WHERE device_invitations.device_id = $1 AND device_invitations.expire_at > $2
+    ORDER BY device_invitations.created_at DESC",
+    device_id,
+    now_utc()
+  )
+  .fetch_all(db.inner())
+  .await;
+
+  match raw_invitations_data {
+    Ok(invitations) => Ok(Json(GetSentInvitationsReturnType { invitations })),
+    Err(err) => {
+      log::error!("There's an error when trying to get the sent device invitations. Error: {}", err);
+      Err(Status::InternalServerError)
+    }
+  }
+}
+
+
+/// Accept an invitation sent to the user's email and start using the device.
+#[post("/device/invitations/<invitation_id>/accept")]
+pub async fn accept(invitation_id: &str, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>, ws_manager: &State<WebSocketManager>) -> Result<Json<AcceptInvitationReturnType>, Status> {
+  // Users who haven't finished registering have to go through /user/register/3
+  if authenticated_user.user.username.is_none() {
+    return Err(Status::Forbidden);
+  }
+
+  let device_data: Device;
+  let invitation_data: DeviceInvitation;
+  {
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+      Ok(res) => res,
+      Err(err) => {
+        log::error!("There's an error when trying to start a transaction in accepting device invitation. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    // Use up the invitation
+    let raw_invitation_data = sqlx::query_as!(
+      DeviceInvitation,
+      "DELETE FROM device_invitations WHERE id = $1 AND lower(email) = lower($2) AND expire_at > $3 RETURNING *",
+      invitation_id,
+      authenticated_user.user.email,
+      now_utc()
+    )
+    .fetch_optional(&mut *transaction)
+    .await;
+
+    invitation_data = match raw_invitation_data {
+      Ok(Some(data)) => data,
+      Ok(None) => {
+        return Err(Status::NotFound);
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to consume the device invitation. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    // Connect the user to the device
+    let connection_result = sqlx::query!(
+      "INSERT INTO connections(id, user_id, device_id, role) VALUES ($1, $2, $3, $4) ON CONFLICT (user_id, device_id) DO NOTHING",
+      generate_token(10),
+      authenticated_user.user.id,
+      invitation_data.device_id,
+      invitation_data.role
+    )
+    .execute(&mut *transaction)
+    .await;
+
+    match connection_result {
+      Ok(res) if res.rows_affected() > 0 => (),
+      Ok(_) => {
+        return Err(Status::Conflict);
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to connect the invited user to the device. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+
+    let raw_device_data = sqlx::query_as!(
+      Device,
+      "SELECT * FROM devices WHERE id = $1",
+      invitation_data.device_id
+    )
+    .fetch_one(&mut *transaction)
+    .await;
+
+    device_data = match raw_device_data {
+      Ok(data) => data,
+      Err(err) => {
+        log::error!("There's an error when trying to get the shared device data. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    match transaction.commit().await {
+      Ok(_) => (),
+      Err(err) => {
+        log::error!("There's an error when trying to commit the device invitation acceptance. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+  }
+
+  // Let the user's live connections listen to the device right away
+  ws_manager.subscribe_user_to_device(&authenticated_user.user.id, &device_data.id).await;
+
+  Ok(Json(AcceptInvitationReturnType {
+    device_data: device_data.into(),
+    role: invitation_data.role
+  }))
+}
+
+
+/// Decline an invitation sent to the user's email.
+#[delete("/device/invitations/<invitation_id>")]
+pub async fn delete(invitation_id: &str, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<(), Status> {
+  let raw_delete_result = sqlx::query!(
+    "DELETE FROM device_invitations WHERE id = $1 AND lower(email) = lower($2)",
+    invitation_id,
+    authenticated_user.user.email
+  )
+  .execute(db.inner())
+  .await;
+
+  match raw_delete_result {
+    Ok(res) if res.rows_affected() > 0 => Ok(()),
+    Ok(_) => Err(Status::NotFound),
+    Err(err) => {
+      log::error!("There's an error when trying to decline the device invitation. Error: {}", err);
+      Err(Status::InternalServerError)
+    }
+  }
+}
+
+
+/// Withdraw an invitation of the device before it's accepted. Only owners can do this.
+#[delete("/device/<device_id>/invitations/<invitation_id>")]
+pub async fn revoke(device_id: &str, invitation_id: &str, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<(), Status> {
+  require_device_role(db.inner(), &authenticated_user.user.id, device_id, DeviceRole::Owner).await?;
+
+  let raw_delete_result = sqlx::query!(
+    "DELETE FROM device_invitations WHERE id = $1 AND device_id = $2",
+    invitation_id,
+    device_id
+  )
+  .execute(db.inner())
+  .await;
+
+  match raw_delete_result {
+    Ok(res) if res.rows_affected() > 0 => Ok(()),
+    Ok(_) => Err(Status::NotFound),
+    Err(err) => {
+      log::error!("There's an error when trying to withdraw the device invitation. Error: {}", err);
+      Err(Status::InternalServerError)
+    }
+  }
+}
@@ -0,0 +1,171 @@
+use rocket::{delete, get, http::Status, patch, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::{auth::{device_access::{require_device_role, DeviceRole}, guards::AuthenticatedUser}, types::WebSocketManager};
+
+/// A user the device is shared with.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExposedDeviceMember {
+  pub user_id: String,
+  pub username: Option<String>,
+  /// Only shown to owners
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub email: Option<String>,
+  pub role: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetMembersReturnType {
+  pub members: Vec<ExposedDeviceMember>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UpdateMemberRequestType {
+  role: String
+}
+
+
+/// List everyone the device is shared with. Any of them can see the list, but only owners see the emails.
+#[get("/device/<device_id>/users")]
+pub async fn get(device_id: &str, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<Json<GetMembersReturnType>, Status> {
+  let role: DeviceRole = require_device_role(db.inner(), &authenticated_user.user.id, device_id, DeviceRole::Viewer).await?;
+
+  let raw_members_data: Result<Vec<ExposedDeviceMember>, sqlx::Error> = sqlx::query_as!(
+    ExposedDeviceMember,
+    r#"SELECT users.id AS user_id, users.username, users.email AS "email?", connections.role FROM connections
+    JOIN users ON users.id = connections.user_id
+    WHERE connections.device_id = $1 ORDER BY users.username"#,
+    device_id
+  )
+  .fetch_all(db.inner())
+  .await;
+
+  match raw_members_data {
+    Ok(mut members) => {
+      if role != DeviceRole::Owner {
+        for member in &mut members {
+          member.email = None;
+        }
+      }
+
+      Ok(Json(GetMembersReturnType { members }))
+    },
+    Err(err) => {
+      log::error!("There's an error when trying to get the device members. Error: {}", err);
+      Err(Status::InternalServerError)
+    }
+  }
+}
+
+
+/// Change the role of someone the device is shared with. Only owners can do this, and not to themselves.
+#[patch("/device/<device_id>/users/<user_id>", data = "<update_member_data>")]
+pub async fn patch(device_id: &str, user_id: &str, update_member_data: Json<UpdateMemberRequestType>, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<Json<ExposedDeviceMember>, Status> {
+  let role: DeviceRole = match DeviceRole::parse(&update_member_data.role) {
+    Some(role) => role,
+    None => {
+      return Err(Status::BadRequest);
+    }
+  };
+
+  require_device_role(db.inner(), &authenticated_user.user.id, device_id, DeviceRole::Owner).await?;
+
+  // Owners step down by giving ownership to someone else and leaving, so a device always has one
+  if user_id == authenticated_user.user.id {
+    return Err(Status::Conflict);
+  }
+
+  let raw_member_data: Result<Option<ExposedDeviceMember>, sqlx::Error> = sqlx::query_as!(
+    ExposedDeviceMember,
+    r#"UPDATE connections SET role = $1 FROM users
+    WHERE connections.device_id = $2 AND connections.user_id = $3 AND users.id = connections.user_id
+    RETURNING users.id AS user_id, users.username, users.email AS "email?", connections.role"#,
+    role.as_str(),
+    device_id,
+    user_id
+  )
+  .fetch_optional(db.inner())
+  .await;
+
+  match raw_member_data {
+    Ok(Some(member)) => Ok(Json(member)),
+    Ok(None) => Err(Status::NotFound),
+    Err(err) => {
+      log::error!("There's an error when trying to change the device member role. Error: {}", err);
+      Err(Status::InternalServerError)
+    }
+  }
+}
+
+
+/// Stop sharing the device with someone. Owners can remove anyone, everyone else can only leave,
+/// and the last owner can't leave.
+#[delete("/device/<device_id>/users/<user_id>")]
+pub async fn delete(device_id: &str, user_id: &str, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>, ws_manager: &State<WebSocketManager>) -> Result<(), Status> {
+  let required_role: DeviceRole = if user_id == authenticated_user.user.id { DeviceRole::Viewer } else { DeviceRole::Owner };
+  require_device_role(db.inner(), &authenticated_user.user.id, device_id, required_role).await?;
+
+  {
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+      Ok(res) => res,
+      Err(err) => {
+        log::error!("There's an error when trying to start a transaction in device member removal. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    // Lock the owners so two of them can't both leave at the same time
+    let raw_owners_data = sqlx::query_scalar!(
+      "SELECT user_id FROM connections WHERE device_id = $1 AND role = 'owner' FOR UPDATE",
+      device_id
+    )
+    .fetch_all(&mut *transaction)
+    .await;
+
+    let owners: Vec<String> = match raw_owners_data {
+      Ok(data) => data,
+      Err(err) => {
+        log::error!("There's an error when trying to get the device owners. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    // Only remove an owner if there's another one left
+    if owners.len() == 1 && owners[0] == user_id {
+      return Err(Status::Conflict);
+    }
+
+    let raw_delete_result = sqlx::query!(
+      "DELETE FROM connections WHERE device_id = $1 AND user_id = $2",
+      device_id,
+      user_id
+    )
+    .execute(&mut *transaction)
+    .await;
+
+    match raw_delete_result {
+      Ok(res) if res.rows_affected() > 0 => (),
+      Ok(_) => {
+        return Err(Status::NotFound);
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to remove the device member. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+
+    match transaction.commit().await {
+      Ok(_) => (),
+      Err(err) => {
+        log::error!("There's an error when trying to commit the device member removal. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+  }
+
+  // Stop their live connections from receiving the device's data
+  ws_manager.unsubscribe_user_from_device(user_id, device_id).await;
+
+  Ok(())
+}
@@ -1 +1,6 @@
-pub mod this;
\ No newline at end of file
+pub mod this;
+pub mod create_device;
+pub mod claim_device;
+pub mod members;
+pub mod invitations;
+pub mod readings;
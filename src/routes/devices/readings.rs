@@ -0,0 +1,558 @@
+use futures_util::{Stream, StreamExt};
+use rocket::{get, http::Status, request::{FromRequest, Outcome}, response::{stream::ByteStream, Responder}, serde::json::Json, FromForm, Request, State, time::{Duration, PrimitiveDateTime, Time}};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::{auth::{device_access::{require_device_role, DeviceRole}, guards::AuthenticatedUser, now_utc}, model::custom_serde, telemetry::{export::{ExportEncoder, ExportFormat, ExportRow, ExportSlots}, fit_bucket, retention::{hourly_retention_cutoff, raw_retention_cutoff}, rollups::{rolled_up_to, HOURLY_ROLLUP}, parse_bucket, parse_query_time, readings_max_points}};
+
+/// How far back readings are looked for when no start is given.
+const DEFAULT_READINGS_RANGE_HOURS: i64 = 24;
+const HOUR_SECS: i64 = 3600;
+const DAY_SECS: i64 = 86400;
+
+#[derive(FromForm)]
+pub struct GetReadingsQuery {
+  sensor: Option<String>,
+  from: Option<String>,
+  to: Option<String>,
+  /// `raw`, `auto` (the default) or a width like `5m`
+  bucket: Option<String>
+}
+
+/// A single reading as the device has sent it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RawReadingPoint {
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub time: PrimitiveDateTime,
+  pub value: serde_json::Value,
+  #[serde(with = "custom_serde::optional_primitive_datetime")]
+  pub device_time: Option<PrimitiveDateTime>
+}
+
+/// The readings that have been received within a bucket. Buckets without readings are kept, with a count of 0.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BucketReadingPoint {
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub time: PrimitiveDateTime,
+  pub min: Option<f64>,
+  pub max: Option<f64>,
+  pub avg: Option<f64>,
+  pub count: i64,
+  /// The latest reading in the bucket, numeric or not
+  pub last: Option<serde_json::Value>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetReadingsReturnType {
+  pub sensor: String,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub from: PrimitiveDateTime,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub to: PrimitiveDateTime,
+  /// The width of the buckets in seconds, none when the readings are raw
+  pub bucket_secs: Option<i64>,
+  /// Whether the buckets come from the hourly and daily rollups, because the raw readings have
+  /// expired. Rollups only summarize numeric readings.
+  pub rollups: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub points: Option<Vec<RawReadingPoint>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub buckets: Option<Vec<BucketReadingPoint>>
+}
+
+struct RawReadingRow {
+  received_at: PrimitiveDateTime,
+  numeric_value: Option<f64>,
+  text_value: Option<String>,
+  device_time: Option<PrimitiveDateTime>
+}
+
+struct BucketReadingRow {
+  time: PrimitiveDateTime,
+  min: Option<f64>,
+  max: Option<f64>,
+  avg: Option<f64>,
+  count: i64,
+  last_numeric: Option<f64>,
+  last_text: Option<String>
+}
+
+fn reading_value(numeric_value: Option<f64>, text_value: Option<String>) -> Option<serde_json::Value> {
+  match (numeric_value, text_value) {
+    (Some(number), _) => Some(serde_json::Value::from(number)),
+    (None, Some(text)) => Some(serde_json::Value::from(text)),
+    (None, None) => None
+  }
+}
+
+
+/// Get the readings of one of the device's sensors between `from` and `to`, the last day by default.
+/// Readings come back raw when there are few enough of them, and are aggregated into buckets
+/// otherwise, or when a `bucket` width is asked for. Buckets are widened so there are never more
+/// than `READINGS_MAX_POINTS` of them.
+#[get("/device/<device_id>/readings?<query..>")]
+pub async fn get(device_id: &str, query: GetReadingsQuery, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<Json<GetReadingsReturnType>, Status> {
+  let sensor: String = match query.sensor.as_deref().map(|sensor| sensor.trim()) {
+    Some(sensor) if !sensor.is_empty() => sensor.to_string(),
+    _ => {
+      return Err(Status::BadRequest);
+    }
+  };
+
+  let to: PrimitiveDateTime = match &query.to {
+    Some(to) => parse_query_time(to).ok_or(Status::BadRequest)?,
+    None => now_utc()
+  };
+
+  let from: PrimitiveDateTime = match &query.from {
+    Some(from) => parse_query_time(from).ok_or(Status::BadRequest)?,
+    None => to - Duration::hours(DEFAULT_READINGS_RANGE_HOURS)
+  };
+
+  if from >= to {
+    return Err(Status::BadRequest);
+  }
+
+  // None means raw readings, if there aren't too many
+  let requested_bucket_secs: Option<i64> = match query.bucket.as_deref().unwrap_or("auto") {
+    "raw" | "auto" => None,
+    bucket => Some(parse_bucket(bucket).ok_or(Status::BadRequest)?)
+  };
+
+  require_device_role(db.inner(), &authenticated_user.user.id, device_id, DeviceRole::Viewer).await?;
+
+  let max_points: i64 = readings_max_points();
+
+  let raw_cutoff: PrimitiveDateTime = match raw_retention_cutoff(db.inner(), device_id).await {
+    Ok(raw_cutoff) => raw_cutoff,
+    Err(err) => {
+      log::error!("There's an error when trying to get the retention of the device readings. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+
+  if from < raw_cutoff {
+    let (bucket_secs, buckets) = get_rollup_buckets(db.inner(), device_id, &sensor, from, to, requested_bucket_secs, max_points).await?;
+    return Ok(Json(GetReadingsReturnType { sensor, from, to, bucket_secs: Some(bucket_secs), rollups: true, points: None, buckets: Some(buckets) }));
+  }
+
+  // Check if the raw readings fit
+  if requested_bucket_secs.is_none() {
+    let raw_points_data: Result<Vec<RawReadingRow>, sqlx::Error> = sqlx::query_as!(
+      RawReadingRow,
+      "SELECT received_at, numeric_value, text_value, device_time FROM device_readings
+      WHERE device_id = $1 AND sensor = $2 AND received_at >= $3 AND received_at < $4
+      ORDER BY received_at, id LIMIT $5",
+      device_id,
+      sensor,
+      from,
+      to,
+      max_points + 1
+    )
+    .fetch_all(db.inner())
+    .await;
+
+    let raw_points_data: Vec<RawReadingRow> = match raw_points_data {
+      Ok(data) => data,
+      Err(err) => {
+        log::error!("There's an error when trying to get the device readings. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    if raw_points_data.len() as i64 <= max_points {
+      let points: Vec<RawReadingPoint> = raw_points_data
+        .into_iter()
+        .filter_map(|row| Some(RawReadingPoint {
+          time: row.received_at,
+          value: reading_value(row.numeric_value, row.text_value)?,
+          device_time: row.device_time
+        }))
+        .collect();
+
+      return Ok(Json(GetReadingsReturnType { sensor, from, to, bucket_secs: None, rollups: false, points: Some(points), buckets: None }));
+    }
+  }
+
+
+  // Aggregate the readings into buckets, keeping the empty ones so charts show the gaps
+  let bucket_secs: i64 = fit_bucket((to - from).whole_seconds(), requested_bucket_secs.unwrap_or(1), max_points);
+
+  let raw_buckets_data: Result<Vec<BucketReadingRow>, sqlx::Error> = sqlx::query_as!(
+    BucketReadingRow,
+    r#"WITH buckets AS (
+      SELECT generate_series(
+        date_bin($5 * INTERVAL '1 second', $3, TIMESTAMP 'epoch'),
+        $4::TIMESTAMP - INTERVAL '1 microsecond',
+        $5 * INTERVAL '1 second'
+      ) AS bucket
+    ), readings AS (
+      SELECT date_bin($5 * INTERVAL '1 second', received_at, TIMESTAMP 'epoch') AS bucket, numeric_value, text_value, received_at FROM device_readings
+      WHERE device_id = $1 AND sensor = $2 AND received_at >= $3 AND received_at < $4
+    )
+    SELECT
+      buckets.bucket AS "time!",
+      MIN(readings.numeric_value) AS min,
+      MAX(readings.numeric_value) AS max,
+      AVG(readings.numeric_value) AS avg,
+      COUNT(readings.received_at) AS "count!",
+      (ARRAY_AGG(readings.numeric_value ORDER BY readings.received_at DESC) FILTER (WHERE readings.numeric_value IS NOT NULL))[1] AS last_numeric,
+      (ARRAY_AGG(readings.text_value ORDER BY readings.received_at DESC) FILTER (WHERE readings.text_value IS NOT NULL))[1] AS last_text
+    FROM buckets LEFT JOIN readings ON readings.bucket = buckets.bucket
+    GROUP BY buckets.bucket ORDER BY buckets.bucket"#,
+    device_id,
+    sensor,
+    from,
+    to,
+    bucket_secs as f64
+  )
+  .fetch_all(db.inner())
+  .await;
+
+  let raw_buckets_data: Vec<BucketReadingRow> = match raw_buckets_data {
+    Ok(data) => data,
+    Err(err) => {
+      log::error!("There's an error when trying to aggregate the device readings. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+
+  let buckets: Vec<BucketReadingPoint> = raw_buckets_data
+    .into_iter()
+    .map(|row| BucketReadingPoint {
+      time: row.time,
+      min: row.min,
+      max: row.max,
+      avg: row.avg,
+      count: row.count,
+      last: reading_value(row.last_numeric, row.last_text)
+    })
+    .collect();
+
+  Ok(Json(GetReadingsReturnType { sensor, from, to, bucket_secs: Some(bucket_secs), rollups: false, points: None, buckets: Some(buckets) }))
+}
+
+/// Aggregate readings into buckets from the rollups, for ranges that go back before the raw readings
+/// have expired. Days that are past the hourly retention come from the daily rollups, hours that
+/// haven't been rolled up yet from the raw readings, so buckets are whole hours or whole days.
+async fn get_rollup_buckets(db: &Pool<Postgres>, device_id: &str, sensor: &str, from: PrimitiveDateTime, to: PrimitiveDateTime, requested_bucket_secs: Option<i64>, max_points: i64) -> Result<(i64, Vec<BucketReadingPoint>), Status> {
+  let hourly_rolled_up_to: Option<PrimitiveDateTime> = match rolled_up_to(db, HOURLY_ROLLUP).await {
+    Ok(hourly_rolled_up_to) => hourly_rolled_up_to,
+    Err(err) => {
+      log::error!("There's an error when trying to get the progress of the readings rollups. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+  // Nothing has been rolled up yet, the raw readings are all there is
+  let hourly_rolled_up_to: PrimitiveDateTime = hourly_rolled_up_to.unwrap_or(from);
+
+  // The hourly rollups are kept from the first whole day after they start expiring
+  let hourly_cutoff: PrimitiveDateTime = hourly_retention_cutoff();
+  let daily_until: PrimitiveDateTime = hourly_cutoff.replace_time(Time::MIDNIGHT) + Duration::days(1);
+
+  let unit_secs: i64 = if from < daily_until { DAY_SECS } else { HOUR_SECS };
+  let bucket_secs: i64 = fit_bucket((to - from).whole_seconds(), requested_bucket_secs.unwrap_or(1).max(unit_secs), max_points);
+  let bucket_secs: i64 = (bucket_secs + unit_secs - 1) / unit_secs * unit_secs;
+
+  let raw_buckets_data: Result<Vec<BucketReadingRow>, sqlx::Error> = sqlx::query_as!(
+    BucketReadingRow,
+    r#"WITH buckets AS (
+      SELECT generate_series(
+        date_bin($5 * INTERVAL '1 second', $3, TIMESTAMP 'epoch'),
+        $4::TIMESTAMP - INTERVAL '1 microsecond',
+        $5 * INTERVAL '1 second'
+      ) AS bucket
+    ), summaries AS (
+      SELECT bucket, min, max, avg, count, last FROM device_reading_rollups_daily
+      WHERE device_id = $1 AND sensor = $2 AND bucket >= date_bin($5 * INTERVAL '1 second', $3, TIMESTAMP 'epoch') AND bucket < LEAST($4, $6)
+      UNION ALL
+      SELECT bucket, min, max, avg, count, last FROM device_reading_rollups_hourly
+      WHERE device_id = $1 AND sensor = $2 AND bucket >= GREATEST(date_bin($5 * INTERVAL '1 second', $3, TIMESTAMP 'epoch'), $6) AND bucket < LEAST($4, $7)
+      UNION ALL
+      SELECT date_bin(INTERVAL '1 hour', received_at, TIMESTAMP 'epoch'), MIN(numeric_value), MAX(numeric_value), AVG(numeric_value), COUNT(*),
+        (ARRAY_AGG(numeric_value ORDER BY received_at DESC, id DESC))[1]
+      FROM device_readings
+      WHERE device_id = $1 AND sensor = $2 AND numeric_value IS NOT NULL AND received_at >= GREATEST($3, $7) AND received_at < $4
+      GROUP BY 1
+    ), readings AS (
+      SELECT date_bin($5 * INTERVAL '1 second', bucket, TIMESTAMP 'epoch') AS bucket, bucket AS summary_bucket, min, max, avg, count, last FROM summaries
+    )
+    SELECT
+      buckets.bucket AS "time!",
+      MIN(readings.min) AS min,
+      MAX(readings.max) AS max,
+      SUM(readings.avg * readings.count) / NULLIF(SUM(readings.count), 0) AS avg,
+      COALESCE(SUM(readings.count), 0)::BIGINT AS "count!",
+      (ARRAY_AGG(readings.last ORDER BY readings.summary_bucket DESC) FILTER (WHERE readings.last IS NOT NULL))[1] AS last_numeric,
+      NULL::TEXT AS last_text
+    FROM buckets LEFT JOIN readings ON readings.bucket = buckets.bucket
+    GROUP BY buckets.bucket ORDER BY buckets.bucket"#,
+    device_id,
+    sensor,
+    from,
+    to,
+    bucket_secs as f64,
+    daily_until,
+    hourly_rolled_up_to
+  )
+  .fetch_all(db)
+  .await;
+
+  let raw_buckets_data: Vec<BucketReadingRow> = match raw_buckets_data {
+    Ok(data) => data,
+    Err(err) => {
+      log::error!("There's an error when trying to aggregate the device readings rollups. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+
+  let buckets: Vec<BucketReadingPoint> = raw_buckets_data
+    .into_iter()
+    .map(|row| BucketReadingPoint {
+      time: row.time,
+      min: row.min,
+      max: row.max,
+      avg: row.avg,
+      count: row.count,
+      last: reading_value(row.last_numeric, row.last_text)
+    })
+    .collect();
+
+  Ok((bucket_secs, buckets))
+}
+
+
+#[derive(FromForm)]
+pub struct ExportReadingsQuery {
+  /// `csv` (the default), `ndjson` or `parquet`
+  format: Option<String>,
+  sensor: Option<String>,
+  from: Option<String>,
+  to: Option<String>,
+  /// Download the export as a gzipped file
+  gzip: Option<bool>
+}
+
+/// Whether the client has said it can take gzipped responses.
+pub struct AcceptsGzip(bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptsGzip {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    let accepts_gzip: bool = request
+      .headers()
+      .get("accept-encoding")
+      .flat_map(|value| value.split(','))
+      .any(|encoding| encoding.split(';').next().is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("gzip")));
+
+    Outcome::Success(AcceptsGzip(accepts_gzip))
+  }
+}
+
+/// How an export is compressed, if it is.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ExportCompression {
+  None,
+  /// Sent with `Content-Encoding: gzip`, clients decompress it on the fly
+  ContentEncoding,
+  /// Sent as a `.gz` file
+  File
+}
+
+/// A streamed export, sent as a file download.
+pub struct ReadingsExport<S> {
+  stream: ByteStream<S>,
+  format: ExportFormat,
+  compression: ExportCompression,
+  file_name: String
+}
+
+impl<'r, S: Stream<Item = Vec<u8>> + Send + 'r> Responder<'r, 'r> for ReadingsExport<S> {
+  fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'r> {
+    let mut file_name: String = format!("{}.{}", self.file_name, self.format.extension());
+    let content_type: &str = match self.compression {
+      ExportCompression::File => {
+        file_name.push_str(".gz");
+        "application/gzip"
+      },
+      _ => self.format.content_type()
+    };
+
+    let mut response = self.stream.respond_to(request)?;
+    response.set_raw_header("Content-Type", content_type);
+    response.set_raw_header("Content-Disposition", format!("attachment; filename=\"{}\"", file_name));
+    if self.compression == ExportCompression::ContentEncoding {
+      response.set_raw_header("Content-Encoding", "gzip");
+      response.set_raw_header("Vary", "Accept-Encoding");
+    }
+
+    Ok(response)
+  }
+}
+
+/// The export settings shared by both export routes.
+struct ExportSettings {
+  format: ExportFormat,
+  compression: ExportCompression,
+  sensor: Option<String>,
+  from: Option<PrimitiveDateTime>,
+  to: Option<PrimitiveDateTime>
+}
+
+impl ExportSettings {
+  fn from_query(query: &ExportReadingsQuery, accepts_gzip: AcceptsGzip) -> Result<Self, Status> {
+    let format: ExportFormat = ExportFormat::parse(query.format.as_deref().unwrap_or("csv")).ok_or(Status::BadRequest)?;
+
+    let compression: ExportCompression = match query.gzip {
+      Some(true) => ExportCompression::File,
+      Some(false) => ExportCompression::None,
+      None if accepts_gzip.0 => ExportCompression::ContentEncoding,
+      None => ExportCompression::None
+    };
+
+    let from: Option<PrimitiveDateTime> = match &query.from {
+      Some(from) => Some(parse_query_time(from).ok_or(Status::BadRequest)?),
+      None => None
+    };
+
+    let to: Option<PrimitiveDateTime> = match &query.to {
+      Some(to) => Some(parse_query_time(to).ok_or(Status::BadRequest)?),
+      None => None
+    };
+
+    if let (Some(from), Some(to)) = (from, to) && from >= to {
+      return Err(Status::BadRequest);
+    }
+
+    let sensor: Option<String> = query.sensor.as_deref().map(|sensor| sensor.trim().to_string()).filter(|sensor| !sensor.is_empty());
+
+    Ok(Self { format, compression, sensor, from, to })
+  }
+
+  fn encoder(&self) -> Result<ExportEncoder, Status> {
+    match ExportEncoder::new(self.format, self.compression != ExportCompression::None) {
+      Ok(encoder) => Ok(encoder),
+      Err(err) => {
+        log::error!("There's an error when trying to start a readings export. Error: {}", err);
+        Err(Status::InternalServerError)
+      }
+    }
+  }
+}
+
+/// Encode the next row, giving back whatever output is ready to be sent, or none once there are no rows
+/// left. The response has already started by the time an error could happen, so an export that runs
+/// into one is cut off there without being finished, e.g. without its gzip trailer or Parquet footer.
+async fn encode_rows(rows: &mut (impl Stream<Item = Result<ExportRow, sqlx::Error>> + Unpin), encoder: &mut ExportEncoder) -> Result<Option<Vec<u8>>, String> {
+  match rows.next().await {
+    Some(Ok(row)) => Ok(Some(encoder.push(row)?.unwrap_or_default())),
+    Some(Err(err)) => Err(format!("There's an error when trying to get the readings to export. Error: {}", err)),
+    None => Ok(None)
+  }
+}
+
+
+/// Export the readings of a device, oldest first, as CSV, NDJSON or Parquet. Everything the
+/// device has sent is exported unless it's narrowed down to a `sensor`, `from` or `to`. Answers
+/// 503 when too many exports are already running.
+#[get("/device/<device_id>/readings/export?<query..>")]
+pub async fn export(device_id: &str, query: ExportReadingsQuery, accepts_gzip: AcceptsGzip, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>, export_slots: &State<ExportSlots>) -> Result<ReadingsExport<impl Stream<Item = Vec<u8>>>, Status> {
+  let settings: ExportSettings = ExportSettings::from_query(&query, accepts_gzip)?;
+
+  require_device_role(db.inner(), &authenticated_user.user.id, device_id, DeviceRole::Viewer).await?;
+
+  let export_slot = export_slots.try_take().ok_or(Status::ServiceUnavailable)?;
+  let mut encoder: ExportEncoder = settings.encoder()?;
+  let pool: Pool<Postgres> = db.inner().clone();
+  let file_name: String = format!("{}-readings", device_id);
+  let device_id: String = device_id.to_string();
+  let (sensor, from, to) = (settings.sensor.clone(), settings.from, settings.to);
+
+  let stream = ByteStream! {
+    // The slot is given back once the export is over, or the client has gone
+    let _export_slot = export_slot;
+    let mut rows = sqlx::query_as!(
+      ExportRow,
+      "SELECT device_id, sensor, numeric_value, text_value, device_time, received_at FROM device_readings
+      WHERE device_id = $1 AND ($2::TEXT IS NULL OR sensor = $2) AND ($3::TIMESTAMP IS NULL OR received_at >= $3) AND ($4::TIMESTAMP IS NULL OR received_at < $4)
+      ORDER BY received_at, id",
+      device_id,
+      sensor,
+      from,
+      to
+    )
+    .fetch(&pool);
+
+    loop {
+      match encode_rows(&mut rows, &mut encoder).await {
+        Ok(Some(chunk)) if !chunk.is_empty() => yield chunk,
+        Ok(Some(_)) => (),
+        Ok(None) => break,
+        Err(err) => {
+          // Leave the export without its end, so it can't pass for a complete one
+          log::error!("{}", err);
+          return;
+        }
+      }
+    }
+
+    match encoder.finish() {
+      Ok(chunk) => yield chunk,
+      Err(err) => log::error!("{}", err)
+    }
+  };
+
+  Ok(ReadingsExport { stream, format: settings.format, compression: settings.compression, file_name })
+}
+
+
+/// Export the readings of every device the user is connected to, in the same way as [`export`].
+#[get("/device/readings/export?<query..>")]
+pub async fn export_all(query: ExportReadingsQuery, accepts_gzip: AcceptsGzip, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>, export_slots: &State<ExportSlots>) -> Result<ReadingsExport<impl Stream<Item = Vec<u8>>>, Status> {
+  let settings: ExportSettings = ExportSettings::from_query(&query, accepts_gzip)?;
+
+  let export_slot = export_slots.try_take().ok_or(Status::ServiceUnavailable)?;
+  let mut encoder: ExportEncoder = settings.encoder()?;
+  let pool: Pool<Postgres> = db.inner().clone();
+  let user_id: String = authenticated_user.user.id;
+  let (sensor, from, to) = (settings.sensor.clone(), settings.from, settings.to);
+
+  let stream = ByteStream! {
+    // The slot is given back once the export is over, or the client has gone
+    let _export_slot = export_slot;
+    let mut rows = sqlx::query_as!(
+      ExportRow,
+      "SELECT device_readings.device_id, device_readings.sensor, device_readings.numeric_value, device_readings.text_value, device_readings.device_time, device_readings.received_at
+      FROM device_readings JOIN connections ON connections.device_id = device_readings.device_id
+      WHERE connections.user_id = $1 AND ($2::TEXT IS NULL OR device_readings.sensor = $2)
+      AND ($3::TIMESTAMP IS NULL OR device_readings.received_at >= $3) AND ($4::TIMESTAMP IS NULL OR device_readings.received_at < $4)
+      ORDER BY device_readings.received_at, device_readings.id",
+      user_id,
+      sensor,
+      from,
+      to
+    )
+    .fetch(&pool);
+
+    loop {
+      match encode_rows(&mut rows, &mut encoder).await {
+        Ok(Some(chunk)) if !chunk.is_empty() => yield chunk,
+        Ok(Some(_)) => (),
+        Ok(None) => break,
+        Err(err) => {
+          // Leave the export without its end, so it can't pass for a complete one
+          log::error!("{}", err);
+          return;
+        }
+      }
+    }
+
+    match encoder.finish() {
+      Ok(chunk) => yield chunk,
+      Err(err) => log::error!("{}", err)
+    }
+  };
+
+  Ok(ReadingsExport { stream, format: settings.format, compression: settings.compression, file_name: String::from("readings") })
+}
@@ -1,109 +1,442 @@
-use rocket::{get, http::{CookieJar, Status}, serde::json::Json, State};
+use rocket::{delete, get, FromForm, http::Status, patch, post, serde::json::Json, State, time::{OffsetDateTime, PrimitiveDateTime}};
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 
-use crate::model::{Device, User, Connection};
+use crate::{auth::{device_access::{require_device_role, DeviceRole}, guards::AuthenticatedUser}, model::{custom_serde, Device}, routes::devices::create_device::MAX_DEVICE_NAME_LENGTH, telemetry::retention::is_valid_retention_days, types::WebSocketManager, util::generate_token};
+
+/// How many tags a device can have.
+const MAX_DEVICE_TAGS: usize = 20;
+/// The longest tag a device can be given.
+const MAX_DEVICE_TAG_LENGTH: usize = 32;
+
+/// A device without its access token, which is only shown once when the device is created.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExposedDevice {
+  pub id: String,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub created_at: PrimitiveDateTime,
+  pub device_name: String,
+  pub description: Option<String>,
+  pub status: bool,
+  pub tags: Vec<String>,
+  #[serde(with = "custom_serde::optional_primitive_datetime")]
+  pub last_seen_at: Option<PrimitiveDateTime>,
+  /// How many days the device's raw readings are kept, none for its owners' setting
+  pub readings_retention_days: Option<i32>
+}
+
+impl From<Device> for ExposedDevice {
+  fn from(device: Device) -> Self {
+    Self {
+      id: device.id,
+      created_at: device.created_at,
+      device_name: device.device_name,
+      description: device.description,
+      status: device.status,
+      tags: device.tags,
+      last_seen_at: device.last_seen_at,
+      readings_retention_days: device.readings_retention_days
+    }
+  }
+}
+
+/// A device in the user's list, along with what they're allowed to do with it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListedDevice {
+  pub id: String,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub created_at: PrimitiveDateTime,
+  pub device_name: String,
+  pub description: Option<String>,
+  pub status: bool,
+  pub tags: Vec<String>,
+  #[serde(with = "custom_serde::optional_primitive_datetime")]
+  pub last_seen_at: Option<PrimitiveDateTime>,
+  pub role: String
+}
+
+#[derive(FromForm)]
+pub struct GetDevicesQuery {
+  online: Option<bool>,
+  search: Option<String>,
+  tag: Option<String>,
+  sort: Option<String>,
+  order: Option<String>,
+  limit: Option<i64>,
+  cursor: Option<String>
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct GetReturnType {
-  devices: Vec<Device>
+  devices: Vec<ListedDevice>,
+  /// Pass this as `cursor` to get the next page, none when this is the last one
+  next_cursor: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetOneReturnType {
+  pub device_data: ExposedDevice
 }
 
-#[get("/device")]
-pub async fn get(cookies: &CookieJar<'_>, db: &State<Pool<Postgres>>) -> Result<Json<GetReturnType>, Status> {
-  // Get the access token
-  let access_token = cookies.get("access_token");
+#[derive(Serialize, Deserialize)]
+pub struct UpdateDeviceRequestType {
+  device_name: Option<String>,
+  /// An empty string removes the description
+  description: Option<String>,
+  /// Replaces all of the device's tags
+  tags: Option<Vec<String>>,
+  /// How many days the device's raw readings are kept, 0 goes back to its owners' setting
+  readings_retention_days: Option<i32>
+}
 
-  let access_token = match access_token {
-    Some(token) => token.value(),
-    None => {
-      return Err(Status::Unauthorized);
+#[derive(Serialize, Deserialize)]
+pub struct RotateTokenReturnType {
+  /// The new token the device authenticates with. It's only shown here, like when the device was created.
+  pub access_token: String
+}
+
+
+/// Trim, lowercase and deduplicate tags, or none if there are too many or one of them is too long.
+fn normalize_tags(tags: &[String]) -> Option<Vec<String>> {
+  let mut normalized: Vec<String> = Vec::new();
+  for tag in tags {
+    let tag: String = tag.trim().to_lowercase();
+    if tag.chars().count() > MAX_DEVICE_TAG_LENGTH {
+      return None;
     }
-  };
 
+    if !tag.is_empty() && !normalized.contains(&tag) {
+      normalized.push(tag);
+    }
+  }
 
-  // Verify access token
-  let user_data: User;
-  {
-    let raw_user_data: Result<Option<User>, sqlx::Error> = sqlx::query_as!(
-      User,
-      "SELECT * FROM users WHERE access_token = $1",
-      access_token
-    )
-    .fetch_optional(db.inner())
-    .await;
+  if normalized.len() > MAX_DEVICE_TAGS {
+    return None;
+  }
 
-    let optional_user_data: Option<User> = match raw_user_data {
-      Ok(res) => res,
-      Err(err) => {
-        log::error!("There's an error when trying to get user data for access verification. Error: {}", err.to_string());
-        return Err(Status::InternalServerError);
-      }
+  Some(normalized)
+}
+
+/// The position of the last device of a page in the order it's sorted by.
+enum DeviceCursor {
+  Name(String, String),
+  CreatedAt(PrimitiveDateTime, String)
+}
+
+impl DeviceCursor {
+  fn of(device: &ListedDevice, sort: &str) -> Self {
+    match sort {
+      "name" => Self::Name(device.device_name.clone(), device.id.clone()),
+      _ => Self::CreatedAt(device.created_at, device.id.clone())
+    }
+  }
+
+  /// Cursors are opaque to clients, they're the sort key and the device ID hex encoded.
+  fn encode(&self) -> String {
+    let raw: String = match self {
+      Self::Name(device_name, id) => format!("name\n{}\n{}", id, device_name),
+      Self::CreatedAt(created_at, id) => format!("created_at\n{}\n{}", id, created_at.assume_utc().unix_timestamp_nanos())
     };
 
-    user_data = match optional_user_data {
-      Some(user) => user,
+    hex::encode(raw)
+  }
+
+  /// Read a cursor made by [`DeviceCursor::encode`], none if it's invalid or was made for another sort.
+  fn decode(cursor: &str, sort: &str) -> Option<Self> {
+    let raw: String = String::from_utf8(hex::decode(cursor).ok()?).ok()?;
+    let mut parts = raw.splitn(3, '\n');
+    let (cursor_sort, id, value) = (parts.next()?, parts.next()?.to_string(), parts.next()?);
+
+    if cursor_sort != sort {
+      return None;
+    }
+
+    match cursor_sort {
+      "name" => Some(Self::Name(value.to_string(), id)),
+      "created_at" => {
+        let created_at: OffsetDateTime = OffsetDateTime::from_unix_timestamp_nanos(value.parse::<i128>().ok()?).ok()?;
+        Some(Self::CreatedAt(PrimitiveDateTime::new(created_at.date(), created_at.time()), id))
+      },
+      _ => None
+    }
+  }
+}
+
+
+/// List the devices the user is connected to, a page at a time. They can be filtered by whether
+/// they're `online`, a `search` in their name and a `tag`, and sorted by `name` or `created_at`
+/// (the default) in either `order`, ascending by default.
+#[get("/device?<query..>")]
+pub async fn get(query: GetDevicesQuery, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<Json<GetReturnType>, Status> {
+  let sort: &str = query.sort.as_deref().unwrap_or("created_at");
+  if !["name", "created_at"].contains(&sort) {
+    return Err(Status::BadRequest);
+  }
+
+  let ascending: bool = match query.order.as_deref().unwrap_or("asc") {
+    "asc" => true,
+    "desc" => false,
+    _ => {
+      return Err(Status::BadRequest);
+    }
+  };
+
+  let cursor: Option<DeviceCursor> = match &query.cursor {
+    Some(cursor) => match DeviceCursor::decode(cursor, sort) {
+      Some(cursor) => Some(cursor),
       None => {
-        return Err(Status::Unauthorized);
+        return Err(Status::BadRequest);
       }
+    },
+    None => None
+  };
+
+  let (cursor_id, cursor_name, cursor_created_at) = match cursor {
+    Some(DeviceCursor::Name(device_name, id)) => (Some(id), Some(device_name), None),
+    Some(DeviceCursor::CreatedAt(created_at, id)) => (Some(id), None, Some(created_at)),
+    None => (None, None, None)
+  };
+
+  // Match the search literally
+  let search: Option<String> = query.search
+    .as_deref()
+    .map(|search| search.trim())
+    .filter(|search| !search.is_empty())
+    .map(|search| search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+  let tag: Option<String> = query.tag.as_deref().map(|tag| tag.trim().to_lowercase());
+
+  // Get one more than asked for, to know if there's another page
+  let limit: i64 = query.limit.unwrap_or(50).clamp(1, 200);
+
+  let raw_devices_data: Result<Vec<ListedDevice>, sqlx::Error> = sqlx::query_as!(
+    ListedDevice,
+    "SELECT devices.id, devices.created_at, devices.device_name, devices.description, devices.status, devices.tags, devices.last_seen_at, connections.role
+    FROM connections JOIN devices ON devices.id = connections.device_id
+    WHERE connections.user_id = $1
+    AND ($2::BOOL IS NULL OR devices.status = $2)
+    AND ($3::TEXT IS NULL OR devices.device_name ILIKE '%' || $3 || '%')
+    AND ($4::TEXT IS NULL OR $4 = ANY(devices.tags))
+    AND ($5::TEXT IS NULL OR CASE
+      WHEN $6 = 'name' AND $7 THEN (devices.device_name, devices.id) > ($8::TEXT, $5)
+      WHEN $6 = 'name' THEN (devices.device_name, devices.id) < ($8::TEXT, $5)
+      WHEN $7 THEN (devices.created_at, devices.id) > ($9::TIMESTAMP, $5)
+      ELSE (devices.created_at, devices.id) < ($9::TIMESTAMP, $5)
+    END)
+    ORDER BY
+      CASE WHEN $6 = 'name' AND $7 THEN devices.device_name END ASC,
+      CASE WHEN $6 = 'name' AND NOT $7 THEN devices.device_name END DESC,
+      CASE WHEN $6 = 'created_at' AND $7 THEN devices.created_at END ASC,
+      CASE WHEN $6 = 'created_at' AND NOT $7 THEN devices.created_at END DESC,
+      CASE WHEN $7 THEN devices.id END ASC,
+      devices.id DESC
+    LIMIT $10",
+    authenticated_user.user.id,
+    query.online,
+    search,
+    tag,
+    cursor_id,
+    sort,
+    ascending,
+    cursor_name,
+    cursor_created_at,
+    limit + 1
+  )
+  .fetch_all(db.inner())
+  .await;
+
+  let mut devices_data: Vec<ListedDevice> = match raw_devices_data {
+    Ok(data) => data,
+    Err(err) => {
+      log::error!("There's an error when trying to get the user's devices. Error: {}", err);
+      return Err(Status::InternalServerError);
     }
+  };
+
+  let next_cursor: Option<String> = if devices_data.len() as i64 > limit {
+    devices_data.truncate(limit as usize);
+    devices_data.last().map(|device| DeviceCursor::of(device, sort).encode())
+  } else {
+    None
+  };
+
+
+  // Return the device data
+  Ok(Json(GetReturnType {
+    devices: devices_data,
+    next_cursor
+  }))
+}
+
+/// Get a single device the user is connected to.
+#[get("/device/<device_id>")]
+pub async fn get_one(device_id: &str, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<Json<GetOneReturnType>, Status> {
+  require_device_role(db.inner(), &authenticated_user.user.id, device_id, DeviceRole::Viewer).await?;
+
+  let raw_device_data: Result<Option<Device>, sqlx::Error> = sqlx::query_as!(
+    Device,
+    "SELECT * FROM devices WHERE id = $1",
+    device_id
+  )
+  .fetch_optional(db.inner())
+  .await;
+
+  match raw_device_data {
+    Ok(Some(device)) => Ok(Json(GetOneReturnType { device_data: device.into() })),
+    Ok(None) => Err(Status::NotFound),
+    Err(err) => {
+      log::error!("There's an error when trying to get device data. Error: {}", err);
+      Err(Status::InternalServerError)
+    }
+  }
+}
+
+
+/// Change the name, description, tags or readings retention of a device. Only its owners can do this.
+#[patch("/device/<device_id>", data = "<update_device_data>")]
+pub async fn patch(device_id: &str, update_device_data: Json<UpdateDeviceRequestType>, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<Json<GetOneReturnType>, Status> {
+  if update_device_data.device_name.is_none() && update_device_data.description.is_none() && update_device_data.tags.is_none() && update_device_data.readings_retention_days.is_none() {
+    return Err(Status::BadRequest);
   }
-  
 
+  let device_name: Option<&str> = update_device_data.device_name.as_deref().map(|device_name| device_name.trim());
+  if device_name.is_some_and(|device_name| device_name.is_empty() || device_name.chars().count() > MAX_DEVICE_NAME_LENGTH) {
+    return Err(Status::BadRequest);
+  }
+
+  let description: Option<&str> = update_device_data.description.as_deref().map(|description| description.trim());
+
+  let tags: Option<Vec<String>> = match &update_device_data.tags {
+    Some(tags) => match normalize_tags(tags) {
+      Some(tags) => Some(tags),
+      None => {
+        return Err(Status::BadRequest);
+      }
+    },
+    None => None
+  };
+
+  if update_device_data.readings_retention_days.is_some_and(|days| !is_valid_retention_days(days)) {
+    return Err(Status::BadRequest);
+  }
+
+  require_device_role(db.inner(), &authenticated_user.user.id, device_id, DeviceRole::Owner).await?;
+
+  // Update the fields that have been given
+  let raw_device_data: Result<Option<Device>, sqlx::Error> = sqlx::query_as!(
+    Device,
+    "UPDATE devices SET device_name = COALESCE($1, device_name), description = CASE WHEN $2::TEXT IS NULL THEN description ELSE NULLIF($2, '') END, tags = COALESCE($3, tags),
+    readings_retention_days = CASE WHEN $4::INTEGER IS NULL THEN readings_retention_days ELSE NULLIF($4, 0) END WHERE id = $5 RETURNING *",
+    device_name,
+    description,
+    tags.as_deref(),
+    update_device_data.readings_retention_days,
+    device_id
+  )
+  .fetch_optional(db.inner())
+  .await;
+
+  match raw_device_data {
+    Ok(Some(device)) => Ok(Json(GetOneReturnType { device_data: device.into() })),
+    Ok(None) => Err(Status::NotFound),
+    Err(err) => {
+      log::error!("There's an error when trying to update the device. Error: {}", err);
+      Err(Status::InternalServerError)
+    }
+  }
+}
+
+
+/// Delete a device along with everything connected to it, stop users from listening to it and close
+/// its live connection. Only its owners can do this.
+#[delete("/device/<device_id>")]
+pub async fn delete(device_id: &str, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>, ws_manager: &State<WebSocketManager>) -> Result<(), Status> {
+  require_device_role(db.inner(), &authenticated_user.user.id, device_id, DeviceRole::Owner).await?;
 
-  // Get the device data
-  let mut devices_data: Vec<Device> = Vec::new();
   {
-    // Get all connections that user connected
-    let raw_connections_data: Result<Vec<Connection>, sqlx::Error> = sqlx::query_as!(
-      Connection,
-      "SELECT * FROM connections WHERE user_id = $1",
-      user_data.id
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+      Ok(res) => res,
+      Err(err) => {
+        log::error!("There's an error when trying to start a transaction in device deletion. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    // Connections don't cascade, claims and invitations do
+    let connections_result = sqlx::query!(
+      "DELETE FROM connections WHERE device_id = $1",
+      device_id
     )
-    .fetch_all(db.inner())
+    .execute(&mut *transaction)
     .await;
 
-    let connections_data = match raw_connections_data {
-      Ok(data) => data,
+    if let Err(err) = connections_result {
+      log::error!("There's an error when trying to delete the device connections. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+
+    let device_result = sqlx::query!(
+      "DELETE FROM devices WHERE id = $1",
+      device_id
+    )
+    .execute(&mut *transaction)
+    .await;
+
+    match device_result {
+      Ok(res) if res.rows_affected() > 0 => (),
+      Ok(_) => {
+        return Err(Status::NotFound);
+      },
       Err(err) => {
-        log::error!("There's an error when trying to get connections data. Error: {}", err.to_string());
+        log::error!("There's an error when trying to delete the device. Error: {}", err);
         return Err(Status::InternalServerError);
       }
-    };
+    }
+
+    match transaction.commit().await {
+      Ok(_) => (),
+      Err(err) => {
+        log::error!("There's an error when trying to commit the device deletion. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+  }
+
+  // Users can't keep sending commands to it, nor the device keep sending readings
+  ws_manager.remove_device(device_id).await;
+
+  Ok(())
+}
+
 
-    // Get all the devices from each connection
-    for connection in connections_data {
-      let raw_device_data: Result<Option<Device>, sqlx::Error> = sqlx::query_as!(
-        Device,
-        "SELECT * FROM devices WHERE id = $1",
-        connection.device_id
-      )
-      .fetch_optional(db.inner())
-      .await;
-
-      let optional_device_data: Option<Device> = match raw_device_data {
-        Ok(device) => device,
-        Err(err) => {
-          log::error!("There's an error when trying to get device data for each connection. Error: {}", err.to_string());
-          return Err(Status::InternalServerError);
-        }
-      };
-
-      let device_data: Device = match optional_device_data {
-        Some(device) => device,
-        None => {
-          log::error!("LOGIC ERROR: There's changes in database in the middle of program. When trying to get all connections. there's device that has their ID change or deleted.");
-          return Err(Status::ServiceUnavailable);
-        }
-      };
-
-      devices_data.push(device_data);
+/// Give the device a new access token and close its live connection, so the old token stops working
+/// right away. Only its owners can do this.
+#[post("/device/<device_id>/rotate-token")]
+pub async fn rotate_token(device_id: &str, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>, ws_manager: &State<WebSocketManager>) -> Result<Json<RotateTokenReturnType>, Status> {
+  require_device_role(db.inner(), &authenticated_user.user.id, device_id, DeviceRole::Owner).await?;
+
+  let access_token: String = generate_token(48);
+
+  let raw_update_result = sqlx::query!(
+    "UPDATE devices SET access_token = $1 WHERE id = $2",
+    access_token,
+    device_id
+  )
+  .execute(db.inner())
+  .await;
+
+  match raw_update_result {
+    Ok(res) if res.rows_affected() > 0 => (),
+    Ok(_) => {
+      return Err(Status::NotFound);
+    },
+    Err(err) => {
+      log::error!("There's an error when trying to rotate the device access token. Error: {}", err);
+      return Err(Status::InternalServerError);
     }
   }
 
+  ws_manager.close_device_connection(device_id).await;
 
-  // Return the device data
-  Ok(Json(GetReturnType {
-    devices: devices_data
-  }))
-}
\ No newline at end of file
+  Ok(Json(RotateTokenReturnType { access_token }))
+}
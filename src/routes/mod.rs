@@ -1,3 +1,4 @@
 pub mod catchers;
 pub mod user;
-pub mod devices;
\ No newline at end of file
+pub mod devices;
+pub mod admin;
\ No newline at end of file
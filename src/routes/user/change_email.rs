@@ -0,0 +1,141 @@
+use crate::{auth::{email_verification::{email_verification_config, issue_email_verification, IssueError, IssueResult}, guards::AuthenticatedUser}, mail::{outbox::enqueue_email, templates::RenderedEmail, MailService}, model::User, util::locale::AcceptLanguage};
+use lettre::message::Mailbox;
+use rocket::{State, http::Status, post, serde::json::Json};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangeEmailRequestType {
+    email: String,
+}
+
+/// Send a verification code to the new address. The email is only swapped once
+/// the code has been confirmed through `/user/email/verify`.
+#[post("/user/email", data = "<change_email_data>")]
+pub async fn post(
+    change_email_data: Json<ChangeEmailRequestType>,
+    authenticated_user: AuthenticatedUser,
+    db: &State<sqlx::postgres::PgPool>,
+    mail: &State<MailService>,
+    accept_language: AcceptLanguage,
+) -> Result<(), IssueError> {
+    // Check if the email is valid
+    let to_address: Mailbox = match change_email_data.email.parse() {
+        Ok(address) => address,
+        Err(_) => {
+            return Err(Status::BadRequest.into());
+        }
+    };
+
+    if change_email_data.email == authenticated_user.user.email {
+        return Err(Status::BadRequest.into());
+    }
+
+
+    // Check if the email is already used by another user
+    {
+        let raw_user_data = sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE email = $1",
+            change_email_data.email
+        )
+        .fetch_optional(db.inner())
+        .await;
+
+        match raw_user_data {
+            Ok(Some(_)) => {
+                return Err(Status::Conflict.into());
+            },
+            Ok(None) => (),
+            Err(err) => {
+                log::error!(
+                    "There's an error when trying to get user data. Error: {}",
+                    err
+                );
+                return Err(Status::InternalServerError.into());
+            }
+        }
+    }
+
+
+    // Start a transaction, so the code is only kept if its email has been queued
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+        Ok(res) => res,
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to start a transaction in email change. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+
+    // Generate verification token bound to the new email and this user
+    let generated_verification_token: String = match issue_email_verification(&mut transaction, &change_email_data.email, Some(&authenticated_user.user.id)).await {
+        Ok(IssueResult::Issued(token)) => token,
+        Ok(IssueResult::Cooldown(remaining)) => {
+            log::warn!("Email change verification has been requested again too soon. Try again in {} seconds", remaining.whole_seconds());
+            return Err(IssueError::Cooldown(remaining));
+        },
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to create verification token. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+
+    // Prefer the language the user has chosen over the one of their browser
+    let locales: Vec<String> = authenticated_user.user.locale.iter().cloned().chain(accept_language.0).collect();
+
+    // Render the email with the verification token
+    let expire_minutes: String = email_verification_config().lifetime.whole_minutes().to_string();
+    let email: RenderedEmail = match mail.render("email_change", &locales, &[("code", &generated_verification_token), ("expire_minutes", &expire_minutes)]) {
+        Ok(email) => email,
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to render the email change verification email. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+
+    // Queue the email with the verification token
+    let result = enqueue_email(
+        &mut *transaction,
+        &to_address,
+        &email,
+    ).await;
+
+    match result {
+        Ok(_) => (),
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to queue the verification email. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    match transaction.commit().await {
+        Ok(_) => (),
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to commit the verification token. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    mail.wake_outbox();
+
+    // Send the OK result
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use rocket::{http::Status, post, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::{auth::guards::AuthenticatedUser, mail::MailService, model::User, routes::user::this::GetUserRequestBody, types::WebSocketManager, util::password::{hash_password, verify_password, PasswordVerification}};
+
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangePasswordRequestType {
+  current_password: String,
+  new_password: String
+}
+
+
+#[post("/user/password", data = "<change_password_data>")]
+pub async fn post(change_password_data: Json<ChangePasswordRequestType>, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>, ws_manager: &State<WebSocketManager>, mail: &State<MailService>) -> Result<Json<GetUserRequestBody>, Status> {
+  if change_password_data.new_password.is_empty() {
+    return Err(Status::BadRequest);
+  }
+
+  let stored_password: &str = match &authenticated_user.user.password {
+    Some(password) => password,
+    None => {
+      // Users who haven't finished registering have to go through /user/register/3
+      return Err(Status::Forbidden);
+    }
+  };
+
+
+  // Verify the current password
+  let verification: PasswordVerification = match verify_password(&change_password_data.current_password, stored_password).await {
+    Ok(result) => result,
+    Err(err) => {
+      log::error!("There's an error when trying to verify password while changing password. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+
+  if !verification.is_valid() {
+    log::warn!("There's a failed attempt to change password for user: {}", authenticated_user.user.id);
+    return Err(Status::Unauthorized);
+  }
+
+
+  // Hash the new password
+  let hashed_password: String = match hash_password(&change_password_data.new_password).await {
+    Ok(hash) => hash,
+    Err(err) => {
+      log::error!("There's an error when trying to hash password while changing password. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+
+
+  // Update the password, log out every other session and let the user know
+  let user_data: User;
+  {
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+      Ok(res) => res,
+      Err(err) => {
+        log::error!("There's an error when trying to start a transaction in password change. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    let raw_user_data = sqlx::query_as!(
+      User,
+      "UPDATE users SET password = $1 WHERE id = $2 RETURNING *",
+      hashed_password,
+      authenticated_user.user.id
+    )
+    .fetch_one(&mut *transaction)
+    .await;
+
+    user_data = match raw_user_data {
+      Ok(data) => data,
+      Err(err) => {
+        log::error!("There's an error when trying to update the password. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    let revoke_result = sqlx::query!(
+      "DELETE FROM sessions WHERE user_id = $1 AND id != $2",
+      authenticated_user.user.id,
+      authenticated_user.session.id
+    )
+    .execute(&mut *transaction)
+    .await;
+
+    if let Err(err) = revoke_result {
+      log::error!("There's an error when trying to revoke other sessions after password change. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+
+    if let Err(err) = mail.enqueue_security_alert(&mut *transaction, &user_data, &user_data.email, "password_changed_alert", &[]).await {
+      log::error!("There's an error when trying to alert the user of the password change. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+
+    match transaction.commit().await {
+      Ok(_) => (),
+      Err(err) => {
+        log::error!("There's an error when trying to commit the password change. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+  }
+
+
+  mail.wake_outbox();
+
+  // Kick out the live connections of the other sessions
+  ws_manager.close_other_session_connections(&user_data.id, &authenticated_user.session.id).await;
+
+
+  // Return the user data
+  Ok(Json(GetUserRequestBody { user_data: user_data.into() }))
+}
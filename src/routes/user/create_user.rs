@@ -1,8 +1,7 @@
-use rocket::{http::{CookieJar, Status}, post, serde::json::Json, State};
+use rocket::{http::Status, post, serde::json::Json, State};
 use serde::{Deserialize, Serialize};
-use sha3::{Sha3_256, Digest};
 use sqlx::{postgres::PgQueryResult, Pool, Postgres};
-use crate::model::User;
+use crate::{auth::guards::AuthenticatedUser, model::User, util::password::hash_password};
 
 
 #[derive(Serialize, Deserialize)]
@@ -13,71 +12,32 @@ pub struct CreateUserRequestType {
 
 
 #[post("/user/register/3", data = "<create_user_data>")]
-pub async fn post(create_user_data: Json<CreateUserRequestType>, db: &State<Pool<Postgres>>, cookies: &CookieJar<'_>) -> Result<(), Status> {
-  // Check if access token exists
-  let access_token = cookies.get("access_token");
-  let access_token = match access_token {
-    Some(cookie) => cookie.value(),
-    None => {
-      log::warn!("Unauthorized out of no access token in their cookie!");
-      return Err(Status::Unauthorized);
-    }
-  };
-
+pub async fn post(create_user_data: Json<CreateUserRequestType>, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<(), Status> {
+  let user_data: User = authenticated_user.user;
 
-  // Verify access token
-  {
-    // Verify if the access token is inside the database
-    let raw_user_data: Result<Option<User>, sqlx::Error> = sqlx::query_as!(
-      User,
-      "SELECT * FROM users WHERE access_token = $1",
-      access_token
-    )
-    .fetch_optional(db.inner())
-    .await;
-
-    let user_data: Option<User> = match raw_user_data {
-      Ok(data) => data,
-      Err(err) => {
-        log::error!("There's an error when trying to verify access token while creating user process. Error: {}", err.to_string());
-        return Err(Status::InternalServerError);
-      } 
-    };
-
-    let user_data = match user_data {
-      Some(data) => data,
-      None => {
-        log::warn!("Unauthorized out of not valid access token! Access Token: {}", access_token);
-        return Err(Status::Unauthorized);
-      }
-    };
-
-    // Verify if the user is not already registered before
-    if user_data.username.is_some() && user_data.password.is_some() {
-      log::warn!("Unauthorized out of duplicated registering!");
-      return Err(Status::Unauthorized);
-    }
+  // Verify if the user is not already registered before
+  if user_data.username.is_some() && user_data.password.is_some() {
+    log::warn!("Unauthorized out of duplicated registering!");
+    return Err(Status::Unauthorized);
   }
 
   
   // Hash the given password
-  let hashed_password: String;
-  {
-    let mut hasher = Sha3_256::new();
-    hasher.update(create_user_data.password.as_bytes());
-    
-    let hash_raw_result = hasher.finalize();
-    
-    hashed_password = hex::encode(hash_raw_result);
-  }
+  let hashed_password: String = match hash_password(&create_user_data.password).await {
+    Ok(hash) => hash,
+    Err(err) => {
+      log::error!("There's an error when trying to hash password while creating user process. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
 
   // Update the username and password
   {
     let raw_user_data: Result<PgQueryResult, sqlx::Error> = sqlx::query!(
-      "UPDATE users SET username = $1, password = $2 WHERE access_token = $3",
+      "UPDATE users SET username = $1, password = $2 WHERE id = $3",
       create_user_data.username,
       hashed_password,
-      access_token
+      user_data.id
     )
     .execute(db.inner())
     .await;
@@ -85,7 +45,7 @@ pub async fn post(create_user_data: Json<CreateUserRequestType>, db: &State<Pool
     match raw_user_data {
       Ok(_) => (),
       Err(err) => {
-        log::error!("There's an error when trying to update user while creating user process. Error: {}", err.to_string());
+        log::error!("There's an error when trying to update user while creating user process. Error: {}", err);
         return Err(Status::InternalServerError);
       }
     }
@@ -0,0 +1,136 @@
+use std::env;
+
+use crate::{auth::password_reset::{issue_password_reset, password_reset_lifetime}, mail::{outbox::enqueue_email, templates::RenderedEmail, MailService}, model::User, util::locale::AcceptLanguage};
+use lettre::message::Mailbox;
+use rocket::{State, http::Status, post, serde::json::Json};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct ForgotPasswordRequestType {
+    email: String,
+}
+
+/// Always answers with OK for a well formed email, so it can't be used to find out who has an account.
+#[post("/user/password/forgot", data = "<forgot_password_data>")]
+pub async fn post(
+    forgot_password_data: Json<ForgotPasswordRequestType>,
+    db: &State<sqlx::postgres::PgPool>,
+    mail: &State<MailService>,
+    accept_language: AcceptLanguage,
+) -> Result<(), Status> {
+    // Check if the email is valid
+    let to_address: Mailbox = match forgot_password_data.email.parse() {
+        Ok(address) => address,
+        Err(_) => {
+            return Err(Status::BadRequest);
+        }
+    };
+
+
+    // Get the registered user with that email
+    let raw_user_data = sqlx::query_as!(
+        User,
+        "SELECT * FROM users WHERE email = $1 AND password IS NOT NULL",
+        forgot_password_data.email
+    )
+    .fetch_optional(db.inner())
+    .await;
+
+    let user_data: User = match raw_user_data {
+        Ok(Some(data)) => data,
+        Ok(None) => {
+            log::warn!("Password reset has been requested for an unknown email");
+            return Ok(());
+        },
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to get user data. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError);
+        }
+    };
+
+
+    // Start a transaction, so the token is only kept if its email has been queued
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+        Ok(res) => res,
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to start a transaction in password reset. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError);
+        }
+    };
+
+
+    // Generate reset token
+    let generated_reset_token: String = match issue_password_reset(&mut *transaction, &user_data.id).await {
+        Ok(token) => token,
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to create password reset token. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError);
+        }
+    };
+
+
+    // Send the token, as a link if the frontend has a reset page
+    let locales: Vec<String> = user_data.locale.into_iter().chain(accept_language.0).collect();
+    let expire_minutes: String = password_reset_lifetime().whole_minutes().to_string();
+
+    let email: Result<RenderedEmail, String> = match env::var("PASSWORD_RESET_URL") {
+        Ok(url) => mail.render(
+            "password_reset_link",
+            &locales,
+            &[("reset_link", &format!("{}?token={}", url, generated_reset_token)), ("expire_minutes", &expire_minutes)]
+        ),
+        Err(_) => mail.render(
+            "password_reset",
+            &locales,
+            &[("token", &generated_reset_token), ("expire_minutes", &expire_minutes)]
+        ),
+    };
+
+    let email: RenderedEmail = match email {
+        Ok(email) => email,
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to render the password reset email. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    match enqueue_email(&mut *transaction, &to_address, &email).await {
+        Ok(_) => (),
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to queue the password reset email. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    match transaction.commit().await {
+        Ok(_) => (),
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to commit the password reset token. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    mail.wake_outbox();
+
+    // Send the OK result
+    Ok(())
+}
@@ -1,11 +1,9 @@
 use rocket::{
-    http::{Cookie, CookieJar, Status}, post, serde::{json::Json, Deserialize}, State
+    http::{CookieJar, Status}, post, serde::{json::Json, Deserialize, Serialize}, time::PrimitiveDateTime, State
 };
 use sqlx::{Pool, Postgres};
 
-use crate::{model::User, util::generate_token};
-use sha3::{Digest, Sha3_256};
-use hex;
+use crate::{auth::{create_session, guards::ClientInfo, set_access_token_cookie}, model::{custom_serde, Session, User}, util::{password::{hash_password, verify_password, PasswordVerification}}};
 use log;
 
 
@@ -13,11 +11,22 @@ use log;
 pub struct LoginRequest {
     username: String,
     password: String,
+    /// Also return the access token in the response body, for clients that can't use cookies
+    #[serde(default)]
+    return_token: bool,
+}
+
+#[derive(Serialize)]
+pub struct LoginReturnType {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", with = "custom_serde::optional_primitive_datetime")]
+    expire_at: Option<PrimitiveDateTime>,
 }
 
 // FUNCTIONS
 #[post("/user/login", data = "<credentials>")]
-pub async fn post(credentials: Json<LoginRequest>, db: &State<Pool<Postgres>>, cookies: &CookieJar<'_>) -> Result<(), Status> {
+pub async fn post(credentials: Json<LoginRequest>, db: &State<Pool<Postgres>>, cookies: &CookieJar<'_>, client_info: ClientInfo) -> Result<Json<LoginReturnType>, Status> {
     // Get the user data from database
     let sqlx_query_result = sqlx::query_as!(
         User,
@@ -30,7 +39,7 @@ pub async fn post(credentials: Json<LoginRequest>, db: &State<Pool<Postgres>>, c
     let user_data = match sqlx_query_result {
         Ok(user_data) => user_data,
         Err(err) => {
-            log::error!("There's an error when trying to get user data, error: {}", err.to_string());
+            log::error!("There's an error when trying to get user data, error: {}", err);
             return Err(Status::InternalServerError);
         }  
     };
@@ -44,62 +53,84 @@ pub async fn post(credentials: Json<LoginRequest>, db: &State<Pool<Postgres>>, c
         }
     };
 
-    if let None = user_data.password {
-        return Err(Status::NotFound);
-    }
+    let stored_password: &str = match &user_data.password {
+        Some(password) => password,
+        None => {
+            return Err(Status::NotFound);
+        }
+    };
 
-    
-    // Get the hash version of the given password
-    let hash_result;
-    {
-        let mut hasher = Sha3_256::new();
-        hasher.update(credentials.password.as_bytes());
-        
-        let hash_raw_result = hasher.finalize();
-        
-        hash_result = hex::encode(hash_raw_result);
-    }
 
-    
     // Verify the password
-    if hash_result != user_data.password.unwrap() {
+    let verification: PasswordVerification = match verify_password(&credentials.password, stored_password).await {
+        Ok(result) => result,
+        Err(err) => {
+            log::error!("There's an error when trying to verify password for user: {}. Error: {}", credentials.username, err);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    if !verification.is_valid() {
         log::warn!("There's a failed attempt to login for user: {}", credentials.username);
         return Err(Status::Unauthorized);
     }
 
 
-    // Generate token
-    let generated_token: String = generate_token(20);
+    // Upgrade the stored hash if it was made with an outdated scheme or cost
+    if verification == PasswordVerification::ValidNeedsRehash {
+        match hash_password(&credentials.password).await {
+            Ok(new_hash) => {
+                // Only replace the hash that was just verified, a reset or change may have committed since
+                let rehash_raw_result = sqlx::query!(
+                    "UPDATE users SET password = $1 WHERE id = $2 AND password = $3",
+                    new_hash,
+                    user_data.id,
+                    stored_password
+                )
+                .execute(db.inner())
+                .await;
+
+                match rehash_raw_result {
+                    Ok(result) if result.rows_affected() == 0 => log::info!("Password has been changed before its hash could be upgraded for user: {}", credentials.username),
+                    Ok(_) => log::info!("Password hash has been upgraded for user: {}", credentials.username),
+                    Err(err) => {
+                        log::error!("There's an error when trying to store the upgraded password hash. Error: {}", err);
+                    }
+                }
+            },
+            Err(err) => {
+                log::error!("There's an error when trying to rehash password for user: {}. Error: {}", credentials.username, err);
+            }
+        }
+    }
 
 
-    // Update access token in database
-    let token_raw_result = sqlx::query!(
-        "UPDATE users SET access_token = $1 WHERE id = $2",
-        generated_token,
-        user_data.id
-    )
-    .execute(db.inner())
-    .await;
+    // Start a new session
+    let token_raw_result = create_session(db.inner(), &user_data.id, &client_info).await;
 
-    match token_raw_result {
-        Ok(_) => (),
+    let session: Session = match token_raw_result {
+        Ok(session) => session,
         Err(err) => {
-            log::error!("There's an error when trying to insert token data. Error: {}", err.to_string());
+            log::error!("There's an error when trying to insert token data. Error: {}", err);
             return Err(Status::InternalServerError);
         }
-    }
+    };
 
 
     // Store access token to the cookie
-    cookies.add(
-        Cookie::build(("access_token", generated_token))
-        .path("/")
-        .secure(true)
-        .http_only(true)
-        .max_age(rocket::time::Duration::days(14))
-    );
+    set_access_token_cookie(cookies, session.access_token.clone(), session.expire_at);
     
 
     // Return the token
-    Ok(())
+    if credentials.return_token {
+        return Ok(Json(LoginReturnType {
+            access_token: Some(session.access_token),
+            expire_at: Some(session.expire_at),
+        }));
+    }
+
+    Ok(Json(LoginReturnType {
+        access_token: None,
+        expire_at: None,
+    }))
 }
\ No newline at end of file
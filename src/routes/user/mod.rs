@@ -2,4 +2,11 @@ pub mod register_email;
 pub mod verify_email;
 pub mod create_user;
 pub mod login;
-pub mod this;
\ No newline at end of file
+pub mod this;
+pub mod sessions;
+pub mod forgot_password;
+pub mod reset_password;
+pub mod update_user;
+pub mod change_password;
+pub mod change_email;
+pub mod verify_email_change;
\ No newline at end of file
@@ -1,16 +1,6 @@
-use std::env;
-
-use crate::{model::User, util::{generate_token, is_duplicated_error}};
-use lettre::{
-    Message, Transport,
-    transport::smtp::{
-        SmtpTransport,
-        authentication::{Credentials, Mechanism},
-    },
-};
+use crate::{auth::email_verification::{email_verification_config, issue_email_verification, IssueError, IssueResult}, mail::{outbox::enqueue_email, templates::RenderedEmail, MailService}, model::User, util::locale::AcceptLanguage};
 use rocket::{State, http::Status, post, serde::json::Json};
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgQueryResult;
 
 #[derive(Serialize, Deserialize)]
 pub struct RegistrationRequestType {
@@ -21,70 +11,10 @@ pub struct RegistrationRequestType {
 pub async fn post(
     registration_data: Json<RegistrationRequestType>,
     db: &State<sqlx::postgres::PgPool>,
-) -> Result<(), Status> {
-    // Generate verification token and user id
-    let generated_verification_token: String = generate_token(5);
-
-
-    // Setting up email message
-    let mail: Message = Message::builder()
-        // From which email account?
-        .from(
-            (env::var("EMAIL_APP_ACCOUNT")
-                .expect("Set your email app account up in the environment bruh")
-                + "+gaia-support")
-                .parse()
-                .expect("There's an error when trying to parse the 'from' email account"),
-        )
-        // Send to where?
-        .to(registration_data
-            .email
-            .parse()
-            .expect("There's an error when trying to parse the 'to' email account"))
-        // Subject
-        .subject("Email Verification Process")
-        // Header
-        .header(lettre::message::header::ContentType::TEXT_PLAIN)
-        // body
-        .body(format!(
-            "Here's the token for verification: {}",
-            generated_verification_token
-        ))
-        // Check if there's an error
-        .expect("There's an error when building email message");
-
-
-    // Setting up email sender
-    let mailer: SmtpTransport = SmtpTransport::starttls_relay("smtp.gmail.com")
-        // If there's an error
-        .expect("There's an error when trying to start SMTP TLS relay")
-        // Put the credentials
-        .credentials(Credentials::new(
-            env::var("EMAIL_APP_ACCOUNT").unwrap(),
-            env::var("EMAIL_APP_PASSWORD")
-                .expect("Set your email app password up in the environment bruh"), // Use an app password, not your account password
-        ))
-        // Set the authentication mechanism
-        .authentication(vec![Mechanism::Plain])
-        // Build the SMTP TLS relay
-        .build();
-    
-
-    // Send an email verification to the given email
-    let result = mailer.send(&mail);
-
-    match result {
-        Ok(_) => (),
-        Err(err) => {
-            log::error!(
-                "There's an error when trying to send email. Error: {}",
-                err.to_string()
-            );
-            return Err(Status::InternalServerError);
-        }
-    }
-
-    // Create or Update a user data with verification token
+    mail: &State<MailService>,
+    accept_language: AcceptLanguage,
+) -> Result<(), IssueError> {
+    // Check if the email is already used by a registered user
     {
         let raw_user_data = sqlx::query_as!(
             User,
@@ -99,61 +29,105 @@ pub async fn post(
             Err(err) => {
                 log::error!(
                     "There's an error when trying to get user data. Error: {}",
-                    err.to_string()
+                    err
                 );
-                return Err(Status::InternalServerError);
+                return Err(Status::InternalServerError.into());
             }
         };
 
-        let raw_query_result: Result<PgQueryResult, sqlx::Error> = match raw_user_data {
-            Some(data) => {
-                // If the user is already registered and has verified before
-                if data.username.is_some() {
-                    return Err(Status::Conflict);
-                }
-                
-                // If the user is already register but hasn't verified yet
-                sqlx::query!(
-                    "UPDATE users SET verification_token = $1 WHERE email = $2",
-                    generated_verification_token,
-                    registration_data.email
-                )
-                .execute(db.inner())
-                .await
-            },
-            None => {
-                // If the user hasn't registered yet
-                let generated_id: String = generate_token(5);
-                sqlx::query!(
-                    "INSERT INTO users(id, email, verification_token) VALUES ($1, $2, $3)",
-                    generated_id,
-                    registration_data.email,
-                    generated_verification_token
-                )
-                .execute(db.inner())
-                .await
-            }
-        };
-        
-        
+        // If the user is already registered and has verified before
+        if let Some(data) = raw_user_data
+            && data.username.is_some() {
+            return Err(Status::Conflict.into());
+        }
+    }
 
-        match raw_query_result {
-            Ok(_) => (),
-            Err(err) => {
-                if is_duplicated_error(&err) {
-                    return Err(Status::Conflict);
-                }
 
-                log::error!(
-                    "There's an error when trying to register user. Error: {}",
-                    err.to_string()
-                );
-                return Err(Status::InternalServerError);
-            }
+    // Check if the email is valid
+    let to_address: lettre::message::Mailbox = match registration_data.email.parse() {
+        Ok(address) => address,
+        Err(_) => {
+            return Err(Status::BadRequest.into());
+        }
+    };
+
+
+    // Start a transaction, so the code is only kept if its email has been queued
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+        Ok(res) => res,
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to start a transaction in registration. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+
+    // Generate verification token bound to the email
+    let generated_verification_token: String = match issue_email_verification(&mut transaction, &registration_data.email, None).await {
+        Ok(IssueResult::Issued(token)) => token,
+        Ok(IssueResult::Cooldown(remaining)) => {
+            log::warn!("Verification email has been requested again too soon. Try again in {} seconds", remaining.whole_seconds());
+            return Err(IssueError::Cooldown(remaining));
+        },
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to create verification token. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+
+    // Render the email with the verification token
+    let expire_minutes: String = email_verification_config().lifetime.whole_minutes().to_string();
+    let email: RenderedEmail = match mail.render("verification", &accept_language.0, &[("code", &generated_verification_token), ("expire_minutes", &expire_minutes)]) {
+        Ok(email) => email,
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to render the verification email. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+
+    // Queue the email with the verification token
+    let result = enqueue_email(
+        &mut *transaction,
+        &to_address,
+        &email,
+    ).await;
+
+    match result {
+        Ok(_) => (),
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to queue the verification email. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError.into());
         }
     }
-    
-    
+
+    match transaction.commit().await {
+        Ok(_) => (),
+        Err(err) => {
+            log::error!(
+                "There's an error when trying to commit the verification token. Error: {}",
+                err
+            );
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    mail.wake_outbox();
+
     // Send the OK result
     Ok(())
 }
@@ -0,0 +1,104 @@
+use rocket::{http::Status, post, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::{auth::password_reset::consume_password_reset, mail::MailService, model::User, types::WebSocketManager, util::password::hash_password};
+
+
+#[derive(Serialize, Deserialize)]
+pub struct ResetPasswordRequestType {
+  token: String,
+  password: String
+}
+
+
+#[post("/user/password/reset", data = "<reset_password_data>")]
+pub async fn post(reset_password_data: Json<ResetPasswordRequestType>, db: &State<Pool<Postgres>>, ws_manager: &State<WebSocketManager>, mail: &State<MailService>) -> Result<(), Status> {
+  if reset_password_data.password.is_empty() {
+    return Err(Status::BadRequest);
+  }
+
+  // Use up the token, set the new password, log the user out everywhere and let them know
+  let user_id: String;
+  {
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+      Ok(res) => res,
+      Err(err) => {
+        log::error!("There's an error when trying to start a transaction in password reset. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    user_id = match consume_password_reset(&mut *transaction, &reset_password_data.token).await {
+      Ok(Some(id)) => id,
+      Ok(None) => {
+        return Err(Status::Unauthorized);
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to consume the password reset token. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    // Only hash once the token is known to be valid, so unknown tokens don't cost a hash
+    let hashed_password: String = match hash_password(&reset_password_data.password).await {
+      Ok(hash) => hash,
+      Err(err) => {
+        log::error!("There's an error when trying to hash password while resetting password. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    let raw_user_data = sqlx::query_as!(
+      User,
+      "UPDATE users SET password = $1 WHERE id = $2 RETURNING *",
+      hashed_password,
+      user_id
+    )
+    .fetch_one(&mut *transaction)
+    .await;
+
+    let user_data: User = match raw_user_data {
+      Ok(data) => data,
+      Err(err) => {
+        log::error!("There's an error when trying to update the password. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    if let Err(err) = mail.enqueue_security_alert(&mut *transaction, &user_data, &user_data.email, "password_changed_alert", &[]).await {
+      log::error!("There's an error when trying to alert the user of the password reset. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+
+    let revoke_result = sqlx::query!(
+      "DELETE FROM sessions WHERE user_id = $1",
+      user_id
+    )
+    .execute(&mut *transaction)
+    .await;
+
+    if let Err(err) = revoke_result {
+      log::error!("There's an error when trying to revoke sessions after password reset. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+
+    match transaction.commit().await {
+      Ok(_) => (),
+      Err(err) => {
+        log::error!("There's an error when trying to commit the password reset. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+  }
+
+
+  mail.wake_outbox();
+
+  // Kick out the live connections that were made with the old sessions
+  ws_manager.close_user_connections(&user_id).await;
+  log::info!("Password has been reset for user: {}", user_id);
+
+  // Return OK Response
+  Ok(())
+}
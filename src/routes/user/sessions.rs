@@ -0,0 +1,120 @@
+use rocket::{delete, get, http::{CookieJar, Status}, serde::json::Json, State, time::PrimitiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::{auth::{guards::AuthenticatedUser, now_utc, remove_access_token_cookie}, model::{custom_serde, Session}, types::WebSocketManager};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExposedSession {
+  pub id: String,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub created_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub last_used_at: PrimitiveDateTime,
+  #[serde(with = "custom_serde::primitive_datetime")]
+  pub expire_at: PrimitiveDateTime,
+  pub user_agent: Option<String>,
+  pub ip_address: Option<String>,
+  /// Whether this is the session making the request
+  pub current: bool
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetSessionsReturnType {
+  pub sessions: Vec<ExposedSession>
+}
+
+
+#[get("/user/sessions")]
+pub async fn get(authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<Json<GetSessionsReturnType>, Status> {
+  // Get all of the sessions that haven't expired yet
+  let raw_sessions_data: Result<Vec<Session>, sqlx::Error> = sqlx::query_as!(
+    Session,
+    "SELECT * FROM sessions WHERE user_id = $1 AND expire_at > $2 ORDER BY last_used_at DESC",
+    authenticated_user.user.id,
+    now_utc()
+  )
+  .fetch_all(db.inner())
+  .await;
+
+  let sessions_data: Vec<Session> = match raw_sessions_data {
+    Ok(data) => data,
+    Err(err) => {
+      log::error!("There's an error when trying to get sessions data. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+
+
+  // Return the sessions without their access tokens
+  Ok(Json(GetSessionsReturnType {
+    sessions: sessions_data.into_iter().map(|session| ExposedSession {
+      current: session.id == authenticated_user.session.id,
+      id: session.id,
+      created_at: session.created_at,
+      last_used_at: session.last_used_at,
+      expire_at: session.expire_at,
+      user_agent: session.user_agent,
+      ip_address: session.ip_address
+    }).collect()
+  }))
+}
+
+#[delete("/user/sessions/<id>")]
+pub async fn delete(id: &str, authenticated_user: AuthenticatedUser, cookies: &CookieJar<'_>, db: &State<Pool<Postgres>>, ws_manager: &State<WebSocketManager>) -> Result<(), Status> {
+  // Revoke the session if it belongs to the user
+  let raw_delete_result = sqlx::query!(
+    "DELETE FROM sessions WHERE id = $1 AND user_id = $2",
+    id,
+    authenticated_user.user.id
+  )
+  .execute(db.inner())
+  .await;
+
+  match raw_delete_result {
+    Ok(res) => {
+      if res.rows_affected() == 0 {
+        return Err(Status::NotFound);
+      }
+    },
+    Err(err) => {
+      log::error!("There's an error when trying to revoke a session. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  }
+
+  // Kick out the live connections made with that session
+  ws_manager.close_session_connections(id).await;
+
+  // The user has logged themselves out
+  if id == authenticated_user.session.id {
+    remove_access_token_cookie(cookies);
+  }
+
+  Ok(())
+}
+
+#[delete("/user/sessions")]
+pub async fn delete_all(authenticated_user: AuthenticatedUser, cookies: &CookieJar<'_>, db: &State<Pool<Postgres>>, ws_manager: &State<WebSocketManager>) -> Result<(), Status> {
+  // Revoke every session of the user, including the current one
+  let raw_delete_result = sqlx::query!(
+    "DELETE FROM sessions WHERE user_id = $1",
+    authenticated_user.user.id
+  )
+  .execute(db.inner())
+  .await;
+
+  match raw_delete_result {
+    Ok(res) => {
+      log::info!("{} sessions have been revoked for user: {}", res.rows_affected(), authenticated_user.user.id);
+    },
+    Err(err) => {
+      log::error!("There's an error when trying to revoke all sessions. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  }
+
+  ws_manager.close_user_connections(&authenticated_user.user.id).await;
+  remove_access_token_cookie(cookies);
+
+  Ok(())
+}
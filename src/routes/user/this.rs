@@ -1,6 +1,6 @@
-use rocket::{get, http::{CookieJar, Status}, serde::json::Json, State, time::PrimitiveDateTime};
+use rocket::{get, serde::json::Json, time::PrimitiveDateTime};
 use serde::{Deserialize, Serialize};
-use crate::model::custom_serde;
+use crate::{auth::guards::AuthenticatedUser, model::{custom_serde, User}};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ExposedUser {
@@ -9,6 +9,22 @@ pub struct ExposedUser {
   pub email: String,
   #[serde(with = "custom_serde::primitive_datetime")]
   pub created_at: PrimitiveDateTime,
+  pub locale: Option<String>,
+  /// How many days the raw readings of the user's devices are kept, none for the server default
+  pub readings_retention_days: Option<i32>
+}
+
+impl From<User> for ExposedUser {
+  fn from(user: User) -> Self {
+    Self {
+      id: user.id,
+      username: user.username,
+      email: user.email,
+      created_at: user.created_at,
+      locale: user.locale,
+      readings_retention_days: user.readings_retention_days
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -17,43 +33,9 @@ pub struct GetUserRequestBody {
 }
 
 #[get("/user/get")]
-pub async fn get(cookies: &CookieJar<'_>, db: &State<sqlx::postgres::PgPool>) -> Result<Json<GetUserRequestBody>, Status> {
-  // Get the access token
-  let access_token: Option<&rocket::http::Cookie<'static>> = cookies.get("access_token");
-
-  let access_token: String = match access_token {
-    Some(token) => token.value().to_string(),
-    None => {
-      return Err(Status::Unauthorized);
-    }
-  };
-  
-  // Get the user data
-  let user_data: ExposedUser;
-  {
-    let raw_user_data: Result<Option<ExposedUser>, sqlx::Error> = sqlx::query_as!(
-      ExposedUser,
-      "SELECT id, username, email, created_at FROM users WHERE access_token = $1",
-      access_token
-    )
-    .fetch_optional(db.inner())
-    .await;
-  
-    user_data = match raw_user_data {
-      Ok(res) => match res {
-        Some(data) => data,
-        None => {
-          return Err(Status::Unauthorized);
-        }
-      },
-      Err(err) => {
-        log::error!("There's an error when trying to get user data. Error: {}", err.to_string());
-        return Err(Status::InternalServerError);
-      }
-    }
-  }
-
-
+pub async fn get(authenticated_user: AuthenticatedUser) -> Json<GetUserRequestBody> {
   // Return user data as the response
-  Ok(Json(GetUserRequestBody { user_data: user_data }))
+  Json(GetUserRequestBody {
+    user_data: authenticated_user.user.into()
+  })
 }
\ No newline at end of file
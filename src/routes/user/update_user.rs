@@ -0,0 +1,70 @@
+use rocket::{http::Status, patch, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::{auth::guards::AuthenticatedUser, model::User, routes::user::this::GetUserRequestBody, telemetry::retention::is_valid_retention_days, util::{is_duplicated_error, locale::is_valid_locale}};
+
+
+#[derive(Serialize, Deserialize)]
+pub struct UpdateUserRequestType {
+  username: Option<String>,
+  /// The language emails are sent in, an empty string goes back to the browser's language
+  locale: Option<String>,
+  /// How many days the raw readings of the user's devices are kept, 0 goes back to the server default
+  readings_retention_days: Option<i32>
+}
+
+
+#[patch("/user", data = "<update_user_data>")]
+pub async fn patch(update_user_data: Json<UpdateUserRequestType>, authenticated_user: AuthenticatedUser, db: &State<Pool<Postgres>>) -> Result<Json<GetUserRequestBody>, Status> {
+  if update_user_data.username.is_none() && update_user_data.locale.is_none() && update_user_data.readings_retention_days.is_none() {
+    return Err(Status::BadRequest);
+  }
+
+  let username: Option<&str> = update_user_data.username.as_deref().map(|username| username.trim());
+  if username.is_some_and(|username| username.is_empty()) {
+    return Err(Status::BadRequest);
+  }
+
+  // Users who haven't finished registering have to go through /user/register/3
+  if username.is_some() && authenticated_user.user.username.is_none() {
+    return Err(Status::Forbidden);
+  }
+
+  let locale: Option<String> = update_user_data.locale.as_deref().map(|locale| locale.trim().to_lowercase());
+  if let Some(locale) = &locale && !locale.is_empty() && !is_valid_locale(locale) {
+    return Err(Status::BadRequest);
+  }
+
+  if update_user_data.readings_retention_days.is_some_and(|days| !is_valid_retention_days(days)) {
+    return Err(Status::BadRequest);
+  }
+
+  // Update the fields that have been given
+  let raw_user_data: Result<User, sqlx::Error> = sqlx::query_as!(
+    User,
+    "UPDATE users SET username = COALESCE($1, username), locale = CASE WHEN $2::TEXT IS NULL THEN locale ELSE NULLIF($2, '') END,
+    readings_retention_days = CASE WHEN $3::INTEGER IS NULL THEN readings_retention_days ELSE NULLIF($3, 0) END WHERE id = $4 RETURNING *",
+    username,
+    locale,
+    update_user_data.readings_retention_days,
+    authenticated_user.user.id
+  )
+  .fetch_one(db.inner())
+  .await;
+
+  let user_data: User = match raw_user_data {
+    Ok(data) => data,
+    Err(err) => {
+      if is_duplicated_error(&err) {
+        return Err(Status::Conflict);
+      }
+
+      log::error!("There's an error when trying to update the user. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+  };
+
+
+  // Return the updated user data
+  Ok(Json(GetUserRequestBody { user_data: user_data.into() }))
+}
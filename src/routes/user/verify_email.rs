@@ -1,81 +1,134 @@
-use rocket::{http::{Cookie, CookieJar, Status}, post, serde::json::Json, time::{macros::datetime, OffsetDateTime, PrimitiveDateTime}, State};
+use rocket::{http::{CookieJar, Status}, post, serde::json::Json, State};
 use serde::{Serialize, Deserialize};
 
-use crate::{model::User, util::{generate_token, is_duplicated_error}};
+use crate::{auth::{create_session, email_verification::{check_email_verification, consume_email_verification, VerificationCheck}, guards::ClientInfo, set_access_token_cookie}, model::{EmailVerification, Session, User}, util::{generate_token, is_duplicated_error}};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RegistrationRequestType {
+  email: String,
   verification_token: String,
 }
 
 
 #[post("/user/register/2", data = "<registration_data>")]
-pub async fn post(registration_data: Json<RegistrationRequestType>, cookies: &CookieJar<'_>, db: &State<sqlx::postgres::PgPool>) -> Result<(), Status> {
-  // Verify the registration token
+pub async fn post(registration_data: Json<RegistrationRequestType>, cookies: &CookieJar<'_>, db: &State<sqlx::postgres::PgPool>, client_info: ClientInfo) -> Result<(), Status> {
+  // Verify the registration token against the one sent to the email
+  let verification: EmailVerification;
   {
-    let selected_user_data = sqlx::query_as!(
-      User,
-      "SELECT * FROM users WHERE verification_token = $1",
-      registration_data.verification_token
-    )
-    .fetch_optional(db.inner())
-    .await;
+    let verification_check = check_email_verification(db.inner(), &registration_data.email, None, &registration_data.verification_token).await;
 
-    match selected_user_data {
+    verification = match verification_check {
       Ok(res) => match res {
-        Some(_) => (),
-        None => {
+        VerificationCheck::Valid(data) => data,
+        VerificationCheck::Invalid => {
           return Err(Status::Unauthorized);
+        },
+        VerificationCheck::TooManyAttempts => {
+          log::warn!("Too many verification attempts for email: {}", registration_data.email);
+          return Err(Status::TooManyRequests);
         }
       },
       Err(err) => {
-        log::error!("There's an error when trying to get the user data. Error: {}", err.to_string());
+        log::error!("There's an error when trying to check the verification token. Error: {}", err);
         return Err(Status::InternalServerError);
       }
     };
   }
 
 
-  // Generate access token
-  let generated_access_token: String = generate_token(20);
-  let current_date: OffsetDateTime = OffsetDateTime::now_utc();
-  let access_token_expire_date: PrimitiveDateTime = datetime!(2000-01-01 00:00:00).replace_date(current_date.date().saturating_add(time::Duration::days(14)));
-  
-  // Update the data in database
+  // Consume the verification token, create the user and start a new session
+  let session: Session;
   {
-    let update_result = sqlx::query!(
-      "UPDATE users SET access_token = $1, access_token_expire = $2, verification_token = NULL WHERE verification_token = $3",
-      generated_access_token,
-      access_token_expire_date,
-      registration_data.verification_token
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+      Ok(res) => res,
+      Err(err) => {
+        log::error!("There's an error when trying to start a transaction in registration. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    match consume_email_verification(&mut *transaction, &verification).await {
+      Ok(true) => (),
+      Ok(false) => {
+        // Somebody else has used this token in the meantime
+        return Err(Status::Unauthorized);
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to consume the verification token. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+
+    // Users who verified before but never finished registering keep their account
+    let raw_user_data = sqlx::query_as!(
+      User,
+      "SELECT * FROM users WHERE email = $1",
+      verification.email
     )
-    .execute(db.inner())
+    .fetch_optional(&mut *transaction)
     .await;
-  
-    match update_result {
+
+    let raw_user_data: Result<User, sqlx::Error> = match raw_user_data {
+      Ok(Some(data)) => {
+        if data.username.is_some() {
+          return Err(Status::Conflict);
+        }
+        Ok(data)
+      },
+      Ok(None) => {
+        sqlx::query_as!(
+          User,
+          "INSERT INTO users(id, email) VALUES ($1, $2) RETURNING *",
+          generate_token(5),
+          verification.email
+        )
+        .fetch_one(&mut *transaction)
+        .await
+      },
+      Err(err) => Err(err)
+    };
+
+    let user_data: User = match raw_user_data {
+      Ok(data) => data,
+      Err(err) => {
+        if is_duplicated_error(&err) {
+          return Err(Status::Conflict);
+        }
+
+        log::error!("There's an error when trying to create the user data in registration. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    let session_result = create_session(&mut *transaction, &user_data.id, &client_info).await;
+
+    session = match session_result {
       Ok(res) => res,
       Err(err) => {
         if is_duplicated_error(&err) {
           return Err(Status::Conflict);
         }
         
-        log::error!("There's an error when trying to update the user data in registration. Error: {}", err.to_string());
+        log::error!("There's an error when trying to create a session in registration. Error: {}", err);
         return Err(Status::InternalServerError);
       }
     };
+
+    match transaction.commit().await {
+      Ok(_) => (),
+      Err(err) => {
+        log::error!("There's an error when trying to commit the registration. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
   }
 
 
   // Create cookie
-  cookies.add(
-    Cookie::build(("access_token", generated_access_token))
-    .path("/")
-    .secure(true)
-    .http_only(true)
-    .max_age(rocket::time::Duration::days(14))
-  );
+  set_access_token_cookie(cookies, session.access_token, session.expire_at);
   
 
   // Return OK Response
   Ok(())
-}
\ No newline at end of file
+}
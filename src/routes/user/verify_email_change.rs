@@ -0,0 +1,105 @@
+use rocket::{http::Status, post, serde::json::Json, State};
+use serde::{Serialize, Deserialize};
+
+use crate::{auth::{email_verification::{check_email_verification, consume_email_verification, VerificationCheck}, guards::AuthenticatedUser}, mail::MailService, model::{EmailVerification, User}, routes::user::this::GetUserRequestBody, util::is_duplicated_error};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerifyEmailChangeRequestType {
+  email: String,
+  verification_token: String,
+}
+
+
+#[post("/user/email/verify", data = "<verification_data>")]
+pub async fn post(verification_data: Json<VerifyEmailChangeRequestType>, authenticated_user: AuthenticatedUser, db: &State<sqlx::postgres::PgPool>, mail: &State<MailService>) -> Result<Json<GetUserRequestBody>, Status> {
+  // Verify the token against the one sent to the new email for this user
+  let verification: EmailVerification;
+  {
+    let verification_check = check_email_verification(db.inner(), &verification_data.email, Some(&authenticated_user.user.id), &verification_data.verification_token).await;
+
+    verification = match verification_check {
+      Ok(res) => match res {
+        VerificationCheck::Valid(data) => data,
+        VerificationCheck::Invalid => {
+          return Err(Status::Unauthorized);
+        },
+        VerificationCheck::TooManyAttempts => {
+          log::warn!("Too many email change verification attempts for user: {}", authenticated_user.user.id);
+          return Err(Status::TooManyRequests);
+        }
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to check the verification token. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+  }
+
+
+  // Consume the verification token, swap the email and let the previous one know
+  let user_data: User;
+  {
+    let transaction = db.inner().begin().await;
+    let mut transaction = match transaction {
+      Ok(res) => res,
+      Err(err) => {
+        log::error!("There's an error when trying to start a transaction in email change. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    match consume_email_verification(&mut *transaction, &verification).await {
+      Ok(true) => (),
+      Ok(false) => {
+        // Somebody else has used this token in the meantime
+        return Err(Status::Unauthorized);
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to consume the verification token. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+
+    let raw_user_data = sqlx::query_as!(
+      User,
+      "UPDATE users SET email = $1 WHERE id = $2 RETURNING *",
+      verification.email,
+      authenticated_user.user.id
+    )
+    .fetch_one(&mut *transaction)
+    .await;
+
+    user_data = match raw_user_data {
+      Ok(data) => data,
+      Err(err) => {
+        // Somebody has registered with this email in the meantime
+        if is_duplicated_error(&err) {
+          return Err(Status::Conflict);
+        }
+
+        log::error!("There's an error when trying to update the email. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    };
+
+    if let Err(err) = mail.enqueue_security_alert(&mut *transaction, &user_data, &authenticated_user.user.email, "email_changed_alert", &[("new_email", &user_data.email)]).await {
+      log::error!("There's an error when trying to alert the user of the email change. Error: {}", err);
+      return Err(Status::InternalServerError);
+    }
+
+    match transaction.commit().await {
+      Ok(_) => (),
+      Err(err) => {
+        log::error!("There's an error when trying to commit the email change. Error: {}", err);
+        return Err(Status::InternalServerError);
+      }
+    }
+  }
+
+
+  mail.wake_outbox();
+
+
+  // Return the updated user data
+  Ok(Json(GetUserRequestBody { user_data: user_data.into() }))
+}
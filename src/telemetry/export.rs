@@ -0,0 +1,452 @@
+use std::{env, io::Write, sync::{Arc, OnceLock}};
+use flate2::{write::GzEncoder, Compression};
+use parquet::{
+  data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type},
+  file::{properties::WriterProperties, writer::SerializedFileWriter},
+  schema::parser::parse_message_type
+};
+use rocket::time::{format_description::FormatItem, macros::format_description, PrimitiveDateTime};
+use serde_json::json;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+
+/// How much encoded output is held back before it's sent, so rows aren't sent one by one.
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+/// How many rows go in a Parquet row group. A row group is only sent once it's full.
+const PARQUET_ROW_GROUP_SIZE: usize = 50_000;
+
+/// Times are exported in UTC down to the microsecond, which is as precise as they're stored.
+const EXPORT_TIME_FORMAT: &[FormatItem<'static>] = format_description!("[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:6]");
+
+const PARQUET_SCHEMA: &str = "
+  message reading {
+    REQUIRED BYTE_ARRAY device_id (UTF8);
+    REQUIRED BYTE_ARRAY sensor (UTF8);
+    OPTIONAL DOUBLE numeric_value;
+    OPTIONAL BYTE_ARRAY text_value (UTF8);
+    OPTIONAL INT64 device_time (TIMESTAMP(MICROS, false));
+    REQUIRED INT64 received_at (TIMESTAMP(MICROS, false));
+  }
+";
+
+static MAX_CONCURRENT_EXPORTS: OnceLock<usize> = OnceLock::new();
+
+/// The most exports that can run at once, read once from `READINGS_MAX_CONCURRENT_EXPORTS`. Each
+/// export holds a database connection until the client has read all of it, so this has to stay
+/// well below the size of the pool.
+pub fn max_concurrent_exports() -> usize {
+  *MAX_CONCURRENT_EXPORTS.get_or_init(|| {
+    match env::var("READINGS_MAX_CONCURRENT_EXPORTS") {
+      Ok(value) => value.parse::<usize>().expect("READINGS_MAX_CONCURRENT_EXPORTS must be a valid number").max(1),
+      Err(_) => 2
+    }
+  })
+}
+
+/// Hands out the slots exports run in, so they can't take up every database connection.
+#[derive(Clone)]
+pub struct ExportSlots {
+  semaphore: Arc<Semaphore>
+}
+
+impl ExportSlots {
+  pub fn new() -> Self {
+    Self { semaphore: Arc::new(Semaphore::new(max_concurrent_exports())) }
+  }
+
+  /// Take a slot for an export, kept until the permit is dropped. None if every slot is taken.
+  pub fn try_take(&self) -> Option<OwnedSemaphorePermit> {
+    self.semaphore.clone().try_acquire_owned().ok()
+  }
+}
+
+impl Default for ExportSlots {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+  Csv,
+  Ndjson,
+  Parquet
+}
+
+impl ExportFormat {
+  pub fn parse(format: &str) -> Option<Self> {
+    match format {
+      "csv" => Some(Self::Csv),
+      "ndjson" => Some(Self::Ndjson),
+      "parquet" => Some(Self::Parquet),
+      _ => None
+    }
+  }
+
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Self::Csv => "csv",
+      Self::Ndjson => "ndjson",
+      Self::Parquet => "parquet"
+    }
+  }
+
+  pub fn content_type(&self) -> &'static str {
+    match self {
+      Self::Csv => "text/csv; charset=utf-8",
+      Self::Ndjson => "application/x-ndjson",
+      Self::Parquet => "application/vnd.apache.parquet"
+    }
+  }
+}
+
+
+/// A stored reading as it's exported.
+#[derive(Debug)]
+pub struct ExportRow {
+  pub device_id: String,
+  pub sensor: String,
+  pub numeric_value: Option<f64>,
+  pub text_value: Option<String>,
+  pub device_time: Option<PrimitiveDateTime>,
+  pub received_at: PrimitiveDateTime
+}
+
+fn unix_micros(time: PrimitiveDateTime) -> i64 {
+  (time.assume_utc().unix_timestamp_nanos() / 1000) as i64
+}
+
+fn format_time(time: PrimitiveDateTime) -> String {
+  time.format(EXPORT_TIME_FORMAT).unwrap_or_default()
+}
+
+fn escape_csv(value: &str) -> String {
+  if value.contains([',', '"', '\n', '\r']) {
+    format!("\"{}\"", value.replace('"', "\"\""))
+  } else {
+    value.to_string()
+  }
+}
+
+
+/// The rows of a Parquet row group, column by column.
+#[derive(Default)]
+struct ParquetColumns {
+  device_ids: Vec<ByteArray>,
+  sensors: Vec<ByteArray>,
+  numeric_values: Vec<f64>,
+  numeric_levels: Vec<i16>,
+  text_values: Vec<ByteArray>,
+  text_levels: Vec<i16>,
+  device_times: Vec<i64>,
+  device_time_levels: Vec<i16>,
+  received_ats: Vec<i64>
+}
+
+impl ParquetColumns {
+  fn push(&mut self, row: ExportRow) {
+    self.device_ids.push(ByteArray::from(row.device_id.into_bytes()));
+    self.sensors.push(ByteArray::from(row.sensor.into_bytes()));
+
+    // Optional columns only hold the values that are there, the levels tell which rows have one
+    self.numeric_levels.push(row.numeric_value.is_some() as i16);
+    self.numeric_values.extend(row.numeric_value);
+    self.text_levels.push(row.text_value.is_some() as i16);
+    self.text_values.extend(row.text_value.map(|text| ByteArray::from(text.into_bytes())));
+    self.device_time_levels.push(row.device_time.is_some() as i16);
+    self.device_times.extend(row.device_time.map(unix_micros));
+
+    self.received_ats.push(unix_micros(row.received_at));
+  }
+
+  fn len(&self) -> usize {
+    self.received_ats.len()
+  }
+}
+
+/// A Parquet file being written, along with the rows of the row group that hasn't been written yet.
+struct ParquetEncoder {
+  writer: SerializedFileWriter<Vec<u8>>,
+  columns: ParquetColumns
+}
+
+enum Encoder {
+  Csv,
+  Ndjson,
+  Parquet(Box<ParquetEncoder>)
+}
+
+/// Turns rows into one of the export formats a chunk at a time, optionally gzipped.
+pub struct ExportEncoder {
+  encoder: Encoder,
+  gzip: Option<GzEncoder<Vec<u8>>>,
+  output: Vec<u8>
+}
+
+impl ExportEncoder {
+  pub fn new(format: ExportFormat, gzip: bool) -> Result<Self, String> {
+    let encoder: Encoder = match format {
+      ExportFormat::Csv => Encoder::Csv,
+      ExportFormat::Ndjson => Encoder::Ndjson,
+      ExportFormat::Parquet => {
+        let schema = parse_message_type(PARQUET_SCHEMA).map_err(|err| format!("The Parquet schema isn't valid. Error: {}", err))?;
+        let writer = SerializedFileWriter::new(Vec::new(), Arc::new(schema), Arc::new(WriterProperties::builder().build()))
+          .map_err(|err| format!("There's an error when trying to start a Parquet file. Error: {}", err))?;
+        Encoder::Parquet(Box::new(ParquetEncoder { writer, columns: ParquetColumns::default() }))
+      }
+    };
+
+    let mut export_encoder: ExportEncoder = Self {
+      encoder,
+      gzip: if gzip { Some(GzEncoder::new(Vec::new(), Compression::default())) } else { None },
+      output: Vec::new()
+    };
+
+    if format == ExportFormat::Csv {
+      export_encoder.write(b"device_id,sensor,numeric_value,text_value,device_time,received_at\n")?;
+    }
+
+    Ok(export_encoder)
+  }
+
+  fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+    match &mut self.gzip {
+      Some(gzip) => {
+        gzip.write_all(bytes).map_err(|err| format!("There's an error when trying to compress the export. Error: {}", err))?;
+        self.output.append(gzip.get_mut());
+      },
+      None => self.output.extend_from_slice(bytes)
+    }
+
+    Ok(())
+  }
+
+  /// Encode a row. Returns a chunk to send once enough output has built up.
+  pub fn push(&mut self, row: ExportRow) -> Result<Option<Vec<u8>>, String> {
+    match &mut self.encoder {
+      Encoder::Csv => {
+        let line: String = format!(
+          "{},{},{},{},{},{}\n",
+          escape_csv(&row.device_id),
+          escape_csv(&row.sensor),
+          row.numeric_value.map(|number| number.to_string()).unwrap_or_default(),
+          row.text_value.as_deref().map(escape_csv).unwrap_or_default(),
+          row.device_time.map(format_time).unwrap_or_default(),
+          format_time(row.received_at)
+        );
+        self.write(line.as_bytes())?;
+      },
+      Encoder::Ndjson => {
+        let line = json!({
+          "device_id": row.device_id,
+          "sensor": row.sensor,
+          "numeric_value": row.numeric_value,
+          "text_value": row.text_value,
+          "device_time": row.device_time.map(format_time),
+          "received_at": format_time(row.received_at)
+        });
+        let mut line: Vec<u8> = serde_json::to_vec(&line).map_err(|err| format!("There's an error when trying to serialize a reading. Error: {}", err))?;
+        line.push(b'\n');
+        self.write(&line)?;
+      },
+      Encoder::Parquet(parquet) => {
+        parquet.columns.push(row);
+        if parquet.columns.len() >= PARQUET_ROW_GROUP_SIZE {
+          self.write_row_group()?;
+        }
+      }
+    }
+
+    if self.output.len() >= EXPORT_CHUNK_SIZE {
+      return Ok(Some(std::mem::take(&mut self.output)));
+    }
+
+    Ok(None)
+  }
+
+  fn write_row_group(&mut self) -> Result<(), String> {
+    let Encoder::Parquet(parquet) = &mut self.encoder else {
+      return Ok(());
+    };
+
+    let writer: &mut SerializedFileWriter<Vec<u8>> = &mut parquet.writer;
+    let columns: ParquetColumns = std::mem::take(&mut parquet.columns);
+    if columns.len() == 0 {
+      return Ok(());
+    }
+
+    let to_error = |err: parquet::errors::ParquetError| format!("There's an error when trying to write a Parquet row group. Error: {}", err);
+    let mut row_group = writer.next_row_group().map_err(to_error)?;
+    let mut column_index: usize = 0;
+
+    while let Some(mut column) = row_group.next_column().map_err(to_error)? {
+      match column_index {
+        0 => column.typed::<ByteArrayType>().write_batch(&columns.device_ids, None, None),
+        1 => column.typed::<ByteArrayType>().write_batch(&columns.sensors, None, None),
+        2 => column.typed::<DoubleType>().write_batch(&columns.numeric_values, Some(&columns.numeric_levels), None),
+        3 => column.typed::<ByteArrayType>().write_batch(&columns.text_values, Some(&columns.text_levels), None),
+        4 => column.typed::<Int64Type>().write_batch(&columns.device_times, Some(&columns.device_time_levels), None),
+        _ => column.typed::<Int64Type>().write_batch(&columns.received_ats, None, None)
+      }
+      .map_err(to_error)?;
+
+      column.close().map_err(to_error)?;
+      column_index += 1;
+    }
+    row_group.close().map_err(to_error)?;
+
+    // Hand over what the writer has put out so far
+    let written: Vec<u8> = std::mem::take(writer.inner_mut());
+    self.write(&written)
+  }
+
+  /// Encode what's left along with the end of the file.
+  pub fn finish(mut self) -> Result<Vec<u8>, String> {
+    self.write_row_group()?;
+
+    if let Encoder::Parquet(parquet) = self.encoder {
+      let footer: Vec<u8> = parquet.writer.into_inner().map_err(|err| format!("There's an error when trying to finish the Parquet file. Error: {}", err))?;
+      self.encoder = Encoder::Csv;
+      self.write(&footer)?;
+    }
+
+    if let Some(gzip) = self.gzip.take() {
+      let rest: Vec<u8> = gzip.finish().map_err(|err| format!("There's an error when trying to finish compressing the export. Error: {}", err))?;
+      self.output.extend(rest);
+    }
+
+    Ok(self.output)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::io::Read;
+  use bytes::Bytes;
+  use flate2::read::GzDecoder;
+  use parquet::{file::reader::{FileReader, SerializedFileReader}, record::{Field, Row}};
+  use rocket::time::macros::datetime;
+  use super::*;
+
+  fn numeric_row() -> ExportRow {
+    ExportRow {
+      device_id: String::from("dev1"),
+      sensor: String::from("temp"),
+      numeric_value: Some(21.5),
+      text_value: None,
+      device_time: Some(datetime!(2026-10-18 08:30:00.123456)),
+      received_at: datetime!(2026-10-18 08:30:01)
+    }
+  }
+
+  fn text_row(text: &str) -> ExportRow {
+    ExportRow {
+      device_id: String::from("dev1"),
+      sensor: String::from("note"),
+      numeric_value: None,
+      text_value: Some(text.to_string()),
+      device_time: None,
+      received_at: datetime!(2026-10-18 08:30:02)
+    }
+  }
+
+  /// Encode every row and put the chunks back together.
+  fn encode(format: ExportFormat, gzip: bool, rows: impl IntoIterator<Item = ExportRow>) -> Vec<u8> {
+    let mut encoder: ExportEncoder = ExportEncoder::new(format, gzip).unwrap();
+    let mut output: Vec<u8> = Vec::new();
+    for row in rows {
+      if let Some(chunk) = encoder.push(row).unwrap() {
+        output.extend(chunk);
+      }
+    }
+    output.extend(encoder.finish().unwrap());
+    output
+  }
+
+  #[test]
+  fn csv_quotes_values_and_leaves_missing_ones_empty() {
+    let output: Vec<u8> = encode(ExportFormat::Csv, false, [numeric_row(), text_row("a,b \"c\"\nd")]);
+
+    assert_eq!(
+      String::from_utf8(output).unwrap(),
+      "device_id,sensor,numeric_value,text_value,device_time,received_at\n\
+      dev1,temp,21.5,,2026-10-18T08:30:00.123456,2026-10-18T08:30:01.000000\n\
+      dev1,note,,\"a,b \"\"c\"\"\nd\",,2026-10-18T08:30:02.000000\n"
+    );
+  }
+
+  #[test]
+  fn ndjson_line() {
+    let output: Vec<u8> = encode(ExportFormat::Ndjson, false, [numeric_row(), text_row("open")]);
+    let lines: Vec<serde_json::Value> = String::from_utf8(output).unwrap()
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+
+    assert_eq!(lines, vec![
+      json!({
+        "device_id": "dev1",
+        "sensor": "temp",
+        "numeric_value": 21.5,
+        "text_value": null,
+        "device_time": "2026-10-18T08:30:00.123456",
+        "received_at": "2026-10-18T08:30:01.000000"
+      }),
+      json!({
+        "device_id": "dev1",
+        "sensor": "note",
+        "numeric_value": null,
+        "text_value": "open",
+        "device_time": null,
+        "received_at": "2026-10-18T08:30:02.000000"
+      })
+    ]);
+  }
+
+  #[test]
+  fn parquet_round_trip() {
+    let output: Vec<u8> = encode(ExportFormat::Parquet, false, [numeric_row(), text_row("open")]);
+    let reader: SerializedFileReader<Bytes> = SerializedFileReader::new(Bytes::from(output)).unwrap();
+    assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+
+    let rows: Vec<Vec<Field>> = reader.get_row_iter(None).unwrap()
+      .map(|row| row.unwrap())
+      .map(|row: Row| row.get_column_iter().map(|(_, field)| field.clone()).collect())
+      .collect();
+
+    assert_eq!(rows, vec![
+      vec![
+        Field::Str(String::from("dev1")),
+        Field::Str(String::from("temp")),
+        Field::Double(21.5),
+        Field::Null,
+        Field::TimestampMicros(unix_micros(datetime!(2026-10-18 08:30:00.123456))),
+        Field::TimestampMicros(unix_micros(datetime!(2026-10-18 08:30:01)))
+      ],
+      vec![
+        Field::Str(String::from("dev1")),
+        Field::Str(String::from("note")),
+        Field::Null,
+        Field::Str(String::from("open")),
+        Field::Null,
+        Field::TimestampMicros(unix_micros(datetime!(2026-10-18 08:30:02)))
+      ]
+    ]);
+  }
+
+  #[test]
+  fn gzip_decodes_to_the_plain_output() {
+    // Enough rows to be sent in several chunks
+    let rows = || (0..5_000).map(|index| if index % 2 == 0 { numeric_row() } else { text_row("a,b") });
+
+    for format in [ExportFormat::Csv, ExportFormat::Ndjson, ExportFormat::Parquet] {
+      let plain: Vec<u8> = encode(format, false, rows());
+      let mut decoded: Vec<u8> = Vec::new();
+      GzDecoder::new(encode(format, true, rows()).as_slice()).read_to_end(&mut decoded).unwrap();
+
+      assert_eq!(decoded, plain, "{:?}", format);
+    }
+  }
+}
@@ -0,0 +1,376 @@
+use std::{env, sync::{atomic::{AtomicU64, Ordering}, Arc, OnceLock}, time::Duration};
+use rocket::time::PrimitiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tokio::{sync::{mpsc::{self, error::TrySendError}, Mutex, Notify}, task::JoinHandle, time::Instant};
+use crate::telemetry::{Reading, ReadingValue};
+
+
+/// Settings of the readings ingestion pipeline.
+pub struct IngestConfig {
+  /// How many readings can wait to be stored, readings that come in while it's full are dropped
+  pub queue_size: usize,
+  /// The most readings stored in one INSERT
+  pub batch_size: usize,
+  /// How long a reading waits for its batch to fill up before it's stored anyway
+  pub flush_interval: Duration
+}
+
+static INGEST_CONFIG: OnceLock<IngestConfig> = OnceLock::new();
+
+fn read_number(name: &str, default: u64) -> u64 {
+  match env::var(name) {
+    Ok(value) => value.parse::<u64>().unwrap_or_else(|_| panic!("{} must be a valid number", name)),
+    Err(_) => default
+  }
+}
+
+/// Ingestion settings, read once from `READINGS_INGEST_QUEUE_SIZE`, `READINGS_INGEST_BATCH_SIZE`
+/// and `READINGS_INGEST_FLUSH_MS`.
+pub fn ingest_config() -> &'static IngestConfig {
+  INGEST_CONFIG.get_or_init(|| {
+    IngestConfig {
+      queue_size: read_number("READINGS_INGEST_QUEUE_SIZE", 10_000).max(1) as usize,
+      batch_size: read_number("READINGS_INGEST_BATCH_SIZE", 500).clamp(1, 10_000) as usize,
+      flush_interval: Duration::from_millis(read_number("READINGS_INGEST_FLUSH_MS", 200).max(1))
+    }
+  })
+}
+
+
+/// A reading waiting to be stored.
+struct PendingReading {
+  device_id: String,
+  reading: Reading,
+  received_at: PrimitiveDateTime
+}
+
+/// Counters of the ingestion pipeline, since the server started.
+#[derive(Default)]
+struct IngestCounters {
+  queued: AtomicU64,
+  dropped: AtomicU64,
+  stored: AtomicU64,
+  failed: AtomicU64,
+  batches: AtomicU64,
+  last_batch_size: AtomicU64,
+  last_flush_micros: AtomicU64
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IngestMetrics {
+  /// Readings waiting to be stored right now
+  pub queue_depth: usize,
+  pub queue_capacity: usize,
+  /// Readings that have been accepted into the queue
+  pub queued: u64,
+  /// Readings that have been dropped because the queue was full
+  pub dropped: u64,
+  pub stored: u64,
+  /// Readings the database refused, even when stored on their own, or that couldn't be stored before shutting down
+  pub failed: u64,
+  pub batches: u64,
+  pub last_batch_size: u64,
+  /// How long the last INSERT took, in microseconds
+  pub last_flush_micros: u64
+}
+
+/// Hands readings over to the ingestion worker without waiting on the database, so a slow database
+/// never holds up a socket. Cheap to clone.
+#[derive(Clone)]
+pub struct ReadingIngest {
+  sender: mpsc::Sender<PendingReading>,
+  counters: Arc<IngestCounters>,
+  /// Tells the worker to store what's left in the queue and stop
+  shutdown: Arc<Notify>,
+  worker: Arc<Mutex<Option<JoinHandle<()>>>>
+}
+
+impl ReadingIngest {
+  /// Start the ingestion worker, which stores the readings in batches until it's shut down.
+  pub fn start(pool: Pool<Postgres>) -> Self {
+    let config: &IngestConfig = ingest_config();
+    let (sender, receiver) = mpsc::channel::<PendingReading>(config.queue_size);
+    let counters: Arc<IngestCounters> = Arc::new(IngestCounters::default());
+    let shutdown: Arc<Notify> = Arc::new(Notify::new());
+
+    let worker: JoinHandle<()> = tokio::spawn(run_ingest_worker(pool, receiver, counters.clone(), shutdown.clone()));
+
+    Self { sender, counters, shutdown, worker: Arc::new(Mutex::new(Some(worker))) }
+  }
+
+  /// Stop taking readings and wait until the ones in the queue have been stored. Readings that come
+  /// in after this are dropped.
+  pub async fn shutdown(&self) {
+    self.shutdown.notify_one();
+
+    let worker: Option<JoinHandle<()>> = self.worker.lock().await.take();
+    if let Some(worker) = worker && let Err(err) = worker.await {
+      log::error!("There's an error when trying to store the last sensor readings. Error: {}", err);
+    }
+  }
+
+  /// Queue a reading to be stored. Returns false if it has been dropped because the queue is full.
+  pub fn ingest(&self, device_id: &str, reading: Reading, received_at: PrimitiveDateTime) -> bool {
+    let pending_reading: PendingReading = PendingReading { device_id: device_id.to_string(), reading, received_at };
+
+    match self.sender.try_send(pending_reading) {
+      Ok(_) => {
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        true
+      },
+      Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => {
+        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        false
+      }
+    }
+  }
+
+  pub fn metrics(&self) -> IngestMetrics {
+    IngestMetrics {
+      queue_depth: self.sender.max_capacity() - self.sender.capacity(),
+      queue_capacity: self.sender.max_capacity(),
+      queued: self.counters.queued.load(Ordering::Relaxed),
+      dropped: self.counters.dropped.load(Ordering::Relaxed),
+      stored: self.counters.stored.load(Ordering::Relaxed),
+      failed: self.counters.failed.load(Ordering::Relaxed),
+      batches: self.counters.batches.load(Ordering::Relaxed),
+      last_batch_size: self.counters.last_batch_size.load(Ordering::Relaxed),
+      last_flush_micros: self.counters.last_flush_micros.load(Ordering::Relaxed)
+    }
+  }
+}
+
+
+/// Store a batch of readings with a single INSERT. Readings of devices that have been deleted
+/// while they were queued are left out.
+async fn store_readings(pool: &Pool<Postgres>, batch: &[PendingReading]) -> Result<u64, sqlx::Error> {
+  let mut device_ids: Vec<String> = Vec::with_capacity(batch.len());
+  let mut sensors: Vec<String> = Vec::with_capacity(batch.len());
+  let mut numeric_values: Vec<Option<f64>> = Vec::with_capacity(batch.len());
+  let mut text_values: Vec<Option<String>> = Vec::with_capacity(batch.len());
+  let mut device_times: Vec<Option<PrimitiveDateTime>> = Vec::with_capacity(batch.len());
+  let mut received_ats: Vec<PrimitiveDateTime> = Vec::with_capacity(batch.len());
+
+  for pending_reading in batch {
+    device_ids.push(pending_reading.device_id.clone());
+    sensors.push(pending_reading.reading.sensor.clone());
+    match &pending_reading.reading.value {
+      ReadingValue::Numeric(number) => {
+        numeric_values.push(Some(*number));
+        text_values.push(None);
+      },
+      ReadingValue::Text(text) => {
+        numeric_values.push(None);
+        text_values.push(Some(text.clone()));
+      }
+    }
+    device_times.push(pending_reading.reading.device_time);
+    received_ats.push(pending_reading.received_at);
+  }
+
+  let result = sqlx::query!(
+    "INSERT INTO device_readings(device_id, sensor, numeric_value, text_value, device_time, received_at)
+    SELECT readings.* FROM UNNEST($1::TEXT[], $2::TEXT[], $3::DOUBLE PRECISION[], $4::TEXT[], $5::TIMESTAMP[], $6::TIMESTAMP[])
+      AS readings(device_id, sensor, numeric_value, text_value, device_time, received_at)
+    WHERE EXISTS (SELECT 1 FROM devices WHERE devices.id = readings.device_id)",
+    &device_ids,
+    &sensors,
+    &numeric_values as &[Option<f64>],
+    &text_values as &[Option<String>],
+    &device_times as &[Option<PrimitiveDateTime>],
+    &received_ats
+  )
+  .execute(pool)
+  .await?;
+
+  Ok(result.rows_affected())
+}
+
+/// Longest wait between two tries of readings the database couldn't be reached for
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+/// How many times readings are tried while shutting down before they're given up on
+const SHUTDOWN_ATTEMPTS: u32 = 3;
+
+/// Store readings, waiting longer and longer and trying again while the database can't be reached.
+/// Only errors of the database itself are returned, or the last one once it has been tried
+/// [`SHUTDOWN_ATTEMPTS`] times while `closing`. Being told to shut down while waiting sets `closing`,
+/// so an outage doesn't hold up the shutdown.
+async fn store_readings_with_retry(pool: &Pool<Postgres>, readings: &[PendingReading], shutdown: &Notify, closing: &mut bool) -> Result<u64, sqlx::Error> {
+  let mut backoff: Duration = ingest_config().flush_interval;
+  let mut attempts: u32 = 0;
+
+  loop {
+    let err: sqlx::Error = match store_readings(pool, readings).await {
+      Ok(stored) => {
+        return Ok(stored);
+      },
+      Err(sqlx::Error::Database(err)) => {
+        return Err(sqlx::Error::Database(err));
+      },
+      Err(err) => err
+    };
+
+    attempts += 1;
+    if *closing && attempts >= SHUTDOWN_ATTEMPTS {
+      return Err(err);
+    }
+
+    log::warn!("The database can't be reached to store {} sensor readings, trying again in {} ms. Error: {}", readings.len(), backoff.as_millis(), err);
+    tokio::select! {
+      _ = tokio::time::sleep(backoff) => (),
+      _ = shutdown.notified(), if !*closing => {
+        *closing = true;
+      }
+    }
+    backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+  }
+}
+
+/// Store a batch of readings, trying again once if the database refuses it. If it's refused again,
+/// the readings are stored one by one so only the ones the database won't take are lost. While the
+/// database can't be reached the batch is kept and tried again, only splitting it on errors of the
+/// database itself. Returns how many readings have been stored and how many have failed.
+async fn store_batch(pool: &Pool<Postgres>, batch: &[PendingReading], shutdown: &Notify, closing: &mut bool) -> (u64, u64) {
+  for attempt in 0..2 {
+    match store_readings_with_retry(pool, batch, shutdown, closing).await {
+      Ok(stored) => {
+        return (stored, 0);
+      },
+      Err(sqlx::Error::Database(err)) => {
+        if attempt == 0 {
+          tokio::time::sleep(ingest_config().flush_interval).await;
+        }
+        else {
+          log::warn!("A batch of {} sensor readings has been refused twice, storing them one by one. Error: {}", batch.len(), err);
+        }
+      },
+      Err(err) => {
+        log::error!("A batch of {} sensor readings couldn't be stored before shutting down. Error: {}", batch.len(), err);
+        return (0, batch.len() as u64);
+      }
+    }
+  }
+
+  let mut stored: u64 = 0;
+  let mut failed: u64 = 0;
+  for pending_reading in batch {
+    match store_readings_with_retry(pool, std::slice::from_ref(pending_reading), shutdown, closing).await {
+      Ok(stored_reading) => {
+        stored += stored_reading;
+      },
+      Err(err) => {
+        failed += 1;
+        log::error!("There's an error when trying to store a sensor reading of device {}. Error: {}", pending_reading.device_id, err);
+      }
+    }
+  }
+
+  (stored, failed)
+}
+
+/// Stop the queue from taking readings, the ones it holds can still be taken off it.
+fn close_queue(receiver: &mut mpsc::Receiver<PendingReading>) {
+  log::info!("The ingestion queue is closing, the sensor readings left are being stored");
+  receiver.close();
+}
+
+/// Take readings off the queue and store them once a batch is full or its first reading has
+/// waited long enough. Once it's told to shut down, the queue is closed and what's left in it is
+/// stored before the worker stops.
+async fn run_ingest_worker(pool: Pool<Postgres>, mut receiver: mpsc::Receiver<PendingReading>, counters: Arc<IngestCounters>, shutdown: Arc<Notify>) {
+  let config: &IngestConfig = ingest_config();
+  let mut batch: Vec<PendingReading> = Vec::with_capacity(config.batch_size);
+  let mut dropped_reported: u64 = 0;
+  let mut closing: bool = false;
+
+  loop {
+    // Wait for the first reading of a batch. A closed queue still gives the readings it holds,
+    // then none once it's empty
+    let pending_reading: Option<PendingReading> = tokio::select! {
+      pending_reading = receiver.recv() => pending_reading,
+      _ = shutdown.notified(), if !closing => {
+        close_queue(&mut receiver);
+        closing = true;
+        receiver.recv().await
+      }
+    };
+    let Some(pending_reading) = pending_reading else {
+      break;
+    };
+    batch.push(pending_reading);
+    let deadline: Instant = Instant::now() + config.flush_interval;
+
+    // Fill the batch with what's already queued, then with what comes in before the deadline.
+    // When the queue is closing, there's nothing to wait for
+    while batch.len() < config.batch_size {
+      let pending_reading: Option<PendingReading> = match receiver.try_recv() {
+        Ok(pending_reading) => Some(pending_reading),
+        Err(_) if closing => None,
+        Err(_) => tokio::select! {
+          pending_reading = tokio::time::timeout_at(deadline, receiver.recv()) => pending_reading.ok().flatten(),
+          _ = shutdown.notified() => {
+            close_queue(&mut receiver);
+            closing = true;
+            None
+          }
+        }
+      };
+
+      match pending_reading {
+        Some(pending_reading) => batch.push(pending_reading),
+        None => break
+      }
+    }
+
+    let started_at: Instant = Instant::now();
+    let was_closing: bool = closing;
+    let (stored, failed) = store_batch(&pool, &batch, &shutdown, &mut closing).await;
+    // The worker may have been told to shut down while the database couldn't be reached
+    if closing && !was_closing {
+      close_queue(&mut receiver);
+    }
+    counters.stored.fetch_add(stored, Ordering::Relaxed);
+    counters.failed.fetch_add(failed, Ordering::Relaxed);
+    counters.batches.fetch_add(1, Ordering::Relaxed);
+    counters.last_batch_size.store(batch.len() as u64, Ordering::Relaxed);
+    counters.last_flush_micros.store(started_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+    batch.clear();
+
+    // Say so when readings are being dropped, once per flush at most
+    let dropped: u64 = counters.dropped.load(Ordering::Relaxed);
+    if dropped > dropped_reported {
+      log::warn!("{} sensor readings have been dropped because the ingestion queue is full", dropped - dropped_reported);
+      dropped_reported = dropped;
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use sqlx::postgres::PgPoolOptions;
+  use crate::{auth::now_utc, telemetry::ReadingValue};
+  use super::*;
+
+  #[tokio::test]
+  async fn shutdown_returns_while_the_database_cant_be_reached() {
+    // Nothing listens on this port, so every INSERT fails without reaching a database
+    let pool: Pool<Postgres> = PgPoolOptions::new()
+      .acquire_timeout(Duration::from_millis(100))
+      .connect_lazy("postgres://wms@127.0.0.1:1/wms")
+      .unwrap();
+    let ingest: ReadingIngest = ReadingIngest::start(pool);
+
+    let reading: Reading = Reading::new("temp", ReadingValue::Numeric(21.5), None).unwrap();
+    assert!(ingest.ingest("dev1", reading, now_utc()));
+
+    // Let the worker start retrying the batch before it's told to shut down
+    tokio::time::sleep(ingest_config().flush_interval * 3).await;
+    tokio::time::timeout(Duration::from_secs(10), ingest.shutdown()).await.expect("shutdown should return");
+
+    let metrics: IngestMetrics = ingest.metrics();
+    assert_eq!(metrics.stored, 0);
+    assert_eq!(metrics.failed, 1);
+  }
+}
@@ -0,0 +1,254 @@
+pub mod export;
+pub mod ingest;
+pub mod retention;
+pub mod rollups;
+
+use std::{env, sync::OnceLock};
+use rocket::time::{format_description::well_known::Iso8601, OffsetDateTime, PrimitiveDateTime};
+use serde::{Deserialize, Serialize};
+
+
+/// The longest sensor key a device can send.
+const MAX_SENSOR_LENGTH: usize = 64;
+/// The longest value a device can send, anything longer isn't a reading.
+const MAX_VALUE_LENGTH: usize = 256;
+/// The keys of the legacy messages the server sends, which a reading can't use or users would
+/// mistake it for one of them.
+const RESERVED_SENSORS: [&str; 7] = ["auth", "claim", "claim_code", "claim_error", "claimed", "error", "status"];
+/// Timestamps above this are in milliseconds, it's year 5138 in seconds.
+const MILLISECONDS_THRESHOLD: i64 = 100_000_000_000;
+/// The times a device can say it took a reading at, from 2000 up to 2100 in unix seconds. Anything
+/// else after an @ is taken as part of a text value, e.g. `note=call me @123`.
+const DEVICE_TIME_RANGE: std::ops::Range<i64> = 946_684_800..4_102_444_800;
+/// The bucket widths in seconds ranges are downsampled to, from the finest.
+const BUCKET_STEPS: [i64; 13] = [1, 10, 30, 60, 300, 900, 1800, 3600, 3 * 3600, 6 * 3600, 12 * 3600, 86400, 7 * 86400];
+
+static READINGS_MAX_POINTS: OnceLock<i64> = OnceLock::new();
+
+/// The most points a readings query returns, read once from `READINGS_MAX_POINTS`.
+pub fn readings_max_points() -> i64 {
+  *READINGS_MAX_POINTS.get_or_init(|| {
+    match env::var("READINGS_MAX_POINTS") {
+      Ok(value) => value.parse::<i64>().expect("READINGS_MAX_POINTS must be a valid number").max(1),
+      Err(_) => 1000
+    }
+  })
+}
+
+
+/// A reading's value, a JSON number or string.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ReadingValue {
+  Numeric(f64),
+  Text(String)
+}
+
+/// A reading sent by a device as `<sensor>=<value>`, or `<sensor>=<value>@<unix timestamp>`
+/// when the device knows when it took it. The timestamp can be in seconds or milliseconds, and has
+/// to be between 2000 and 2100.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reading {
+  pub sensor: String,
+  pub value: ReadingValue,
+  pub device_time: Option<PrimitiveDateTime>
+}
+
+impl Reading {
+  /// A reading with a valid sensor key and value, none otherwise.
+  pub fn new(sensor: &str, value: ReadingValue, device_time: Option<PrimitiveDateTime>) -> Option<Self> {
+    let sensor: &str = sensor.trim();
+    if !is_valid_sensor(sensor) {
+      return None;
+    }
+
+    let value: ReadingValue = match value {
+      ReadingValue::Numeric(number) if number.is_finite() => ReadingValue::Numeric(number),
+      ReadingValue::Numeric(_) => {
+        return None;
+      },
+      ReadingValue::Text(text) => {
+        let text: &str = text.trim();
+        if text.is_empty() || text.len() > MAX_VALUE_LENGTH || text.chars().any(char::is_control) {
+          return None;
+        }
+        ReadingValue::Text(text.to_string())
+      }
+    };
+
+    Some(Self { sensor: sensor.to_string(), value, device_time })
+  }
+}
+
+fn is_valid_sensor(sensor: &str) -> bool {
+  !sensor.is_empty()
+    && sensor.len() <= MAX_SENSOR_LENGTH
+    && sensor.chars().all(|char| char.is_ascii_alphanumeric() || char == '_' || char == '-' || char == '.')
+    && !RESERVED_SENSORS.contains(&sensor)
+}
+
+fn parse_device_time(timestamp: &str) -> Option<PrimitiveDateTime> {
+  let device_time: PrimitiveDateTime = from_unix_timestamp(timestamp.trim().parse::<i64>().ok()?)?;
+  DEVICE_TIME_RANGE.contains(&device_time.assume_utc().unix_timestamp()).then_some(device_time)
+}
+
+/// Turn a unix timestamp, in seconds or milliseconds, into a time.
+pub fn from_unix_timestamp(timestamp: i64) -> Option<PrimitiveDateTime> {
+  let timestamp_nanos: i128 = if timestamp.abs() >= MILLISECONDS_THRESHOLD {
+    timestamp as i128 * 1_000_000
+  } else {
+    timestamp as i128 * 1_000_000_000
+  };
+
+  let device_time: OffsetDateTime = OffsetDateTime::from_unix_timestamp_nanos(timestamp_nanos).ok()?;
+  Some(PrimitiveDateTime::new(device_time.date(), device_time.time()))
+}
+
+/// Parse a message from a device into a reading, none if it isn't one.
+pub fn parse_reading(text: &str) -> Option<Reading> {
+  let (sensor, value) = text.split_once('=')?;
+
+  // Only take the suffix as a timestamp if it is one, text values can have an @ in them
+  let (value, device_time) = match value.rsplit_once('@') {
+    Some((timed_value, timestamp)) => match parse_device_time(timestamp) {
+      Some(device_time) => (timed_value, Some(device_time)),
+      None => (value, None)
+    },
+    None => (value, None)
+  };
+
+  let value: &str = value.trim();
+  let value: ReadingValue = match value.parse::<f64>() {
+    Ok(number) if number.is_finite() => ReadingValue::Numeric(number),
+    _ => ReadingValue::Text(value.to_string())
+  };
+
+  Reading::new(sensor, value, device_time)
+}
+
+/// Parse a time given in a query, in ISO 8601 like the rest of the API, e.g. `2026-10-18T07:00:00`.
+pub fn parse_query_time(time: &str) -> Option<PrimitiveDateTime> {
+  PrimitiveDateTime::parse(time.trim(), &Iso8601::DEFAULT).ok()
+}
+
+/// Parse a bucket width like `30s`, `5m`, `1h` or `1d` into seconds.
+pub fn parse_bucket(bucket: &str) -> Option<i64> {
+  let bucket: &str = bucket.trim();
+  let unit_index: usize = bucket.find(|char: char| !char.is_ascii_digit())?;
+  let (amount, unit) = bucket.split_at(unit_index);
+  let amount: i64 = amount.parse::<i64>().ok().filter(|amount| *amount > 0)?;
+
+  let unit_secs: i64 = match unit {
+    "s" => 1,
+    "m" => 60,
+    "h" => 3600,
+    "d" => 86400,
+    _ => {
+      return None;
+    }
+  };
+
+  amount.checked_mul(unit_secs)
+}
+
+/// The narrowest bucket, at least `min_bucket_secs` wide, that splits a range into no more than `max_points` buckets.
+pub fn fit_bucket(range_secs: i64, min_bucket_secs: i64, max_points: i64) -> i64 {
+  let needed_secs: i64 = ((range_secs + max_points - 1) / max_points).max(1);
+  if min_bucket_secs >= needed_secs {
+    return min_bucket_secs;
+  }
+
+  BUCKET_STEPS
+    .into_iter()
+    .find(|step| *step >= needed_secs)
+    .unwrap_or(needed_secs)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_reading_numbers_and_text() {
+    let reading: Reading = parse_reading("temp=21.5").unwrap();
+    assert_eq!(reading.sensor, "temp");
+    assert_eq!(reading.value, ReadingValue::Numeric(21.5));
+    assert_eq!(reading.device_time, None);
+
+    let reading: Reading = parse_reading(" door = open ").unwrap();
+    assert_eq!(reading.sensor, "door");
+    assert_eq!(reading.value, ReadingValue::Text(String::from("open")));
+
+    // Only the first = splits the key from the value
+    assert_eq!(parse_reading("eq=a=b").unwrap().value, ReadingValue::Text(String::from("a=b")));
+    assert_eq!(parse_reading("temp=NaN").unwrap().value, ReadingValue::Text(String::from("NaN")));
+  }
+
+  #[test]
+  fn parse_reading_device_time() {
+    let reading: Reading = parse_reading("temp=21.5@1760770800").unwrap();
+    assert_eq!(reading.value, ReadingValue::Numeric(21.5));
+    assert_eq!(reading.device_time, from_unix_timestamp(1_760_770_800));
+
+    let reading: Reading = parse_reading("temp=21.5@1760770800123").unwrap();
+    assert_eq!(reading.device_time, from_unix_timestamp(1_760_770_800_123));
+
+    // A suffix that isn't a plausible time is part of the value
+    let reading: Reading = parse_reading("note=call me @123").unwrap();
+    assert_eq!(reading.value, ReadingValue::Text(String::from("call me @123")));
+    assert_eq!(reading.device_time, None);
+
+    let reading: Reading = parse_reading("email=a@example.com").unwrap();
+    assert_eq!(reading.value, ReadingValue::Text(String::from("a@example.com")));
+    assert_eq!(reading.device_time, None);
+
+    assert_eq!(parse_reading("temp=21.5@99999999999").unwrap().device_time, None);
+  }
+
+  #[test]
+  fn parse_reading_rejects_invalid_readings() {
+    assert_eq!(parse_reading("temp"), None);
+    assert_eq!(parse_reading("=21.5"), None);
+    assert_eq!(parse_reading("temp="), None);
+    assert_eq!(parse_reading("temp c=21.5"), None);
+    assert_eq!(parse_reading("note=a\0b"), None);
+    assert_eq!(parse_reading(&format!("{}=1", "a".repeat(MAX_SENSOR_LENGTH + 1))), None);
+    assert_eq!(parse_reading(&format!("note={}", "a".repeat(MAX_VALUE_LENGTH + 1))), None);
+
+    // Keys of the messages the server sends
+    assert_eq!(parse_reading("status=0"), None);
+    assert_eq!(parse_reading("error=x"), None);
+    assert_eq!(parse_reading("claimed=1"), None);
+  }
+
+  #[test]
+  fn parse_bucket_units() {
+    assert_eq!(parse_bucket("30s"), Some(30));
+    assert_eq!(parse_bucket("5m"), Some(300));
+    assert_eq!(parse_bucket(" 1h "), Some(3600));
+    assert_eq!(parse_bucket("2d"), Some(2 * 86400));
+
+    assert_eq!(parse_bucket("0s"), None);
+    assert_eq!(parse_bucket("h"), None);
+    assert_eq!(parse_bucket("10"), None);
+    assert_eq!(parse_bucket("5w"), None);
+    assert_eq!(parse_bucket("-5m"), None);
+    assert_eq!(parse_bucket(&format!("{}d", i64::MAX)), None);
+  }
+
+  #[test]
+  fn fit_bucket_steps() {
+    // An hour in at most 1000 points needs 4 seconds, rounded up to the next step
+    assert_eq!(fit_bucket(3600, 1, 1000), 10);
+    assert_eq!(fit_bucket(1000, 1, 1000), 1);
+    assert_eq!(fit_bucket(0, 1, 1000), 1);
+
+    // A wide enough bucket asked for is kept as it is
+    assert_eq!(fit_bucket(3600, 45, 1000), 45);
+    assert_eq!(fit_bucket(86400, 60, 1000), 300);
+
+    // Beyond the widest step, the exact width is used
+    assert_eq!(fit_bucket(365 * 86400, 1, 10), 365 * 8640);
+  }
+}
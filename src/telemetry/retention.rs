@@ -0,0 +1,165 @@
+use std::{env, sync::OnceLock};
+use rocket::time::{Duration, PrimitiveDateTime};
+use sqlx::{PgExecutor, Pool, Postgres};
+use crate::{auth::now_utc, telemetry::rollups::{roll_up_daily, roll_up_hourly, rolled_up_to, DAILY_ROLLUP, HOURLY_ROLLUP}};
+
+
+/// The longest retention that can be set, about ten years.
+const MAX_RETENTION_DAYS: i32 = 3650;
+/// How many expired rows are deleted at once, so a large cleanup doesn't hold long locks.
+const DELETE_BATCH_SIZE: i64 = 10_000;
+
+/// How long readings are kept and how often they're cleaned up.
+pub struct RetentionConfig {
+  /// How long raw readings are kept when neither the device nor its owners have a setting
+  pub raw_retention_days: i32,
+  /// How long hourly rollups are kept, daily rollups are kept forever
+  pub hourly_retention_days: i32,
+  /// How often rollups are computed and expired data is deleted
+  pub maintenance_interval: std::time::Duration
+}
+
+static RETENTION_CONFIG: OnceLock<RetentionConfig> = OnceLock::new();
+
+fn read_number(name: &str, default: i64) -> i64 {
+  match env::var(name) {
+    Ok(value) => value.parse::<i64>().unwrap_or_else(|_| panic!("{} must be a valid number", name)),
+    Err(_) => default
+  }
+}
+
+/// Retention settings, read once from `READINGS_RETENTION_DAYS`, `READINGS_HOURLY_RETENTION_DAYS`
+/// and `TELEMETRY_MAINTENANCE_INTERVAL_SECS`.
+pub fn retention_config() -> &'static RetentionConfig {
+  RETENTION_CONFIG.get_or_init(|| {
+    RetentionConfig {
+      raw_retention_days: read_number("READINGS_RETENTION_DAYS", 90).clamp(1, MAX_RETENTION_DAYS as i64) as i32,
+      hourly_retention_days: read_number("READINGS_HOURLY_RETENTION_DAYS", 365).clamp(1, MAX_RETENTION_DAYS as i64) as i32,
+      maintenance_interval: std::time::Duration::from_secs(read_number("TELEMETRY_MAINTENANCE_INTERVAL_SECS", 600).max(1) as u64)
+    }
+  })
+}
+
+/// Whether a retention given by a user can be used. 0 is also accepted by the routes to go back to the default.
+pub fn is_valid_retention_days(days: i32) -> bool {
+  (0..=MAX_RETENTION_DAYS).contains(&days)
+}
+
+/// Raw readings of the device received before this have expired. The device's own setting comes
+/// first, then the longest setting of its owners, then the server default.
+pub async fn raw_retention_cutoff<'e, E: PgExecutor<'e>>(executor: E, device_id: &str) -> Result<PrimitiveDateTime, sqlx::Error> {
+  let days: Option<i32> = sqlx::query_scalar!(
+    "SELECT COALESCE(
+      devices.readings_retention_days,
+      (SELECT MAX(users.readings_retention_days) FROM connections JOIN users ON users.id = connections.user_id WHERE connections.device_id = devices.id AND connections.role = 'owner'),
+      $2
+    ) FROM devices WHERE id = $1",
+    device_id,
+    retention_config().raw_retention_days
+  )
+  .fetch_optional(executor)
+  .await?
+  .flatten();
+
+  Ok(now_utc() - Duration::days(days.unwrap_or(retention_config().raw_retention_days) as i64))
+}
+
+/// Hourly rollups before this have expired.
+pub fn hourly_retention_cutoff() -> PrimitiveDateTime {
+  now_utc() - Duration::days(retention_config().hourly_retention_days as i64)
+}
+
+
+/// Delete the raw readings that have expired, but only once they've been rolled up, which they
+/// haven't if their hour is waiting to be rolled up again.
+async fn delete_expired_readings(pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+  let Some(hourly_rolled_up_to) = rolled_up_to(pool, HOURLY_ROLLUP).await? else {
+    return Ok(0);
+  };
+
+  let mut deleted: u64 = 0;
+  loop {
+    // The retention of every device is worked out once, instead of for every reading
+    let result = sqlx::query!(
+      "WITH owner_retention AS (
+        SELECT connections.device_id, MAX(users.readings_retention_days) AS days FROM connections
+        JOIN users ON users.id = connections.user_id
+        WHERE connections.role = 'owner'
+        GROUP BY connections.device_id
+      ), device_cutoffs AS (
+        SELECT devices.id AS device_id, $2::TIMESTAMP - COALESCE(devices.readings_retention_days, owner_retention.days, $3) * INTERVAL '1 day' AS cutoff
+        FROM devices LEFT JOIN owner_retention ON owner_retention.device_id = devices.id
+      )
+      DELETE FROM device_readings WHERE id IN (
+        SELECT device_readings.id FROM device_readings
+        JOIN device_cutoffs ON device_cutoffs.device_id = device_readings.device_id
+        WHERE device_readings.received_at < $1
+        AND device_readings.received_at < (SELECT MAX(cutoff) FROM device_cutoffs)
+        AND device_readings.received_at < device_cutoffs.cutoff
+        AND date_bin(INTERVAL '1 hour', device_readings.received_at, TIMESTAMP 'epoch') NOT IN (
+          SELECT bucket FROM telemetry_rollup_dirty_buckets WHERE name = $5
+        )
+        LIMIT $4
+      )",
+      hourly_rolled_up_to,
+      now_utc(),
+      retention_config().raw_retention_days,
+      DELETE_BATCH_SIZE,
+      HOURLY_ROLLUP
+    )
+    .execute(pool)
+    .await?;
+
+    deleted += result.rows_affected();
+    if (result.rows_affected() as i64) < DELETE_BATCH_SIZE {
+      return Ok(deleted);
+    }
+  }
+}
+
+/// Delete the hourly rollups that have expired, but only once they've been rolled up into days.
+async fn delete_expired_hourly_rollups(pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+  let Some(daily_rolled_up_to) = rolled_up_to(pool, DAILY_ROLLUP).await? else {
+    return Ok(0);
+  };
+
+  let result = sqlx::query!(
+    "DELETE FROM device_reading_rollups_hourly WHERE bucket < $1 AND bucket < $2
+    AND date_bin(INTERVAL '1 day', bucket, TIMESTAMP 'epoch') NOT IN (
+      SELECT bucket FROM telemetry_rollup_dirty_buckets WHERE name = $3
+    )",
+    daily_rolled_up_to,
+    hourly_retention_cutoff(),
+    DAILY_ROLLUP
+  )
+  .execute(pool)
+  .await?;
+
+  Ok(result.rows_affected())
+}
+
+async fn run_maintenance(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+  roll_up_hourly(pool).await?;
+  roll_up_daily(pool).await?;
+
+  let deleted_readings: u64 = delete_expired_readings(pool).await?;
+  let deleted_rollups: u64 = delete_expired_hourly_rollups(pool).await?;
+  if deleted_readings > 0 || deleted_rollups > 0 {
+    log::info!("{} expired readings and {} expired hourly rollups have been deleted", deleted_readings, deleted_rollups);
+  }
+
+  Ok(())
+}
+
+/// Keep the rollups up to date and delete expired data, every `TELEMETRY_MAINTENANCE_INTERVAL_SECS`.
+pub async fn run_telemetry_maintenance(pool: Pool<Postgres>) {
+  let config: &RetentionConfig = retention_config();
+
+  loop {
+    if let Err(err) = run_maintenance(&pool).await {
+      log::error!("There's an error when trying to maintain the device readings. Error: {}", err);
+    }
+
+    tokio::time::sleep(config.maintenance_interval).await;
+  }
+}
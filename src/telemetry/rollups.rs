@@ -0,0 +1,208 @@
+use rocket::time::{Duration, PrimitiveDateTime, Time};
+use sqlx::{PgExecutor, Pool, Postgres};
+use crate::{auth::now_utc, telemetry::ingest::ingest_config};
+
+
+/// The name the progress of the hourly rollups is kept under.
+pub const HOURLY_ROLLUP: &str = "hourly";
+/// The name the progress of the daily rollups is kept under.
+pub const DAILY_ROLLUP: &str = "daily";
+/// How much is rolled up in one go, so catching up on a large backlog is done in small steps.
+const HOURLY_ROLLUP_STEP_HOURS: i64 = 24;
+const DAILY_ROLLUP_STEP_DAYS: i64 = 31;
+/// How long after an hour is over it's first rolled up, on top of the ingestion flush interval, so
+/// it isn't rolled up again and again while it's still filling. Readings stored later than that are
+/// picked up through the dirty buckets.
+const HOURLY_ROLLUP_DELAY_SECS: i64 = 300;
+
+
+fn start_of_hour(time: PrimitiveDateTime) -> PrimitiveDateTime {
+  time.replace_time(Time::from_hms(time.hour(), 0, 0).unwrap_or(Time::MIDNIGHT))
+}
+
+fn start_of_day(time: PrimitiveDateTime) -> PrimitiveDateTime {
+  time.replace_time(Time::MIDNIGHT)
+}
+
+/// Everything before this has been rolled up into `name`, none if nothing has been yet.
+pub async fn rolled_up_to<'e, E: PgExecutor<'e>>(executor: E, name: &str) -> Result<Option<PrimitiveDateTime>, sqlx::Error> {
+  sqlx::query_scalar!(
+    "SELECT rolled_up_to FROM telemetry_rollup_state WHERE name = $1",
+    name
+  )
+  .fetch_optional(executor)
+  .await
+}
+
+async fn set_rolled_up_to<'e, E: PgExecutor<'e>>(executor: E, name: &str, time: PrimitiveDateTime) -> Result<(), sqlx::Error> {
+  sqlx::query!(
+    "INSERT INTO telemetry_rollup_state(name, rolled_up_to) VALUES ($1, $2) ON CONFLICT (name) DO UPDATE SET rolled_up_to = EXCLUDED.rolled_up_to",
+    name,
+    time
+  )
+  .execute(executor)
+  .await?;
+
+  Ok(())
+}
+
+/// Take a batch of the buckets of `name` before `until` that have to be rolled up again, oldest first.
+/// They're gone once the transaction is committed, and skipped by other runs until then.
+async fn take_dirty_buckets<'e, E: PgExecutor<'e>>(executor: E, name: &str, until: PrimitiveDateTime, limit: i64) -> Result<Vec<PrimitiveDateTime>, sqlx::Error> {
+  sqlx::query_scalar!(
+    "DELETE FROM telemetry_rollup_dirty_buckets WHERE (name, bucket) IN (
+      SELECT name, bucket FROM telemetry_rollup_dirty_buckets
+      WHERE name = $1 AND bucket < $2
+      ORDER BY bucket
+      LIMIT $3
+      FOR UPDATE SKIP LOCKED
+    )
+    RETURNING bucket",
+    name,
+    until,
+    limit
+  )
+  .fetch_all(executor)
+  .await
+}
+
+/// Summarize the numeric readings of every hour that has been written to since the last run. Readings
+/// are bucketed by when they've been received, and an hour is first rolled up once the readings
+/// received in it have had time to go through the ingestion queue. Readings stored after that mark
+/// their hour again, so it's rolled up once more on the next run.
+pub async fn roll_up_hourly(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+  let until: PrimitiveDateTime = start_of_hour(now_utc() - ingest_config().flush_interval - Duration::seconds(HOURLY_ROLLUP_DELAY_SECS));
+
+  loop {
+    let mut transaction = pool.begin().await?;
+
+    let hours: Vec<PrimitiveDateTime> = take_dirty_buckets(&mut *transaction, HOURLY_ROLLUP, until, HOURLY_ROLLUP_STEP_HOURS).await?;
+    if hours.is_empty() {
+      break;
+    }
+
+    // An hour whose raw readings have partly expired is left as it was rather than summarized from what's left
+    sqlx::query!(
+      r#"INSERT INTO device_reading_rollups_hourly(device_id, sensor, bucket, min, max, avg, count, last)
+      SELECT device_id, sensor, hours.hour,
+        MIN(numeric_value), MAX(numeric_value), AVG(numeric_value), COUNT(*),
+        (ARRAY_AGG(numeric_value ORDER BY received_at DESC, id DESC))[1]
+      FROM UNNEST($1::TIMESTAMP[]) AS hours(hour)
+      JOIN device_readings ON received_at >= hours.hour AND received_at < hours.hour + INTERVAL '1 hour'
+      WHERE numeric_value IS NOT NULL
+      GROUP BY device_id, sensor, hours.hour
+      ON CONFLICT (device_id, sensor, bucket) DO UPDATE SET
+        min = EXCLUDED.min, max = EXCLUDED.max, avg = EXCLUDED.avg, count = EXCLUDED.count, last = EXCLUDED.last
+      WHERE device_reading_rollups_hourly.count <= EXCLUDED.count"#,
+      &hours
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query!(
+      r#"INSERT INTO telemetry_rollup_dirty_buckets(name, bucket)
+      SELECT DISTINCT $2, date_bin(INTERVAL '1 day', hour, TIMESTAMP 'epoch')
+      FROM UNNEST($1::TIMESTAMP[]) AS hours(hour)
+      ON CONFLICT (name, bucket) DO UPDATE SET bucket = EXCLUDED.bucket"#,
+      &hours,
+      DAILY_ROLLUP
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+  }
+
+  if rolled_up_to(pool, HOURLY_ROLLUP).await?.is_none_or(|rolled_up_to| rolled_up_to < until) {
+    set_rolled_up_to(pool, HOURLY_ROLLUP, until).await?;
+  }
+
+  Ok(())
+}
+
+/// Summarize the hourly rollups of every day that has been fully rolled up and has changed since the last run.
+pub async fn roll_up_daily(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+  let Some(hourly_rolled_up_to) = rolled_up_to(pool, HOURLY_ROLLUP).await? else {
+    return Ok(());
+  };
+  let until: PrimitiveDateTime = start_of_day(hourly_rolled_up_to);
+
+  loop {
+    let mut transaction = pool.begin().await?;
+
+    let days: Vec<PrimitiveDateTime> = take_dirty_buckets(&mut *transaction, DAILY_ROLLUP, until, DAILY_ROLLUP_STEP_DAYS).await?;
+    if days.is_empty() {
+      break;
+    }
+
+    // Same as the hours, a day whose hourly rollups have partly expired is left as it was
+    sqlx::query!(
+      r#"INSERT INTO device_reading_rollups_daily(device_id, sensor, bucket, min, max, avg, count, last)
+      SELECT device_id, sensor, days.day,
+        MIN(min), MAX(max), SUM(avg * count) / SUM(count), SUM(count)::BIGINT,
+        (ARRAY_AGG(last ORDER BY bucket DESC))[1]
+      FROM UNNEST($1::TIMESTAMP[]) AS days(day)
+      JOIN device_reading_rollups_hourly ON bucket >= days.day AND bucket < days.day + INTERVAL '1 day'
+      GROUP BY device_id, sensor, days.day
+      ON CONFLICT (device_id, sensor, bucket) DO UPDATE SET
+        min = EXCLUDED.min, max = EXCLUDED.max, avg = EXCLUDED.avg, count = EXCLUDED.count, last = EXCLUDED.last
+      WHERE device_reading_rollups_daily.count <= EXCLUDED.count"#,
+      &days
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+  }
+
+  if rolled_up_to(pool, DAILY_ROLLUP).await?.is_none_or(|rolled_up_to| rolled_up_to < until) {
+    set_rolled_up_to(pool, DAILY_ROLLUP, until).await?;
+  }
+
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  async fn store_reading(pool: &Pool<Postgres>, value: f64, received_at: PrimitiveDateTime) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+      "INSERT INTO device_readings(device_id, sensor, numeric_value, received_at) VALUES ('device', 'temperature', $1, $2)",
+      value,
+      received_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+  }
+
+  #[sqlx::test]
+  async fn late_reading_is_rolled_up_again(pool: Pool<Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::query!("INSERT INTO devices(id, access_token, device_name) VALUES ('device', 'token', 'Device')")
+      .execute(&pool)
+      .await?;
+
+    let hour: PrimitiveDateTime = start_of_hour(now_utc()) - Duration::hours(2);
+    store_reading(&pool, 1.0, hour + Duration::minutes(10)).await?;
+    roll_up_hourly(&pool).await?;
+
+    // Received within the hour, but only stored once it has been rolled up
+    store_reading(&pool, 5.0, hour + Duration::minutes(5)).await?;
+    roll_up_hourly(&pool).await?;
+
+    let rollup = sqlx::query!(
+      "SELECT min, max, count, last FROM device_reading_rollups_hourly WHERE device_id = 'device' AND sensor = 'temperature' AND bucket = $1",
+      hour
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    assert_eq!((rollup.min, rollup.max, rollup.count, rollup.last), (1.0, 5.0, 2, 1.0));
+    assert!(rolled_up_to(&pool, HOURLY_ROLLUP).await?.is_some_and(|rolled_up_to| rolled_up_to > hour));
+
+    Ok(())
+  }
+}
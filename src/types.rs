@@ -1,35 +1,160 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::{atomic::{AtomicBool, Ordering}, Arc}};
 use futures_util::{stream::SplitSink, SinkExt};
-use tokio::{net::TcpStream, sync::RwLock};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio::{net::TcpStream, sync::{Notify, RwLock, RwLockWriteGuard}};
+use tokio_tungstenite::{tungstenite::{self, Message}, WebSocketStream};
+use crate::websocket::protocol::{Envelope, WireProtocol};
 
 
-pub type WebSocketSender = Arc<RwLock<SplitSink<WebSocketStream<TcpStream>, Message>>>;
+pub type WebSocketSink = SplitSink<WebSocketStream<TcpStream>, Message>;
 
+/// The sending half of a Web Socket connection, along with the protocol the client speaks.
+#[derive(Debug)]
+pub struct WebSocketConnection {
+  sink: RwLock<WebSocketSink>,
+  protocol: std::sync::RwLock<WireProtocol>,
+  /// Whether the protocol has been asked for in the handshake, rather than being the default
+  negotiated: bool,
+  /// Set once the credentials the connection was opened with are no longer valid
+  revoked: AtomicBool,
+  revocation: Notify
+}
+
+pub type WebSocketSender = Arc<WebSocketConnection>;
+
+impl WebSocketConnection {
+  /// A connection speaking the negotiated protocol, or the default one if none has been.
+  pub fn new(sink: WebSocketSink, negotiated_protocol: Option<WireProtocol>) -> WebSocketSender {
+    Arc::new(Self {
+      sink: RwLock::new(sink),
+      protocol: std::sync::RwLock::new(negotiated_protocol.unwrap_or_default()),
+      negotiated: negotiated_protocol.is_some(),
+      revoked: AtomicBool::new(false),
+      revocation: Notify::new()
+    })
+  }
+
+  /// Lock the connection to send raw frames through it.
+  pub async fn write(&self) -> RwLockWriteGuard<'_, WebSocketSink> {
+    self.sink.write().await
+  }
+
+  pub fn protocol(&self) -> WireProtocol {
+    self.protocol.read().map(|protocol| *protocol).unwrap_or_default()
+  }
+
+  pub fn is_negotiated(&self) -> bool {
+    self.negotiated
+  }
+
+  /// Stop the connection from being used any further. Its handler stops reading frames as soon
+  /// as it sees the flag, whether or not the client answers the close frame.
+  pub fn revoke(&self) {
+    self.revoked.store(true, Ordering::SeqCst);
+    self.revocation.notify_one();
+  }
+
+  pub fn is_revoked(&self) -> bool {
+    self.revoked.load(Ordering::SeqCst)
+  }
 
+  /// Wait until the connection has been revoked.
+  pub async fn revoked(&self) {
+    if !self.is_revoked() {
+      self.revocation.notified().await;
+    }
+  }
+
+  pub fn set_protocol(&self, protocol: WireProtocol) {
+    if let Ok(mut current_protocol) = self.protocol.write() {
+      *current_protocol = protocol;
+    }
+  }
+
+  /// Send a message the way the client wants it. Messages it has no way to be written in are skipped.
+  pub async fn send_envelope(&self, envelope: &Envelope) -> Result<(), tungstenite::Error> {
+    let Some(message) = envelope.encode(self.protocol()) else {
+      return Ok(());
+    };
+
+    self.write().await.send(message).await
+  }
+}
+
+
+/// A user's Web Socket connection, tracked so it can be closed when their session is revoked.
 #[derive(Clone)]
+pub struct UserClient {
+  pub user_id: String,
+  pub session_id: String,
+  pub sender: WebSocketSender
+}
+
+
+#[derive(Clone, Default)]
 pub struct WebSocketManager {
   pub user_senders: Arc<RwLock<HashMap<String, HashMap<String, WebSocketSender>>>>,
-  pub device_senders: Arc<RwLock<HashMap<String, WebSocketSender>>>
+  pub device_senders: Arc<RwLock<HashMap<String, WebSocketSender>>>,
+  pub user_clients: Arc<RwLock<HashMap<String, UserClient>>>
 }
 
 
 impl WebSocketManager {
   pub fn new() -> Self {
-    Self {
-      user_senders: Arc::new(RwLock::new(HashMap::new())),
-      device_senders: Arc::new(RwLock::new(HashMap::new()))
+    Self::default()
+  }
+
+  pub async fn new_user_client(&self, addr: String, client: UserClient) {
+    let mut clients = self.user_clients.write().await;
+    clients.insert(addr, client);
+  }
+
+  pub async fn remove_user_client(&self, addr: &str) {
+    let mut clients = self.user_clients.write().await;
+    clients.remove(addr);
+  }
+
+  /// Close every user connection that matches the predicate. The connection handlers
+  /// clean up after themselves once the socket has been closed.
+  async fn close_user_clients_where<F: Fn(&UserClient) -> bool>(&self, predicate: F) {
+    let senders: Vec<WebSocketSender> = {
+      let clients = self.user_clients.read().await;
+      clients.values().filter(|client| predicate(client)).map(|client| client.sender.clone()).collect()
+    };
+
+    for sender in senders {
+      sender.revoke();
+      let mut sender_lock = sender.write().await;
+      if let Err(err) = sender_lock.close().await {
+        log::warn!("There's an error when trying to close a user web socket connection. Error: {}", err);
+      }
     }
   }
 
-  pub async fn new_device_connection(&self, device_id: String, ws_sender: WebSocketSender) -> Result<(), String> {
-    {
+  /// Close all live connections of a user, e.g. after their password has been reset.
+  pub async fn close_user_connections(&self, user_id: &str) {
+    self.close_user_clients_where(|client| client.user_id == user_id).await;
+  }
+
+  /// Close all live connections of a user except the ones made with `session_id`.
+  pub async fn close_other_session_connections(&self, user_id: &str, session_id: &str) {
+    self.close_user_clients_where(|client| client.user_id == user_id && client.session_id != session_id).await;
+  }
+
+  /// Close all live connections that were authenticated with a session.
+  pub async fn close_session_connections(&self, session_id: &str) {
+    self.close_user_clients_where(|client| client.session_id == session_id).await;
+  }
+
+  /// Register the live connection of a device, returning the one it replaces if the device was
+  /// already connected, e.g. over a connection that has died without being closed.
+  pub async fn new_device_connection(&self, device_id: String, ws_sender: WebSocketSender) -> Option<WebSocketSender> {
+    let previous_sender: Option<WebSocketSender> = {
       let mut senders = self.device_senders.write().await;
-      senders.insert(device_id.clone(), ws_sender);
-    }
+      senders.insert(device_id.clone(), ws_sender)
+    };
     log::info!("A new device connection has been added: {}", device_id);
 
-    Ok(())
+    previous_sender
   }
 
   pub async fn new_user_connection(&self, device_id: String, addr: String, ws_sender: WebSocketSender) -> Result<(), String> {
@@ -49,18 +174,15 @@ impl WebSocketManager {
     Ok(())
   }
 
-  pub async fn send_device_message(&self, id: &str, message: &str) -> Result<(), String> {
-    // Get the senders
-    let raw_senders = Arc::clone(&self.device_senders);
-    let mut write_mode_senders = raw_senders.write().await;
-    
-
+  /// Send a message to a device. The sender is copied out first, so a slow device doesn't hold up
+  /// the others or devices connecting and disconnecting.
+  pub async fn send_device_message(&self, id: &str, message: &Envelope) -> Result<(), String> {
     // Get the sender from ID
-    let sender: Option<&mut WebSocketSender> = write_mode_senders.get_mut(id);
+    let sender: Option<WebSocketSender> = self.device_senders.read().await.get(id).cloned();
 
 
     // Check if the sender for that ID is exists
-    let sender: &mut WebSocketSender = match sender {
+    let sender: WebSocketSender = match sender {
       Some(data) => data,
       None => {
         return Err(format!("There's no recorded web socket connection with ID: {}", id));
@@ -68,15 +190,14 @@ impl WebSocketManager {
     };
 
     // Send message
-    let mut sender_lock = sender.write().await;
-    let send_result: Result<(), tokio_tungstenite::tungstenite::Error> = sender_lock.send(Message::Text(message.into())).await;
+    let send_result: Result<(), tokio_tungstenite::tungstenite::Error> = sender.send_envelope(message).await;
 
     
     // Check if there's an error
     match send_result {
       Ok(_) => (),
       Err(err) => {
-        let err_message = format!("There's an error when trying to send data through Web Socket. Error: {}", err.to_string());
+        let err_message = format!("There's an error when trying to send data through Web Socket. Error: {}", err);
         log::error!("{}", err_message);
         return Err(err_message);
       }
@@ -85,43 +206,61 @@ impl WebSocketManager {
     Ok(())
   }
 
-  pub async fn send_user_message(&self, id: &str, message: &str) -> Result<Option<()>, String> {
-    // Get the senders
-    let raw_user_senders = Arc::clone(&self.user_senders);
-    let mut write_mode_user_senders = raw_user_senders.write().await;
-
+  /// Send a message to every user listening to a device, written the way each of them wants it.
+  /// The senders are copied out first so a slow connection doesn't hold up the others, and the ones
+  /// that couldn't be sent to are dropped from the device afterwards.
+  pub async fn send_user_message(&self, id: &str, message: &Envelope) -> Result<Option<()>, String> {
     // Get all of the user senders from ID
-    let raw_senders: Option<&mut HashMap<String, WebSocketSender>> = write_mode_user_senders.get_mut(id);
-
-
-    // Check if the values from that ID is exists
-    let senders_by_addr: &mut HashMap<String, WebSocketSender> = match raw_senders {
-      Some(data) => data,
-      None => {
-        log::warn!("There's no recorded web socket connection with ID: {}", id);
-        return Ok(None);
+    let senders: Vec<(String, WebSocketSender)> = {
+      let user_senders_lock = self.user_senders.read().await;
+      match user_senders_lock.get(id) {
+        Some(senders_by_addr) => senders_by_addr.iter().map(|(addr, sender)| (addr.clone(), sender.clone())).collect(),
+        None => {
+          log::warn!("There's no recorded web socket connection with ID: {}", id);
+          return Ok(None);
+        }
       }
     };
 
-    log::debug!("CURRENT SENDERS: {:?}", senders_by_addr);
+    // Each protocol's frame is only encoded once, however many users speak it
+    let mut encoded_messages: HashMap<WireProtocol, Option<Message>> = HashMap::new();
+    let mut dead_senders: Vec<(String, WebSocketSender)> = Vec::new();
+
     // Iterate for each senders
-    for sender in senders_by_addr.values() {
-      // Send message
-      let mut sender_lock = sender.write().await;
-      let send_result: Result<(), tokio_tungstenite::tungstenite::Error> = sender_lock.send(Message::Text(message.into())).await;
-
-      // Check if there's an error
-      match send_result {
-        Ok(_) => (),
-        Err(err) => {
-          let err_message = format!("There's an error when trying to send data through Web Socket. Error: {}", err.to_string());
-          log::error!("{}", err_message);
-          return Err(err_message);
+    for (addr, sender) in senders {
+      let encoded_message: Option<Message> = encoded_messages
+        .entry(sender.protocol())
+        .or_insert_with_key(|protocol| message.encode(*protocol))
+        .clone();
+
+      let Some(encoded_message) = encoded_message else {
+        continue;
+      };
+
+      // Send message, a failed one doesn't stop the others from getting it
+      let send_result: Result<(), tokio_tungstenite::tungstenite::Error> = sender.write().await.send(encoded_message).await;
+      if let Err(err) = send_result {
+        log::warn!("There's an error when trying to send data through Web Socket to {}. Error: {}", addr, err);
+        dead_senders.push((addr, sender));
+      }
+    }
+
+    // Drop the connections that couldn't be sent to, unless they've been replaced in the meantime
+    if !dead_senders.is_empty() {
+      let mut user_senders_lock = self.user_senders.write().await;
+      if let Some(senders_by_addr) = user_senders_lock.get_mut(id) {
+        for (addr, dead_sender) in &dead_senders {
+          if senders_by_addr.get(addr).is_some_and(|sender| Arc::ptr_eq(sender, dead_sender)) {
+            senders_by_addr.remove(addr);
+          }
+        }
+
+        if senders_by_addr.is_empty() {
+          user_senders_lock.remove(id);
         }
       }
     }
-    
-    
+
     Ok(Some(()))
   }
 
@@ -162,14 +301,102 @@ impl WebSocketManager {
     }
   }
 
-  pub async fn remove_device_connection(&self, room_id: &str) -> Result<(), String> {
-    // Lock the user senders write mode
+  /// Drop a user connection from every device room it's listening to.
+  pub async fn remove_user_address(&self, addr: &str) {
+    let mut user_senders_lock = self.user_senders.write().await;
+
+    for senders_by_addr in user_senders_lock.values_mut() {
+      senders_by_addr.remove(addr);
+    }
+    user_senders_lock.retain(|_, senders_by_addr| !senders_by_addr.is_empty());
+  }
+
+  /// Let the live connections of a user listen to a device they've just been connected to.
+  pub async fn subscribe_user_to_device(&self, user_id: &str, device_id: &str) {
+    let clients: Vec<(String, WebSocketSender)> = {
+      let clients = self.user_clients.read().await;
+      clients
+        .iter()
+        .filter(|(_, client)| client.user_id == user_id)
+        .map(|(addr, client)| (addr.clone(), client.sender.clone()))
+        .collect()
+    };
+
+    for (addr, sender) in clients {
+      if let Err(err) = self.new_user_connection(device_id.to_string(), addr, sender).await {
+        log::error!("There's an error when trying to subscribe a user connection to a device. Error: {}", err);
+      }
+    }
+  }
+
+  /// Stop the live connections of a user from listening to a device they've lost access to.
+  pub async fn unsubscribe_user_from_device(&self, user_id: &str, device_id: &str) {
+    let addrs: Vec<String> = {
+      let clients = self.user_clients.read().await;
+      clients
+        .iter()
+        .filter(|(_, client)| client.user_id == user_id)
+        .map(|(addr, _)| addr.clone())
+        .collect()
+    };
+
+    let mut user_senders_lock = self.user_senders.write().await;
+    if let Some(senders_by_addr) = user_senders_lock.get_mut(device_id) {
+      for addr in addrs {
+        senders_by_addr.remove(&addr);
+      }
+
+      if senders_by_addr.is_empty() {
+        user_senders_lock.remove(device_id);
+      }
+    }
+  }
+
+  /// Close the live connection of a device, e.g. after its token has been rotated. The connection
+  /// handler cleans up after itself once the socket has been closed.
+  pub async fn close_device_connection(&self, device_id: &str) {
+    let sender: Option<WebSocketSender> = self.device_senders.read().await.get(device_id).cloned();
+
+    if let Some(sender) = sender {
+      Self::close_device_sender(&sender).await;
+    }
+  }
+
+  /// Forget a device that has been deleted. Users stop listening to it and its live connection is
+  /// closed, without being marked offline since there's nothing left to mark.
+  pub async fn remove_device(&self, device_id: &str) {
+    self.user_senders.write().await.remove(device_id);
+
+    let sender: Option<WebSocketSender> = self.device_senders.write().await.remove(device_id);
+    if let Some(sender) = sender {
+      Self::close_device_sender(&sender).await;
+    }
+  }
+
+  async fn close_device_sender(sender: &WebSocketSender) {
+    sender.revoke();
+    let mut sender_lock = sender.write().await;
+    if let Err(err) = sender_lock.close().await {
+      log::warn!("There's an error when trying to close a device web socket connection. Error: {}", err);
+    }
+  }
+
+  /// Whether the device currently has a live connection.
+  pub async fn is_device_online(&self, device_id: &str) -> bool {
+    self.device_senders.read().await.contains_key(device_id)
+  }
+
+  /// Drop the live connection of a device, unless it has already been replaced by a newer one.
+  /// Returns whether it was still the device's connection.
+  pub async fn remove_device_connection(&self, room_id: &str, ws_sender: &WebSocketSender) -> bool {
     let mut device_senders_lock = self.device_senders.write().await;
-    let result = device_senders_lock.remove(room_id);
 
-    match result {
-      Some(_) => Ok(()),
-      None => Err(format!("There's no device data with room_id: {}", room_id))
+    match device_senders_lock.get(room_id) {
+      Some(current_sender) if Arc::ptr_eq(current_sender, ws_sender) => {
+        device_senders_lock.remove(room_id);
+        true
+      },
+      _ => false
     }
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,112 @@
+use std::{env, net::IpAddr, sync::OnceLock};
+use rocket::Request;
+
+
+static TRUSTED_PROXIES: OnceLock<Vec<IpAddr>> = OnceLock::new();
+
+/// Addresses of the reverse proxies whose `X-Forwarded-For` header can be trusted,
+/// read once from the comma separated `TRUSTED_PROXIES`.
+pub fn trusted_proxies() -> &'static Vec<IpAddr> {
+  TRUSTED_PROXIES.get_or_init(|| {
+    env::var("TRUSTED_PROXIES")
+      .unwrap_or_default()
+      .split(',')
+      .map(|proxy| proxy.trim())
+      .filter(|proxy| !proxy.is_empty())
+      .map(|proxy| proxy.parse::<IpAddr>().unwrap_or_else(|_| panic!("TRUSTED_PROXIES has an invalid IP address: {}", proxy)))
+      .collect()
+  })
+}
+
+/// The address of the client that made the request. Forwarding headers are only
+/// followed when the request comes from a trusted proxy, so clients can't spoof it.
+pub fn client_ip(request: &Request<'_>) -> Option<IpAddr> {
+  client_ip_behind(request, trusted_proxies())
+}
+
+fn client_ip_behind(request: &Request<'_>, proxies: &[IpAddr]) -> Option<IpAddr> {
+  let remote_ip: IpAddr = request.remote()?.ip();
+
+  if !proxies.contains(&remote_ip) {
+    return Some(remote_ip);
+  }
+
+  // Walk the chain from the closest hop, the first untrusted address is the client
+  if let Some(forwarded_for) = request.headers().get_one("X-Forwarded-For") {
+    let forwarded_ips = forwarded_for
+      .split(',')
+      .rev()
+      .filter_map(|ip| ip.trim().parse::<IpAddr>().ok());
+
+    let mut client: Option<IpAddr> = None;
+    for ip in forwarded_ips {
+      client = Some(ip);
+      if !proxies.contains(&ip) {
+        break;
+      }
+    }
+
+    if let Some(ip) = client {
+      return Some(ip);
+    }
+  }
+
+  // Fall back to the header a single proxy usually sets
+  if let Some(real_ip) = request.headers().get_one("X-Real-IP").and_then(|ip| ip.trim().parse::<IpAddr>().ok()) {
+    return Some(real_ip);
+  }
+
+  Some(remote_ip)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use rocket::{http::Header, local::blocking::Client};
+  use super::*;
+
+  const PROXY: &str = "10.0.0.1";
+  const CLIENT: &str = "203.0.113.7";
+
+  fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+  }
+
+  fn client_ip_of(remote: &str, headers: &[(&'static str, &'static str)], proxies: &[IpAddr]) -> Option<IpAddr> {
+    let client: Client = Client::untracked(rocket::build()).unwrap();
+    let mut request = client.get("/").remote((ip(remote), 443).into());
+    for (name, value) in headers {
+      request.add_header(Header::new(*name, *value));
+    }
+
+    client_ip_behind(request.inner(), proxies)
+  }
+
+  #[test]
+  fn forwarding_headers_are_ignored_without_trusted_proxies() {
+    assert_eq!(client_ip_of(CLIENT, &[], &[]), Some(ip(CLIENT)));
+    assert_eq!(client_ip_of(CLIENT, &[("X-Forwarded-For", "198.51.100.1"), ("X-Real-IP", "198.51.100.2")], &[]), Some(ip(CLIENT)));
+
+    // Headers sent straight to us by a client are ignored even if some proxies are trusted
+    assert_eq!(client_ip_of(CLIENT, &[("X-Forwarded-For", "198.51.100.1")], &[ip(PROXY)]), Some(ip(CLIENT)));
+  }
+
+  #[test]
+  fn forwarding_headers_are_followed_from_trusted_proxies() {
+    let proxies: Vec<IpAddr> = vec![ip(PROXY), ip("10.0.0.2")];
+
+    assert_eq!(client_ip_of(PROXY, &[("X-Forwarded-For", CLIENT)], &proxies), Some(ip(CLIENT)));
+
+    // The first untrusted hop from the right is the client, whatever it has put before itself
+    assert_eq!(client_ip_of(PROXY, &[("X-Forwarded-For", "198.51.100.1, 203.0.113.7, 10.0.0.2")], &proxies), Some(ip(CLIENT)));
+
+    // Invalid entries are skipped
+    assert_eq!(client_ip_of(PROXY, &[("X-Forwarded-For", "203.0.113.7, garbage")], &proxies), Some(ip(CLIENT)));
+
+    // A chain of only trusted proxies ends at the furthest one
+    assert_eq!(client_ip_of(PROXY, &[("X-Forwarded-For", "10.0.0.2")], &proxies), Some(ip("10.0.0.2")));
+
+    assert_eq!(client_ip_of(PROXY, &[("X-Real-IP", CLIENT)], &proxies), Some(ip(CLIENT)));
+    assert_eq!(client_ip_of(PROXY, &[], &proxies), Some(ip(PROXY)));
+  }
+}
@@ -0,0 +1,71 @@
+use rocket::{request::{FromRequest, Outcome}, Request};
+
+
+/// Whether `locale` looks like a language tag, e.g. `en` or `pt-br`.
+pub fn is_valid_locale(locale: &str) -> bool {
+  !locale.is_empty()
+    && locale.len() <= 35
+    && locale.split('-').all(|part| !part.is_empty() && part.len() <= 8 && part.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// The languages of an `Accept-Language` header in lowercase, most preferred first.
+pub fn parse_accept_language(header_value: &str) -> Vec<String> {
+  let mut languages: Vec<(String, f32)> = header_value
+    .split(',')
+    .filter_map(|entry| {
+      let mut parts = entry.split(';');
+      let language: String = parts.next()?.trim().to_lowercase();
+
+      let quality: f32 = parts
+        .find_map(|param| param.trim().strip_prefix("q="))
+        .map(|quality| quality.trim().parse::<f32>().unwrap_or(0.0))
+        .unwrap_or(1.0);
+
+      if quality <= 0.0 || !is_valid_locale(&language) {
+        return None;
+      }
+
+      Some((language, quality))
+    })
+    .collect();
+
+  // Stable, so languages with the same quality keep their order
+  languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+  languages.into_iter().map(|(language, _)| language).collect()
+}
+
+
+/// The languages the client accepts according to its `Accept-Language` header.
+pub struct AcceptLanguage(pub Vec<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptLanguage {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    Outcome::Success(AcceptLanguage(
+      request.headers().get_one("Accept-Language").map(parse_accept_language).unwrap_or_default()
+    ))
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn accept_language_is_ordered_by_quality() {
+    assert_eq!(parse_accept_language("fr;q=0.5, id, EN-US;q=0.8"), vec!["id", "en-us", "fr"]);
+    // Languages with the same quality keep their order
+    assert_eq!(parse_accept_language("id;q=0.7, en;q=0.7, fr"), vec!["fr", "id", "en"]);
+  }
+
+  #[test]
+  fn accept_language_skips_what_it_cant_use() {
+    assert_eq!(parse_accept_language("id;q=0, en"), vec!["en"]);
+    assert_eq!(parse_accept_language("id;q=abc, en;q=0.1"), vec!["en"]);
+    assert_eq!(parse_accept_language("*, en_US, en"), vec!["en"]);
+    assert!(parse_accept_language("").is_empty());
+  }
+}
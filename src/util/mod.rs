@@ -1,3 +1,7 @@
+pub mod password;
+pub mod client_ip;
+pub mod locale;
+
 use rand::{self, Rng};
 
 pub fn generate_token(length: u8) -> String {
@@ -13,16 +17,21 @@ pub fn generate_token(length: u8) -> String {
       token += (choosen_ascii_code as char).to_string().as_str();
     }
 
-    return token;
+    token
+}
+
+/// Compare two strings without short circuiting, so the timing doesn't leak how much of a secret matched.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 use sqlx::{Error, postgres::PgDatabaseError};
 
 pub fn is_duplicated_error(err: &Error) -> bool {
-    if let Error::Database(db_err) = err {
-        if let Some(pg_err) = db_err.try_downcast_ref::<PgDatabaseError>() {
-            return pg_err.code() == "23505";
-        }
+    if let Error::Database(db_err) = err
+        && let Some(pg_err) = db_err.try_downcast_ref::<PgDatabaseError>() {
+        return pg_err.code() == "23505";
     }
     false
 }
@@ -0,0 +1,166 @@
+use std::{env, sync::OnceLock};
+use argon2::{
+  password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+  Algorithm, Argon2, Params, Version
+};
+use sha3::{Digest, Sha3_256};
+use crate::util::constant_time_eq;
+
+
+/// The result of checking a password against the hash stored in the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordVerification {
+  /// The password doesn't match the stored hash.
+  Invalid,
+  /// The password matches and the stored hash is up to date.
+  Valid,
+  /// The password matches, but the stored hash uses an outdated scheme or cost
+  /// and should be replaced with a fresh hash of the same password.
+  ValidNeedsRehash
+}
+
+impl PasswordVerification {
+  pub fn is_valid(&self) -> bool {
+    *self != PasswordVerification::Invalid
+  }
+}
+
+
+static PASSWORD_HASH_PARAMS: OnceLock<Params> = OnceLock::new();
+
+fn read_cost(name: &str, default: u32) -> u32 {
+  match env::var(name) {
+    Ok(value) => value.parse::<u32>().unwrap_or_else(|_| panic!("{} must be a valid number", name)),
+    Err(_) => default
+  }
+}
+
+/// Argon2id cost parameters, read once from `PASSWORD_HASH_MEMORY_COST` (KiB),
+/// `PASSWORD_HASH_TIME_COST` and `PASSWORD_HASH_PARALLELISM`.
+fn password_hash_params() -> &'static Params {
+  PASSWORD_HASH_PARAMS.get_or_init(|| {
+    Params::new(
+      read_cost("PASSWORD_HASH_MEMORY_COST", Params::DEFAULT_M_COST),
+      read_cost("PASSWORD_HASH_TIME_COST", Params::DEFAULT_T_COST),
+      read_cost("PASSWORD_HASH_PARALLELISM", Params::DEFAULT_P_COST),
+      None
+    )
+    .expect("The configured password hashing cost is not valid for Argon2")
+  })
+}
+
+fn hasher() -> Argon2<'static> {
+  Argon2::new(Algorithm::Argon2id, Version::V0x13, password_hash_params().clone())
+}
+
+fn hash_password_blocking(password: &str) -> Result<String, String> {
+  let salt: SaltString = SaltString::generate(&mut OsRng);
+
+  match hasher().hash_password(password.as_bytes(), &salt) {
+    Ok(hash) => Ok(hash.to_string()),
+    Err(err) => Err(format!("There's an error when trying to hash password. Error: {}", err))
+  }
+}
+
+fn verify_password_blocking(password: &str, stored_hash: &str) -> Result<PasswordVerification, String> {
+  // Hashes created before Argon2 was introduced are bare hex encoded SHA3-256 digests
+  if !stored_hash.starts_with('$') {
+    let legacy_hash: String = hex::encode(Sha3_256::digest(password.as_bytes()));
+
+    return Ok(if constant_time_eq(&legacy_hash, stored_hash) { PasswordVerification::ValidNeedsRehash } else { PasswordVerification::Invalid });
+  }
+
+  let parsed_hash: PasswordHash = match PasswordHash::new(stored_hash) {
+    Ok(hash) => hash,
+    Err(err) => {
+      return Err(format!("The stored password hash is not a valid PHC string. Error: {}", err));
+    }
+  };
+
+  match hasher().verify_password(password.as_bytes(), &parsed_hash) {
+    Ok(_) => (),
+    Err(argon2::password_hash::Error::Password) => {
+      return Ok(PasswordVerification::Invalid);
+    },
+    Err(err) => {
+      return Err(format!("There's an error when trying to verify password. Error: {}", err));
+    }
+  }
+
+  // Check if the hash was made with the current algorithm and cost
+  let current_params: &Params = password_hash_params();
+  let is_outdated: bool = match Params::try_from(&parsed_hash) {
+    Ok(params) => {
+      parsed_hash.algorithm != Algorithm::Argon2id.ident()
+        || parsed_hash.version != Some(Version::V0x13.into())
+        || params.m_cost() != current_params.m_cost()
+        || params.t_cost() != current_params.t_cost()
+        || params.p_cost() != current_params.p_cost()
+    },
+    Err(_) => true
+  };
+
+  Ok(if is_outdated { PasswordVerification::ValidNeedsRehash } else { PasswordVerification::Valid })
+}
+
+
+/// Hash a password with Argon2id and a random salt, returned in PHC string format.
+pub async fn hash_password(password: &str) -> Result<String, String> {
+  let password: String = password.to_string();
+
+  match tokio::task::spawn_blocking(move || hash_password_blocking(&password)).await {
+    Ok(result) => result,
+    Err(err) => Err(format!("The password hashing task has failed. Error: {}", err))
+  }
+}
+
+/// Verify a password against a stored hash. Both Argon2 PHC strings and the
+/// legacy unsalted SHA3-256 hex digests are accepted.
+pub async fn verify_password(password: &str, stored_hash: &str) -> Result<PasswordVerification, String> {
+  let password: String = password.to_string();
+  let stored_hash: String = stored_hash.to_string();
+
+  match tokio::task::spawn_blocking(move || verify_password_blocking(&password, &stored_hash)).await {
+    Ok(result) => result,
+    Err(err) => Err(format!("The password verification task has failed. Error: {}", err))
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn argon2id_hash_verifies() {
+    let hash: String = hash_password_blocking("correct horse").unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+
+    assert_eq!(verify_password_blocking("correct horse", &hash).unwrap(), PasswordVerification::Valid);
+    assert_eq!(verify_password_blocking("wrong horse", &hash).unwrap(), PasswordVerification::Invalid);
+
+    // Every hash has its own salt
+    assert_ne!(hash_password_blocking("correct horse").unwrap(), hash);
+  }
+
+  #[test]
+  fn legacy_sha3_hash_needs_rehash() {
+    let legacy_hash: String = hex::encode(Sha3_256::digest(b"correct horse"));
+
+    assert_eq!(verify_password_blocking("correct horse", &legacy_hash).unwrap(), PasswordVerification::ValidNeedsRehash);
+    assert_eq!(verify_password_blocking("wrong horse", &legacy_hash).unwrap(), PasswordVerification::Invalid);
+  }
+
+  #[test]
+  fn outdated_hash_needs_rehash() {
+    let salt: SaltString = SaltString::generate(&mut OsRng);
+    let hash_with = |algorithm: Algorithm, params: Params| Argon2::new(algorithm, Version::V0x13, params).hash_password(b"correct horse", &salt).unwrap().to_string();
+
+    let cheaper_hash: String = hash_with(Algorithm::Argon2id, Params::new(Params::MIN_M_COST, 1, 1, None).unwrap());
+    assert_eq!(verify_password_blocking("correct horse", &cheaper_hash).unwrap(), PasswordVerification::ValidNeedsRehash);
+    assert_eq!(verify_password_blocking("wrong horse", &cheaper_hash).unwrap(), PasswordVerification::Invalid);
+
+    let argon2i_hash: String = hash_with(Algorithm::Argon2i, password_hash_params().clone());
+    assert_eq!(verify_password_blocking("correct horse", &argon2i_hash).unwrap(), PasswordVerification::ValidNeedsRehash);
+  }
+}
@@ -1,40 +1,75 @@
-use std::{collections::HashMap, env, net::SocketAddr, sync::{Arc, Mutex}};
-use futures_util::{stream::SplitSink, StreamExt};
+use std::{collections::HashMap, env, net::SocketAddr, sync::{Arc, Mutex, OnceLock}, time::Duration};
+use futures_util::{SinkExt, StreamExt};
 use tokio_tungstenite::{tungstenite::{self, protocol::{frame::coding::CloseCode, CloseFrame}}, WebSocketStream};
-use crate::{model::{Connection, Device, User}, types::{WebSocketManager, WebSocketSender}};
+use crate::{auth::{authenticate_device, authenticate_user, check_session, device_access::device_role, device_claim::{format_claim_code, is_device_claimed, issue_device_claim}, now_utc, parse_bearer_token, session_valid_until}, model::{Connection, Device, User}, telemetry::ingest::ReadingIngest, types::{UserClient, WebSocketConnection, WebSocketManager, WebSocketSender}, websocket::{presence::{mark_device_offline, mark_device_online, presence_config, touch_device, PresenceConfig}, protocol::{ClaimCodePayload, Envelope, ErrorPayload, HelloPayload, Payload, TelemetryPayload, unix_millis, WireProtocol, PROTOCOL_VERSION}}};
 use http::{Request, Response};
+use rocket::time::PrimitiveDateTime;
 use sqlx::{Pool, Postgres};
-use tokio::{net::{TcpListener, TcpStream}, sync::RwLock};
+use tokio::{net::{TcpListener, TcpStream}, time::{Instant, MissedTickBehavior}};
 use either::Either;
 
-pub async fn run_websocket_server(ws_manager: WebSocketManager, pool: Pool<Postgres>) {
+pub async fn run_websocket_server(ws_manager: WebSocketManager, pool: Pool<Postgres>, ingest: ReadingIngest) {
   // Setting up listener
   let addr = env::var("WEBSOCKET_ADDRESS").unwrap_or(String::from("127.0.0.1:8040"));
   let listener = TcpListener::bind(
     &addr
   )
   .await
-  .expect(format!("Can't listen to the address ws://{}", addr).as_str());
+  .unwrap_or_else(|_| panic!("Can't listen to the address ws://{}", addr));
 
   log::warn!("Web Socket listenning to ws://{}", addr);
+
+  // Read the settings now, so a bad one stops the server instead of a connection
+  auth_timeout();
   
   while let Ok((stream, addr)) = listener.accept().await {
     log::warn!("({}:{}) WebSocket Client Connected", addr.ip(), addr.port());
     let ws_manager_instance: WebSocketManager = ws_manager.clone();
     let pool_instance: Pool<Postgres> = pool.clone();
-    tokio::spawn(handle_websocket_connection(stream, ws_manager_instance, pool_instance, addr));
+    let ingest_instance: ReadingIngest = ingest.clone();
+    tokio::spawn(handle_websocket_connection(stream, ws_manager_instance, pool_instance, ingest_instance, addr));
   }
 }
 
-fn handle_websocket_header_inspection(request: &Request<()>) -> Result<String, Response<Option<String>>> {
-  // Get all of the cookies
-  let mut cookies = HashMap::new();
+/// What has been found in the handshake request of a Web Socket connection.
+struct HeaderInspection {
+  /// The access token, if the client has sent it along with the handshake
+  access_token: Option<String>,
+  /// The `Sec-WebSocket-Protocol` entry to echo back, if the token came from there
+  protocol: Option<String>,
+  /// The protocol the client has asked to speak, if it has asked for one this server speaks
+  wire_protocol: Option<WireProtocol>
+}
 
-  for (name, value) in request.headers() {
-      if name.as_str().to_lowercase() != "cookie" {
-        continue;
+/// The prefix of a `Sec-WebSocket-Protocol` entry that carries an access token, e.g. `bearer.<token>`.
+const BEARER_PROTOCOL_PREFIX: &str = "bearer.";
+
+fn handle_websocket_header_inspection(request: &Request<()>) -> HeaderInspection {
+  // Check the authorization header first
+  if let Some(header_value) = request.headers().get("authorization").and_then(|value| value.to_str().ok())
+    && let Some(token) = parse_bearer_token(header_value) {
+    return HeaderInspection { access_token: Some(token.to_string()), protocol: None, wire_protocol: None };
+  }
+
+  // Then the subprotocols, for clients that can't set custom headers
+  for value in request.headers().get_all("sec-websocket-protocol") {
+    let Ok(protocols_str) = value.to_str() else {
+      continue;
+    };
+
+    for protocol in protocols_str.split(',') {
+      let protocol = protocol.trim();
+      if let Some(token) = protocol.strip_prefix(BEARER_PROTOCOL_PREFIX)
+        && !token.is_empty() {
+        return HeaderInspection { access_token: Some(token.to_string()), protocol: Some(protocol.to_string()), wire_protocol: None };
       }
-      
+    }
+  }
+
+  // Then the cookies
+  let mut cookies = HashMap::new();
+
+  for value in request.headers().get_all("cookie") {
       if let Ok(cookie_str) = value.to_str() {
         for cookie_pair in cookie_str.split(';') {
           let cookie_pair = cookie_pair.trim();
@@ -48,39 +83,238 @@ fn handle_websocket_header_inspection(request: &Request<()>) -> Result<String, R
       }
   }
 
-  // Get the access token
-  let access_token = match cookies.get("access_token") {
-    Some(token) => token,
-    None => {
-      return Err(Response::builder().status(401).body(Some(String::from("No Token Provided!"))).unwrap());
+  // No token at all means the client will send it in its first message
+  HeaderInspection { access_token: cookies.remove("access_token"), protocol: None, wire_protocol: None }
+}
+
+/// The first protocol the client offers in `Sec-WebSocket-Protocol` that this server speaks.
+fn negotiate_wire_protocol(request: &Request<()>) -> Option<WireProtocol> {
+  request
+    .headers()
+    .get_all("sec-websocket-protocol")
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|protocols_str| protocols_str.split(','))
+    .find_map(|protocol| WireProtocol::from_subprotocol(protocol.trim()))
+}
+
+static AUTH_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// How long a client has to send its token after connecting, read once from `WEBSOCKET_AUTH_TIMEOUT_SECS`.
+fn auth_timeout() -> Duration {
+  *AUTH_TIMEOUT.get_or_init(|| {
+    let auth_timeout: u64 = env::var("WEBSOCKET_AUTH_TIMEOUT_SECS")
+      .unwrap_or(String::from("10"))
+      .parse::<u64>()
+      .expect("WEBSOCKET_AUTH_TIMEOUT_SECS must be a valid number");
+
+    Duration::from_secs(auth_timeout)
+  })
+}
+
+/// Wait for an `auth=<token>` frame, or an `auth` envelope, from a client that didn't send its token during the handshake.
+async fn wait_for_auth_message(ws_stream: &mut WebSocketStream<TcpStream>) -> Option<String> {
+  let first_message = tokio::time::timeout(auth_timeout(), ws_stream.next()).await;
+
+  match first_message {
+    Ok(Some(Ok(message))) if message.is_binary() => match Envelope::from_msgpack(&message.into_data()) {
+      Ok(Envelope { payload: Payload::Auth(auth), .. }) => Some(auth.token.trim().to_string()),
+      _ => None
+    },
+    Ok(Some(Ok(message))) if message.is_text() => {
+      let text = message.to_text().ok()?;
+      if let Ok(Envelope { payload: Payload::Auth(auth), .. }) = Envelope::from_json(text) {
+        return Some(auth.token.trim().to_string());
+      }
+
+      text.strip_prefix("auth=").map(|token| token.trim().to_string())
+    },
+    _ => None
+  }
+}
+
+/// Answer a client over its own connection.
+async fn answer(sender: &WebSocketSender, envelope: &Envelope) {
+  if let Err(err) = sender.send_envelope(envelope).await {
+    log::error!("There's an error when trying to answer a web socket message. Error: {}", err);
+  }
+}
+
+/// Switch a legacy connection to the JSON protocol, answered with a hello of its own.
+async fn handle_hello(sender: &WebSocketSender, request: &Envelope, hello: &HelloPayload) {
+  if hello.version != PROTOCOL_VERSION {
+    answer(sender, &request.error("unsupported_version")).await;
+    return;
+  }
+
+  // Connections that have negotiated a protocol keep it, even if it's the legacy one
+  if !sender.is_negotiated() {
+    sender.set_protocol(WireProtocol::JsonV1);
+  }
+  let mut response: Envelope = Envelope::new(Payload::Hello(HelloPayload { version: PROTOCOL_VERSION })).with_ts(now_utc());
+  response.id = request.id.clone();
+  answer(sender, &response).await;
+}
+
+/// Give an unclaimed device a claim code, answered with `claim_code=<code>,<seconds until it expires>`.
+/// The code can be shown as it is or as a QR code of `WMS-CLAIM:<code>`.
+async fn handle_claim_request(pool: &Pool<Postgres>, ws_manager: &WebSocketManager, device_id: &str, request: &Envelope) {
+  let payload: Payload = match issue_device_claim(pool, device_id).await {
+    Ok(Some((code, expire_at))) => {
+      log::info!("Device {} has asked for a claim code", device_id);
+      Payload::ClaimCode(ClaimCodePayload { code: format_claim_code(&code), expires_in: (expire_at - now_utc()).whole_seconds() })
+    },
+    Ok(None) => Payload::ClaimError(ErrorPayload { code: String::from("already_claimed") }),
+    Err(err) => {
+      log::error!("There's an error when trying to create a claim code. Error: {}", err);
+      Payload::ClaimError(ErrorPayload { code: String::from("unexpected") })
+    }
+  };
+
+  let mut response: Envelope = Envelope::new(payload).with_ts(now_utc());
+  response.id = request.id.clone();
+  if let Err(err) = ws_manager.send_device_message(device_id, &response).await {
+    log::error!("There's an error when trying to send the claim code to the device. Error: {}", err);
+  }
+}
+
+/// Relay a reading to the device's users and queue it to be stored.
+async fn handle_telemetry(ws_manager: &WebSocketManager, ingest: &ReadingIngest, sender: &WebSocketSender, device_id: &str, request: &Envelope) {
+  let Some(reading) = request.reading() else {
+    log::warn!("Device {} has sent data that isn't a valid reading: {:?}", device_id, request);
+    answer(sender, &request.error("invalid_reading")).await;
+    return;
+  };
+
+  let mut data: Envelope = Envelope::new(Payload::Telemetry(TelemetryPayload { sensor: reading.sensor.clone(), value: reading.value.clone() })).with_device_id(device_id);
+  data.id = request.id.clone();
+  data.ts = request.ts;
+
+  log::info!("Device {} is currently sending data: {}={:?}", device_id, reading.sensor, reading.value);
+  match ws_manager.send_user_message(device_id, &data).await {
+    Ok(_) => {
+      log::info!("Data has been successfully sent!");
+    },
+    Err(err) => {
+      log::error!("There's an error when trying to send sensor data. Error: {}", err);
+    }
+  }
+
+  // Keep the reading, so users who weren't listening can see it later. It's stored in the
+  // background, dropped readings are counted and reported by the ingestion worker
+  ingest.ingest(device_id, reading, now_utc());
+
+  if let Some(ack) = request.ack() {
+    answer(sender, &ack).await;
+  }
+}
+
+/// Relay a command from a user to the device. Only controllers and owners can send commands, anyone
+/// else is answered with a `forbidden` error.
+async fn handle_user_command(pool: &Pool<Postgres>, ws_manager: &WebSocketManager, sender: &WebSocketSender, user_id: &str, request: &Envelope) {
+  let Some(device_id) = request.device_id.as_deref() else {
+    answer(sender, &request.error("invalid_message")).await;
+    return;
+  };
+
+  let error: Option<&str> = match device_role(pool, user_id, device_id).await {
+    Ok(Some(role)) if role.can_control() => {
+      // Devices don't need to be told their own ID
+      let command: Envelope = Envelope { device_id: None, ts: Some(request.ts.unwrap_or(unix_millis(now_utc()))), ..request.clone() };
+      match ws_manager.send_device_message(device_id, &command).await {
+        Ok(_) => None,
+        Err(err) => {
+          log::warn!("Command couldn't be sent to the device. {}", err);
+          Some("offline")
+        }
+      }
+    },
+    Ok(_) => {
+      log::warn!("User {} has tried to send a command to device {} without the permission to", user_id, device_id);
+      Some("forbidden")
+    },
+    Err(err) => {
+      log::error!("There's an error when trying to get the device role. Error: {}", err);
+      Some("unexpected")
     }
   };
 
+  match error {
+    Some(error) => answer(sender, &request.error(error)).await,
+    None => {
+      if let Some(ack) = request.ack() {
+        answer(sender, &ack).await;
+      }
+    }
+  }
+}
+
+/// Handle a message from a device. Devices nobody has claimed yet can only say hello and ask for a
+/// claim code, anything else is answered with an `unclaimed` error until someone claims them.
+async fn handle_device_message(pool: &Pool<Postgres>, ws_manager: &WebSocketManager, ingest: &ReadingIngest, sender: &WebSocketSender, device_id: &str, unclaimed: &mut bool, request: Envelope) {
+  if *unclaimed && !matches!(request.payload, Payload::Hello(_) | Payload::ClaimRequest(_)) {
+    // The device may have been claimed since it connected
+    match is_device_claimed(pool, device_id).await {
+      Ok(true) => {
+        *unclaimed = false;
+      },
+      Ok(false) => {
+        log::warn!("Device {} has sent a message before being claimed: {:?}", device_id, request);
+        answer(sender, &request.error("unclaimed")).await;
+        return;
+      },
+      Err(err) => {
+        log::error!("There's an error when trying to check if the device has been claimed. Error: {}", err);
+        answer(sender, &request.error("unexpected")).await;
+        return;
+      }
+    }
+  }
+
+  match &request.payload {
+    Payload::Hello(hello) => handle_hello(sender, &request, hello).await,
+    Payload::ClaimRequest(_) => handle_claim_request(pool, ws_manager, device_id, &request).await,
+    Payload::Telemetry(_) => handle_telemetry(ws_manager, ingest, sender, device_id, &request).await,
+    // Devices answering a command
+    Payload::Ack(_) | Payload::Error(_) => {
+      log::info!("Get an answer from device {}: {:?}", device_id, request);
+    },
+    _ => answer(sender, &request.error("unsupported_type")).await
+  }
+}
 
-  Ok(access_token.clone())
+/// Handle a message from a user.
+async fn handle_user_message(pool: &Pool<Postgres>, ws_manager: &WebSocketManager, sender: &WebSocketSender, user_id: &str, request: Envelope) {
+  match &request.payload {
+    Payload::Hello(hello) => handle_hello(sender, &request, hello).await,
+    Payload::Command(_) => handle_user_command(pool, ws_manager, sender, user_id, &request).await,
+    _ => answer(sender, &request.error("unsupported_type")).await
+  }
 }
 
-async fn handle_websocket_connection(stream: TcpStream, ws_manager: WebSocketManager, pool: Pool<Postgres>, addr: SocketAddr) {
-  //? Try to handle cookies and get the access token
-  let access_token: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
-  let access_token_instance = access_token.clone();
+// The handshake callback's error type comes from tungstenite
+#[allow(clippy::result_large_err)]
+async fn handle_websocket_connection(stream: TcpStream, ws_manager: WebSocketManager, pool: Pool<Postgres>, ingest: ReadingIngest, addr: SocketAddr) {
+  //? Try to handle the handshake headers and get the access token
+  let header_inspection: Arc<Mutex<Option<HeaderInspection>>> = Arc::new(Mutex::new(None));
+  let header_inspection_instance = header_inspection.clone();
   let ws_stream = tokio_tungstenite::accept_hdr_async(
     stream, 
-    move |request: &Request<()>, response: Response<()>| {
-      
-      // Get the cookie
-      let result: Result<String, Response<Option<String>>> = handle_websocket_header_inspection(request);
-      
-      match result {
-        Ok(token) => {
-          let mut safe_access_token = access_token_instance.lock().unwrap();
-          *safe_access_token = Some(token); // Exactly a token passed
-          return Ok(response);
-        },
-        Err(err) => {
-          return Err(err);
-        }
+    move |request: &Request<()>, mut response: Response<()>| {
+      let mut result: HeaderInspection = handle_websocket_header_inspection(request);
+      result.wire_protocol = negotiate_wire_protocol(request);
+
+      // Echo the chosen subprotocol back, browsers drop the connection otherwise. Only one can be
+      // chosen, so a negotiated protocol wins over the token entry
+      let echoed_protocol: Option<&str> = result.wire_protocol.map(|wire_protocol| wire_protocol.subprotocol()).or(result.protocol.as_deref());
+      if let Some(protocol) = echoed_protocol
+        && let Ok(header_value) = protocol.parse() {
+        response.headers_mut().insert("sec-websocket-protocol", header_value);
       }
+
+      let mut safe_header_inspection = header_inspection_instance.lock().unwrap();
+      *safe_header_inspection = Some(result);
+      Ok(response)
     }
   )
   .await;
@@ -90,50 +324,60 @@ async fn handle_websocket_connection(stream: TcpStream, ws_manager: WebSocketMan
   let mut ws_stream: WebSocketStream<TcpStream> = match ws_stream {
     Ok(ws) => ws,
     Err(err) => {
-      log::error!("There's an error when trying to accept web socket connection to the client. Error: {}", err.to_string());
+      log::error!("There's an error when trying to accept web socket connection to the client. Error: {}", err);
       return;
     }
   };
 
 
-  //? Get the access token
-  let safe_access_token;
-  {
-    let raw_safe_access_token: Option<String> = match access_token.lock() {
-      Ok(res) => Some(res.clone().unwrap()),
-      Err(err) => {
-        log::error!("There's an error when trying to get access token safely. Error: {}", err.to_string());
-        None
-      }
-    };
-  
-    if raw_safe_access_token.is_none() {
+  //? Get the access token and the negotiated protocol
+  let raw_header_inspection: Result<Option<HeaderInspection>, String> = match header_inspection.lock() {
+    Ok(mut res) => Ok(res.take()),
+    Err(err) => Err(err.to_string())
+  };
+
+  let (handshake_access_token, wire_protocol) = match raw_header_inspection {
+    Ok(inspection) => match inspection {
+      Some(inspection) => (inspection.access_token, inspection.wire_protocol),
+      None => (None, None)
+    },
+    Err(err) => {
+      log::error!("There's an error when trying to get access token safely. Error: {}", err);
       ws_stream.close(Some(CloseFrame {
         code: CloseCode::Error,
         reason: "There's an unexpected error".into()
       })).await.unwrap();
       return;
     }
+  };
 
-    safe_access_token = String::from(raw_safe_access_token.unwrap());
-  }
+  let safe_access_token: String = match handshake_access_token {
+    Some(token) => token,
+    None => match wait_for_auth_message(&mut ws_stream).await {
+      Some(token) => token,
+      None => {
+        log::warn!("No access token has been provided. Closing the connection.");
+        let _ = ws_stream.close(Some(CloseFrame {
+          code: CloseCode::Policy,
+          reason: "No Token Provided!".into()
+        })).await;
+        return;
+      }
+    }
+  };
 
 
   //? Get user or device data
   let mut client_data: Option<either::Either<User, Device>> = None;
-  let raw_user_data = sqlx::query_as!(
-    User,
-    "SELECT * FROM users WHERE access_token = $1",
-    safe_access_token
-  )
-  .fetch_optional(&pool)
-  .await;
+  let mut user_session_id: Option<String> = None;
+  let mut session_expire_at: Option<PrimitiveDateTime> = None;
+  let raw_user_data = authenticate_user(&pool, &safe_access_token).await;
 
   //? Check if there's any error when trying to get user data from token
   let user_data = match raw_user_data {
     Ok(data) => data,
     Err(err) => {
-      log::error!("There's an error when trying to get user data. Error: {}", err.to_string());
+      log::error!("There's an error when trying to get user data. Error: {}", err);
 
       ws_stream.close(Some(CloseFrame {
         code: CloseCode::Error,
@@ -144,27 +388,20 @@ async fn handle_websocket_connection(stream: TcpStream, ws_manager: WebSocketMan
   };
 
   //? Check if we get user data from the access token
-  match user_data {
-    Some(data) => {
-      client_data = Some(either::Either::Left(data));
-    },
-    None => ()
-  };
+  if let Some(data) = user_data {
+    session_expire_at = Some(session_valid_until(&data.session));
+    user_session_id = Some(data.session.id);
+    client_data = Some(either::Either::Left(data.user));
+  }
 
   if client_data.is_none() {
-    let raw_device_data = sqlx::query_as!(
-      Device,
-      "SELECT * FROM devices WHERE access_token = $1",
-      safe_access_token
-    )
-    .fetch_optional(&pool)
-    .await;
+    let raw_device_data = authenticate_device(&pool, &safe_access_token).await;
   
     // Check if there's any error when trying to get user data from token
     let device_data = match raw_device_data {
       Ok(data) => data,
       Err(err) => {
-        log::error!("There's an error when trying to get user data. Error: {}", err.to_string());
+        log::error!("There's an error when trying to get user data. Error: {}", err);
   
         ws_stream.close(Some(CloseFrame {
           code: CloseCode::Error,
@@ -174,12 +411,9 @@ async fn handle_websocket_connection(stream: TcpStream, ws_manager: WebSocketMan
       }
     };
 
-    match device_data {
-      Some(data) => {
-        client_data = Some(either::Either::Right(data));
-      },
-      None => ()
-    };
+    if let Some(data) = device_data {
+      client_data = Some(either::Either::Right(data));
+    }
   }
 
   //? Check if there's device or user with that ID
@@ -228,7 +462,7 @@ async fn handle_websocket_connection(stream: TcpStream, ws_manager: WebSocketMan
   let connections_data: Vec<Connection> = match connections_data {
     Ok(res) => res,
     Err(err) => {
-      log::error!("There's an error when trying to get connection data. Error: {}", err.to_string());
+      log::error!("There's an error when trying to get connection data. Error: {}", err);
       ws_stream.close(Some(CloseFrame {
         code: CloseCode::Error,
         reason: "There's an unexpected error.".into()
@@ -237,67 +471,119 @@ async fn handle_websocket_connection(stream: TcpStream, ws_manager: WebSocketMan
     }
   };
 
-  //? Fetch the connection ID
-  if connections_data.is_empty() {
-    log::warn!("No connection formed yet");
-    ws_stream.close(Some(CloseFrame {
-      code: CloseCode::Policy,
-      reason: "You're unauthorized".into()
-    })).await.unwrap();
-    return;
+  //? Devices nobody has claimed yet can only ask for a claim code
+  let mut unclaimed: bool = connections_data.is_empty() && client_type == "device";
+  if unclaimed {
+    log::info!("Device hasn't been claimed yet, waiting for it to ask for a claim code");
   }
   
   
-  //? Update device status
-  if let Either::Right(data) = &client_data {
-    // Update device status to all users
-    let send_result = ws_manager.send_user_message(&data.id, format!("status={},1", data.id).as_str()).await;
-
-    match send_result {
-      Ok(_) => (),
-      Err(err) => {
-        log::error!("There's an error when trying to update device status to all users through web socket. Error: {}", err.to_string());
-      }
-    }
-    
-    // Update device status in the database
-    let raw_update_device_status = sqlx::query!(
-      "UPDATE devices SET status = TRUE WHERE id = $1",
-      data.id
-    )
-    .execute(&pool)
-    .await;
-
-    match raw_update_device_status {
-      Ok(_) => (),
-      Err(err) => {
-        log::error!("There's an error when trying to update device status. Error: {}", err.to_string());
-        ws_stream.close(Some(CloseFrame {
-          code: CloseCode::Error,
-          reason: "There's unexpected error. try again.".into()
-        })).await.unwrap();
-        return;
-      }
-    }
-  }
-
   let (ws_write, mut ws_read) = ws_stream.split();
   let ws_client_address: String = format!("{}:{}", addr.ip(), addr.port());
 
-  let ws_write: WebSocketSender = Arc::new(RwLock::new(SplitSink::from(ws_write).into()));
+  let ws_write: WebSocketSender = WebSocketConnection::new(ws_write, wire_protocol);
+  log::info!("({}) {} is speaking {}", ws_client_address, client_type, ws_write.protocol().subprotocol());
   
   //? Add connection to the list
-  for connection_data in &connections_data {
-    match client_type {
-      "user" => ws_manager.new_user_connection(connection_data.device_id.clone(), ws_client_address.clone(), ws_write.clone()).await.unwrap(),
-      "device" => ws_manager.new_device_connection(connection_data.device_id.clone(), ws_write.clone()).await.unwrap(),
-      _ => ()
+  if let (Either::Left(user), Some(session_id)) = (&client_data, &user_session_id) {
+    ws_manager.new_user_client(ws_client_address.clone(), UserClient {
+      user_id: user.id.clone(),
+      session_id: session_id.clone(),
+      sender: ws_write.clone()
+    }).await;
+  }
+
+  match &client_data {
+    Either::Left(_) => {
+      for connection_data in &connections_data {
+        ws_manager.new_user_connection(connection_data.device_id.clone(), ws_client_address.clone(), ws_write.clone()).await.unwrap();
+      }
+    },
+    Either::Right(device_data) => {
+      // A device that reconnects before its previous connection has timed out replaces it
+      if let Some(previous_sender) = ws_manager.new_device_connection(device_data.id.clone(), ws_write.clone()).await {
+        log::warn!("Device {} has reconnected, closing its previous connection", device_data.id);
+        previous_sender.revoke();
+        let mut previous_sender_lock = previous_sender.write().await;
+        let _ = tokio::time::timeout(Duration::from_secs(1), previous_sender_lock.close()).await;
+      }
+
+      //? Update device status
+      if let Err(err) = mark_device_online(&pool, &ws_manager, &device_data.id).await {
+        log::error!("There's an error when trying to update device status. Error: {}", err);
+      }
     }
   }
   
     
-  //? Listen for incoming messages
-  while let Some(raw_message) = ws_read.next().await {
+  //? Listen for incoming messages, pinging the client so dead connections don't linger
+  let presence: &PresenceConfig = presence_config();
+  let mut heartbeat = tokio::time::interval_at(Instant::now() + presence.heartbeat_interval, presence.heartbeat_interval);
+  heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+  let mut last_received_at: Instant = Instant::now();
+  let mut last_seen_recorded_at: Instant = Instant::now();
+
+  loop {
+    let raw_message = tokio::select! {
+      biased;
+      _ = ws_write.revoked() => {
+        log::info!("({}) Connection has been revoked, no more frames are handled", ws_client_address);
+        break;
+      },
+      raw_message = ws_read.next() => raw_message,
+      _ = heartbeat.tick() => {
+        // Users are only let in while their session is valid. It may have been renewed through the
+        // REST API since, so the database has the last word
+        if let (Some(expire_at), Some(session_id)) = (session_expire_at, &user_session_id) && now_utc() >= expire_at {
+          match check_session(&pool, session_id).await {
+            Ok(Some(expire_at)) => {
+              session_expire_at = Some(expire_at);
+            },
+            Ok(None) => {
+              log::info!("({}) Session has expired, closing the connection", ws_client_address);
+              answer(&ws_write, &Envelope::new(Payload::Error(ErrorPayload { code: String::from("session_expired") }))).await;
+              let close_frame: CloseFrame = CloseFrame { code: CloseCode::Policy, reason: "Your session has expired".into() };
+              let _ = tokio::time::timeout(Duration::from_secs(1), ws_write.write().await.send(tungstenite::Message::Close(Some(close_frame)))).await;
+              break;
+            },
+            Err(err) => {
+              log::error!("There's an error when trying to check the session of a connection. Error: {}", err);
+            }
+          }
+        }
+
+        let mut sender_lock = ws_write.write().await;
+
+        if last_received_at.elapsed() > presence.heartbeat_timeout {
+          log::warn!("({}) Connection timed out after {} seconds without a heartbeat", ws_client_address, presence.heartbeat_timeout.as_secs());
+          let _ = tokio::time::timeout(Duration::from_secs(1), sender_lock.close()).await;
+          break;
+        }
+
+        if let Err(err) = sender_lock.send(tungstenite::Message::Ping(Default::default())).await {
+          log::warn!("({}) Heartbeat couldn't be sent. Error: {}", ws_client_address, err);
+        }
+        continue;
+      }
+    };
+
+    let Some(raw_message) = raw_message else {
+      break;
+    };
+
+    if ws_write.is_revoked() {
+      break;
+    }
+
+    // Any message counts as a heartbeat, pongs included
+    last_received_at = Instant::now();
+    if let Either::Right(device_data) = &client_data && last_seen_recorded_at.elapsed() >= presence.heartbeat_interval {
+      last_seen_recorded_at = last_received_at;
+      if let Err(err) = touch_device(&pool, &device_data.id).await {
+        log::error!("There's an error when trying to update the device last seen time. Error: {}", err);
+      }
+    }
+
     match raw_message {
       Ok(message) => {
         if message.is_text() {
@@ -308,30 +594,53 @@ async fn handle_websocket_connection(stream: TcpStream, ws_manager: WebSocketMan
             }
           };
 
-          if let Either::Right(device_data) = &client_data && text.contains("=") {
-            let data = text.split("=").collect::<Vec<&str>>();
-
-            // Append device ID
-            let data = format!("{}={},{}", data[0], device_data.id, data[1]);
-            
-            log::info!("Device is currently sending data: {}", data);
-            let send_result: Result<Option<()>, String>;
-            {
-              let device_data = client_data.as_ref().expect_right("Makes no sense cause if the client type is 'device' the client data should be a device");
-              send_result = ws_manager.send_user_message(&device_data.id, &data).await;
-            }
-
-            match send_result {
-              Ok(_) => {
-                log::info!("Data has been successfully sent!");
-              },
+          // JSON envelopes and legacy messages can be sent whatever the connection speaks
+          let envelope: Option<Envelope> = if text.trim_start().starts_with('{') {
+            match Envelope::from_json(text) {
+              Ok(envelope) => Some(envelope),
               Err(err) => {
-                log::error!("There's an error when trying to send sensor data. Error: {}", err.to_string());
+                log::warn!("Get an invalid message from a {}. Error: {}", client_type, err);
+                answer(&ws_write, &Envelope::new(Payload::Error(ErrorPayload { code: String::from("invalid_message") }))).await;
+                continue;
               }
             }
-          } 
+          }
           else {
-            log::info!("Get data from a {}: {}", client_type, text);
+            match &client_data {
+              Either::Left(_) => Envelope::from_legacy_user_message(text),
+              Either::Right(_) => Envelope::from_legacy_device_message(text)
+            }
+          };
+
+          match (envelope, &client_data) {
+            (Some(envelope), Either::Left(user_data)) => {
+              handle_user_message(&pool, &ws_manager, &ws_write, &user_data.id, envelope).await;
+            },
+            (Some(envelope), Either::Right(device_data)) => {
+              handle_device_message(&pool, &ws_manager, &ingest, &ws_write, &device_data.id, &mut unclaimed, envelope).await;
+            },
+            (None, Either::Right(device_data)) if text.contains('=') => {
+              log::warn!("Device {} has sent data that isn't a valid reading: {}", device_data.id, text);
+              answer(&ws_write, &Envelope::new(Payload::Error(ErrorPayload { code: String::from("invalid_reading") }))).await;
+            },
+            (None, _) => {
+              log::info!("Get data from a {}: {}", client_type, text);
+            }
+          }
+        }
+        else if message.is_binary() {
+          let envelope: Envelope = match Envelope::from_msgpack(&message.into_data()) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+              log::warn!("Get an invalid binary message from a {}. Error: {}", client_type, err);
+              answer(&ws_write, &Envelope::new(Payload::Error(ErrorPayload { code: String::from("invalid_message") }))).await;
+              continue;
+            }
+          };
+
+          match &client_data {
+            Either::Left(user_data) => handle_user_message(&pool, &ws_manager, &ws_write, &user_data.id, envelope).await,
+            Either::Right(device_data) => handle_device_message(&pool, &ws_manager, &ingest, &ws_write, &device_data.id, &mut unclaimed, envelope).await
           }
         }
       },
@@ -341,7 +650,7 @@ async fn handle_websocket_connection(stream: TcpStream, ws_manager: WebSocketMan
             log::warn!("({}) Connection closed", ws_client_address);
           },
           _ => {
-            log::error!("There's an error found in a message. Error: {}", err.to_string());
+            log::error!("There's an error found in a message. Error: {}", err);
           }
         }
       }
@@ -351,37 +660,49 @@ async fn handle_websocket_connection(stream: TcpStream, ws_manager: WebSocketMan
   // After connection closed
   match client_data {
     Either::Left(_) => {
-      for connection_data in connections_data {
-        ws_manager.remove_user_connection(&connection_data.device_id, &ws_client_address).await.unwrap()
-      }
+      ws_manager.remove_user_client(&ws_client_address).await;
+      // Devices claimed during the connection aren't in its connections data, so drop the address everywhere
+      ws_manager.remove_user_address(&ws_client_address).await;
     },
     Either::Right(device_data) => {
-      // Update device status to all users
-      let send_result = ws_manager.send_user_message(&device_data.id, format!("status={},0", device_data.id).as_str()).await;
-
-      match send_result {
-        Ok(_) => (),
-        Err(err) => {
-          log::error!("There's an error when trying to update device status to all users through web socket. Error: {}", err.to_string());
-        }
+      // A connection that has been replaced leaves the device online
+      if ws_manager.remove_device_connection(&device_data.id, &ws_write).await {
+        mark_device_offline(pool, ws_manager, device_data.id).await;
       }
+    }
+  }
+}
 
-      // Update device status in the database
-      let raw_update_device_status = sqlx::query!(
-        "UPDATE devices SET status = FALSE WHERE id = $1",
-        device_data.id
-      )
-      .execute(&pool)
-      .await;
 
-      match raw_update_device_status {
-        Ok(_) => (),
-        Err(err) => {
-          log::error!("There's an error when trying to update device status. Error: {}", err.to_string());
-        }
-      }
+#[cfg(test)]
+mod tests {
+  use super::*;
 
-      ws_manager.remove_device_connection(&device_data.id).await.unwrap()
+  fn handshake(protocols: &[&str]) -> Request<()> {
+    let mut request = Request::builder().uri("/");
+    for protocol in protocols {
+      request = request.header("sec-websocket-protocol", *protocol);
     }
+    request.body(()).unwrap()
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn negotiate_wire_protocols() {
+    assert_eq!(negotiate_wire_protocol(&handshake(&[])), None);
+    assert_eq!(negotiate_wire_protocol(&handshake(&["wms.legacy"])), Some(WireProtocol::Legacy));
+    assert_eq!(negotiate_wire_protocol(&handshake(&["wms.json.v1"])), Some(WireProtocol::JsonV1));
+    assert_eq!(negotiate_wire_protocol(&handshake(&["wms.msgpack.v1"])), Some(WireProtocol::MessagePackV1));
+
+    // The first one this server speaks wins, tokens and unknown protocols are skipped
+    assert_eq!(negotiate_wire_protocol(&handshake(&["bearer.secret, wms.json.v2, wms.msgpack.v1, wms.json.v1"])), Some(WireProtocol::MessagePackV1));
+    assert_eq!(negotiate_wire_protocol(&handshake(&["bearer.secret", "wms.json.v1"])), Some(WireProtocol::JsonV1));
+    assert_eq!(negotiate_wire_protocol(&handshake(&["bearer.secret, chat"])), None);
+  }
+
+  #[test]
+  fn bearer_token_protocol_is_echoed() {
+    let inspection: HeaderInspection = handle_websocket_header_inspection(&handshake(&["wms.json.v1, bearer.secret"]));
+    assert_eq!(inspection.access_token.as_deref(), Some("secret"));
+    assert_eq!(inspection.protocol.as_deref(), Some("bearer.secret"));
+  }
+}
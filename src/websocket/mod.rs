@@ -1 +1,3 @@
-pub mod core;
\ No newline at end of file
+pub mod core;
+pub mod presence;
+pub mod protocol;
\ No newline at end of file
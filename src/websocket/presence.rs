@@ -0,0 +1,134 @@
+use std::{env, sync::OnceLock, time::Duration};
+use rocket::time::PrimitiveDateTime;
+use sqlx::{Pool, Postgres};
+use crate::{auth::now_utc, types::WebSocketManager, websocket::protocol::Envelope};
+
+
+/// How the server keeps track of which devices are online.
+pub struct PresenceConfig {
+  /// How often connected clients are pinged
+  pub heartbeat_interval: Duration,
+  /// How long a client can stay silent, pongs included, before its connection is dropped
+  pub heartbeat_timeout: Duration,
+  /// How long a disconnected device has to come back before users are told it's offline
+  pub offline_grace: Duration
+}
+
+static PRESENCE_CONFIG: OnceLock<PresenceConfig> = OnceLock::new();
+
+fn read_number(name: &str, default: u64) -> u64 {
+  match env::var(name) {
+    Ok(value) => value.parse::<u64>().unwrap_or_else(|_| panic!("{} must be a valid number", name)),
+    Err(_) => default
+  }
+}
+
+/// Presence settings, read once from `DEVICE_HEARTBEAT_INTERVAL_SECS`,
+/// `DEVICE_HEARTBEAT_TIMEOUT_SECS` and `DEVICE_OFFLINE_GRACE_SECS`.
+pub fn presence_config() -> &'static PresenceConfig {
+  PRESENCE_CONFIG.get_or_init(|| {
+    PresenceConfig {
+      heartbeat_interval: Duration::from_secs(read_number("DEVICE_HEARTBEAT_INTERVAL_SECS", 15).max(1)),
+      heartbeat_timeout: Duration::from_secs(read_number("DEVICE_HEARTBEAT_TIMEOUT_SECS", 45).max(1)),
+      offline_grace: Duration::from_secs(read_number("DEVICE_OFFLINE_GRACE_SECS", 10))
+    }
+  })
+}
+
+
+/// Mark every device as offline. No device can be connected before the Web Socket server
+/// has started, so anything still online was left behind by a crash.
+pub async fn reconcile_device_status(pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+  let result = sqlx::query!(
+    "UPDATE devices SET status = FALSE WHERE status = TRUE"
+  )
+  .execute(pool)
+  .await?;
+
+  Ok(result.rows_affected())
+}
+
+/// Mark a device that has just connected as online and let its users know.
+pub async fn mark_device_online(pool: &Pool<Postgres>, ws_manager: &WebSocketManager, device_id: &str) -> Result<(), sqlx::Error> {
+  sqlx::query!(
+    "UPDATE devices SET status = TRUE, last_seen_at = $1 WHERE id = $2",
+    now_utc(),
+    device_id
+  )
+  .execute(pool)
+  .await?;
+
+  if let Err(err) = ws_manager.send_user_message(device_id, &Envelope::status(device_id, true)).await {
+    log::error!("There's an error when trying to update device status to all users through web socket. Error: {}", err);
+  }
+
+  Ok(())
+}
+
+/// Record that a connected device has just been heard from.
+pub async fn touch_device(pool: &Pool<Postgres>, device_id: &str) -> Result<(), sqlx::Error> {
+  sqlx::query!(
+    "UPDATE devices SET last_seen_at = $1 WHERE id = $2",
+    now_utc(),
+    device_id
+  )
+  .execute(pool)
+  .await?;
+
+  Ok(())
+}
+
+/// Mark a device that has disconnected as offline once the grace period has passed, unless
+/// it has come back by then. Users are only told about it if the device is really gone.
+pub async fn mark_device_offline(pool: Pool<Postgres>, ws_manager: WebSocketManager, device_id: String) {
+  // Remember when it left, a reconnection moves this forward
+  let raw_disconnected_at: Result<Option<PrimitiveDateTime>, sqlx::Error> = sqlx::query_scalar!(
+    "UPDATE devices SET last_seen_at = $1 WHERE id = $2 RETURNING last_seen_at",
+    now_utc(),
+    device_id
+  )
+  .fetch_optional(&pool)
+  .await
+  .map(|last_seen_at| last_seen_at.flatten());
+
+  let disconnected_at: PrimitiveDateTime = match raw_disconnected_at {
+    Ok(Some(disconnected_at)) => disconnected_at,
+    // The device has been deleted
+    Ok(None) => {
+      return;
+    },
+    Err(err) => {
+      log::error!("There's an error when trying to update the device last seen time. Error: {}", err);
+      return;
+    }
+  };
+
+  tokio::time::sleep(presence_config().offline_grace).await;
+
+  if ws_manager.is_device_online(&device_id).await {
+    return;
+  }
+
+  let raw_update_device_status = sqlx::query!(
+    "UPDATE devices SET status = FALSE WHERE id = $1 AND status = TRUE AND last_seen_at <= $2",
+    device_id,
+    disconnected_at
+  )
+  .execute(&pool)
+  .await;
+
+  match raw_update_device_status {
+    Ok(res) if res.rows_affected() > 0 => (),
+    Ok(_) => {
+      return;
+    },
+    Err(err) => {
+      log::error!("There's an error when trying to update device status. Error: {}", err);
+      return;
+    }
+  }
+
+  if let Err(err) = ws_manager.send_user_message(&device_id, &Envelope::status(&device_id, false)).await {
+    log::error!("There's an error when trying to update device status to all users through web socket. Error: {}", err);
+  }
+}
@@ -0,0 +1,393 @@
+use rocket::time::PrimitiveDateTime;
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message;
+use crate::{auth::now_utc, telemetry::{from_unix_timestamp, parse_reading, Reading, ReadingValue}};
+
+
+/// The version of the JSON protocol this server speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How a connection wants its messages written, negotiated with the `Sec-WebSocket-Protocol`
+/// header when the connection is opened. Clients that don't ask for one speak the legacy format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum WireProtocol {
+  /// The original text messages, like `temp=<device id>,21.5`, as `wms.legacy`
+  #[default]
+  Legacy,
+  /// JSON envelopes in text frames, as `wms.json.v1`, or after a hello with version 1
+  JsonV1,
+  /// The same envelopes in MessagePack, in binary frames, as `wms.msgpack.v1`
+  MessagePackV1
+}
+
+impl WireProtocol {
+  /// The protocol for a `Sec-WebSocket-Protocol` entry, none if it isn't one this server speaks.
+  pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+    match subprotocol {
+      "wms.legacy" => Some(Self::Legacy),
+      "wms.json.v1" => Some(Self::JsonV1),
+      "wms.msgpack.v1" => Some(Self::MessagePackV1),
+      _ => None
+    }
+  }
+
+  pub fn subprotocol(&self) -> &'static str {
+    match self {
+      Self::Legacy => "wms.legacy",
+      Self::JsonV1 => "wms.json.v1",
+      Self::MessagePackV1 => "wms.msgpack.v1"
+    }
+  }
+}
+
+
+/// A message in the JSON protocol, e.g.
+/// `{"type":"telemetry","id":"42","device_id":"abc","ts":1760770800000,"payload":{"sensor":"temp","value":21.5}}`.
+///
+/// - `type` and `payload` say what the message is, see [`Payload`].
+/// - `id` is chosen by the sender and is optional. Acks and errors carry the id of the message they answer.
+/// - `device_id` is the device the message is about. Devices leave it out, it's always themselves.
+/// - `ts` is when the message was made, in unix milliseconds. For telemetry it's when the device took
+///   the reading, and is left out if the device hasn't said.
+///
+/// Clients that haven't negotiated a protocol speak the legacy text format until they send a
+/// `hello`. Whatever a connection speaks, it can send JSON and legacy text frames, and MessagePack
+/// binary frames. Legacy messages are turned into envelopes as they come in.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Envelope {
+  #[serde(flatten)]
+  pub payload: Payload,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub id: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub device_id: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub ts: Option<i64>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum Payload {
+  /// Switch a legacy connection to the JSON protocol, answered with a hello. Legacy: none.
+  Hello(HelloPayload),
+  /// The token of a client that couldn't send it in the handshake, as its first message. Legacy: `auth=<token>`.
+  Auth(AuthPayload),
+  /// A reading, from a device and relayed to its users. Legacy: `<sensor>=<value>` from the device,
+  /// `<sensor>=<device id>,<value>` to users.
+  Telemetry(TelemetryPayload),
+  /// Whether a device is connected, sent to its users. Legacy: `status=<device id>,<1 or 0>`.
+  Status(StatusPayload),
+  /// A command from a controller, relayed to the device. Legacy: `<key>=<device id>,<value>` from the
+  /// user, `<key>=<value>` to the device.
+  Command(CommandPayload),
+  /// A message with an id has been handled. Legacy: none.
+  Ack(AckPayload),
+  /// A message couldn't be handled. Legacy: `error=<device id>,<code>`, or `error=<code>`.
+  Error(ErrorPayload),
+  /// An unclaimed device asking for a claim code. Legacy: `claim`.
+  ClaimRequest(ClaimRequestPayload),
+  /// The code an unclaimed device shows to be claimed. Legacy: `claim_code=<code>,<expires_in>`.
+  ClaimCode(ClaimCodePayload),
+  /// A claim code couldn't be given, e.g. `already_claimed`. Legacy: `claim_error=<code>`.
+  ClaimError(ErrorPayload),
+  /// The device has been claimed. Legacy: `claimed=1`.
+  Claimed(ClaimedPayload)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HelloPayload {
+  pub version: u32
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuthPayload {
+  pub token: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TelemetryPayload {
+  pub sensor: String,
+  pub value: ReadingValue
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StatusPayload {
+  pub online: bool
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CommandPayload {
+  pub key: String,
+  pub value: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct AckPayload {}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ErrorPayload {
+  /// e.g. `forbidden`, `offline`, `invalid_message`, `unsupported_version`
+  pub code: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ClaimRequestPayload {}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClaimCodePayload {
+  pub code: String,
+  /// Seconds until the code expires
+  pub expires_in: i64
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ClaimedPayload {}
+
+
+/// A time as unix milliseconds, the way `ts` is sent.
+pub fn unix_millis(time: PrimitiveDateTime) -> i64 {
+  (time.assume_utc().unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+impl Envelope {
+  pub fn new(payload: Payload) -> Self {
+    Self { payload, id: None, device_id: None, ts: None }
+  }
+
+  pub fn with_device_id(mut self, device_id: &str) -> Self {
+    self.device_id = Some(device_id.to_string());
+    self
+  }
+
+  pub fn with_ts(mut self, time: PrimitiveDateTime) -> Self {
+    self.ts = Some(unix_millis(time));
+    self
+  }
+
+  /// Tell the users of a device whether it's connected.
+  pub fn status(device_id: &str, online: bool) -> Self {
+    Envelope::new(Payload::Status(StatusPayload { online })).with_device_id(device_id).with_ts(now_utc())
+  }
+
+  /// An ack of the message, none if it has no id to refer to.
+  pub fn ack(&self) -> Option<Envelope> {
+    let id: String = self.id.clone()?;
+    Some(Envelope { payload: Payload::Ack(AckPayload {}), id: Some(id), device_id: self.device_id.clone(), ts: None })
+  }
+
+  /// An error answering the message.
+  pub fn error(&self, code: &str) -> Envelope {
+    Envelope { payload: Payload::Error(ErrorPayload { code: code.to_string() }), id: self.id.clone(), device_id: self.device_id.clone(), ts: None }
+  }
+
+  /// The reading in a telemetry message, none if it isn't valid.
+  pub fn reading(&self) -> Option<Reading> {
+    let Payload::Telemetry(telemetry) = &self.payload else {
+      return None;
+    };
+
+    let device_time: Option<PrimitiveDateTime> = match self.ts {
+      Some(ts) => Some(from_unix_timestamp(ts)?),
+      None => None
+    };
+    Reading::new(&telemetry.sensor, telemetry.value.clone(), device_time)
+  }
+
+  /// Parse a JSON envelope.
+  pub fn from_json(text: &str) -> Result<Self, serde_json::Error> {
+    serde_json::from_str::<Envelope>(text)
+  }
+
+  /// Parse a MessagePack envelope.
+  pub fn from_msgpack(bytes: &[u8]) -> Result<Self, rmp_serde::decode::Error> {
+    rmp_serde::from_slice::<Envelope>(bytes)
+  }
+
+  pub fn to_json(&self) -> Result<String, serde_json::Error> {
+    serde_json::to_string(self)
+  }
+
+  /// Turn a legacy message from a device into an envelope, none if it isn't one.
+  pub fn from_legacy_device_message(text: &str) -> Option<Self> {
+    if text.trim() == "claim" {
+      return Some(Envelope::new(Payload::ClaimRequest(ClaimRequestPayload {})));
+    }
+
+    let reading: Reading = parse_reading(text)?;
+    let mut envelope: Envelope = Envelope::new(Payload::Telemetry(TelemetryPayload { sensor: reading.sensor, value: reading.value }));
+    if let Some(device_time) = reading.device_time {
+      envelope = envelope.with_ts(device_time);
+    }
+
+    Some(envelope)
+  }
+
+  /// Turn a legacy `<key>=<device id>,<value>` command from a user into an envelope, none if it isn't one.
+  pub fn from_legacy_user_message(text: &str) -> Option<Self> {
+    let (key, rest) = text.split_once('=')?;
+    let (device_id, value) = rest.split_once(',')?;
+
+    Some(Envelope::new(Payload::Command(CommandPayload { key: key.to_string(), value: value.to_string() })).with_device_id(device_id))
+  }
+
+  /// Write the message in the legacy text format, none if it has no legacy form.
+  pub fn to_legacy(&self) -> Option<String> {
+    let device_id: &str = self.device_id.as_deref().unwrap_or_default();
+
+    match &self.payload {
+      Payload::Telemetry(telemetry) => {
+        let value: String = match &telemetry.value {
+          ReadingValue::Numeric(number) => number.to_string(),
+          ReadingValue::Text(text) => text.clone()
+        };
+
+        // Legacy users don't expect the time the reading was taken, so it's left out
+        Some(format!("{}={},{}", telemetry.sensor, device_id, value))
+      },
+      Payload::Status(status) => Some(format!("status={},{}", device_id, if status.online { 1 } else { 0 })),
+      // Commands only go out to devices, which don't need to be told their own ID
+      Payload::Command(command) => Some(format!("{}={}", command.key, command.value)),
+      Payload::Error(error) if self.device_id.is_some() => Some(format!("error={},{}", device_id, error.code)),
+      Payload::Error(error) => Some(format!("error={}", error.code)),
+      Payload::ClaimCode(claim_code) => Some(format!("claim_code={},{}", claim_code.code, claim_code.expires_in)),
+      Payload::ClaimError(error) => Some(format!("claim_error={}", error.code)),
+      Payload::Claimed(_) => Some(String::from("claimed=1")),
+      Payload::Auth(auth) => Some(format!("auth={}", auth.token)),
+      Payload::Hello(_) | Payload::Ack(_) | Payload::ClaimRequest(_) => None
+    }
+  }
+
+  /// Write the message as a frame the way a connection wants it, none if it can't be written that way.
+  pub fn encode(&self, protocol: WireProtocol) -> Option<Message> {
+    match protocol {
+      WireProtocol::Legacy => self.to_legacy().map(|text| Message::Text(text.into())),
+      WireProtocol::JsonV1 => match self.to_json() {
+        Ok(json) => Some(Message::Text(json.into())),
+        Err(err) => {
+          log::error!("There's an error when trying to serialize a web socket message. Error: {}", err);
+          None
+        }
+      },
+      // Field names are kept, so the envelope reads the same as in JSON
+      WireProtocol::MessagePackV1 => match rmp_serde::to_vec_named(self) {
+        Ok(bytes) => Some(Message::Binary(bytes.into())),
+        Err(err) => {
+          log::error!("There's an error when trying to serialize a web socket message. Error: {}", err);
+          None
+        }
+      }
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn telemetry(sensor: &str, value: ReadingValue) -> Envelope {
+    Envelope::new(Payload::Telemetry(TelemetryPayload { sensor: sensor.to_string(), value }))
+  }
+
+  fn error(code: &str) -> Payload {
+    Payload::Error(ErrorPayload { code: code.to_string() })
+  }
+
+  #[test]
+  fn envelope_json_round_trip() {
+    let text: &str = r#"{"type":"telemetry","id":"42","device_id":"abc","ts":1760770800000,"payload":{"sensor":"temp","value":21.5}}"#;
+    let envelope: Envelope = Envelope::from_json(text).unwrap();
+
+    let mut expected: Envelope = telemetry("temp", ReadingValue::Numeric(21.5)).with_device_id("abc");
+    expected.id = Some(String::from("42"));
+    expected.ts = Some(1_760_770_800_000);
+    assert_eq!(envelope, expected);
+    assert_eq!(Envelope::from_json(&envelope.to_json().unwrap()).unwrap(), envelope);
+
+    // Fields that aren't set are left out
+    assert_eq!(Envelope::new(Payload::Ack(AckPayload {})).to_json().unwrap(), r#"{"type":"ack","payload":{}}"#);
+
+    assert!(Envelope::from_json(r#"{"type":"unknown","payload":{}}"#).is_err());
+    assert!(Envelope::from_json(r#"{"type":"telemetry","payload":{"sensor":"temp"}}"#).is_err());
+  }
+
+  #[test]
+  fn envelope_msgpack_round_trip() {
+    let mut envelope: Envelope = telemetry("door", ReadingValue::Text(String::from("open"))).with_device_id("abc");
+    envelope.id = Some(String::from("7"));
+
+    let Some(Message::Binary(bytes)) = envelope.encode(WireProtocol::MessagePackV1) else {
+      panic!("MessagePack envelopes should be sent in binary frames");
+    };
+    assert_eq!(Envelope::from_msgpack(&bytes).unwrap(), envelope);
+  }
+
+  #[test]
+  fn to_legacy_of_each_payload() {
+    let numeric: Envelope = telemetry("temp", ReadingValue::Numeric(21.5)).with_device_id("abc");
+    assert_eq!(numeric.to_legacy().as_deref(), Some("temp=abc,21.5"));
+
+    // The time the reading was taken isn't sent to legacy users
+    let mut text: Envelope = telemetry("door", ReadingValue::Text(String::from("open"))).with_device_id("abc");
+    text.ts = Some(1_760_770_800_000);
+    assert_eq!(text.to_legacy().as_deref(), Some("door=abc,open"));
+
+    assert_eq!(Envelope::new(Payload::Status(StatusPayload { online: true })).with_device_id("abc").to_legacy().as_deref(), Some("status=abc,1"));
+    assert_eq!(Envelope::new(Payload::Status(StatusPayload { online: false })).with_device_id("abc").to_legacy().as_deref(), Some("status=abc,0"));
+    assert_eq!(
+      Envelope::new(Payload::Command(CommandPayload { key: String::from("led"), value: String::from("on") })).with_device_id("abc").to_legacy().as_deref(),
+      Some("led=on")
+    );
+    assert_eq!(Envelope::new(error("offline")).with_device_id("abc").to_legacy().as_deref(), Some("error=abc,offline"));
+    assert_eq!(Envelope::new(error("invalid_message")).to_legacy().as_deref(), Some("error=invalid_message"));
+    assert_eq!(
+      Envelope::new(Payload::ClaimCode(ClaimCodePayload { code: String::from("ABCD-EFGH"), expires_in: 600 })).to_legacy().as_deref(),
+      Some("claim_code=ABCD-EFGH,600")
+    );
+    assert_eq!(Envelope::new(Payload::ClaimError(ErrorPayload { code: String::from("already_claimed") })).to_legacy().as_deref(), Some("claim_error=already_claimed"));
+    assert_eq!(Envelope::new(Payload::Claimed(ClaimedPayload {})).to_legacy().as_deref(), Some("claimed=1"));
+    assert_eq!(Envelope::new(Payload::Auth(AuthPayload { token: String::from("secret") })).to_legacy().as_deref(), Some("auth=secret"));
+
+    assert_eq!(Envelope::new(Payload::Hello(HelloPayload { version: PROTOCOL_VERSION })).to_legacy(), None);
+    assert_eq!(Envelope::new(Payload::Ack(AckPayload {})).to_legacy(), None);
+    assert_eq!(Envelope::new(Payload::ClaimRequest(ClaimRequestPayload {})).to_legacy(), None);
+  }
+
+  #[test]
+  fn legacy_device_messages() {
+    assert_eq!(Envelope::from_legacy_device_message("temp=21.5"), Some(telemetry("temp", ReadingValue::Numeric(21.5))));
+    assert_eq!(Envelope::from_legacy_device_message("door=open"), Some(telemetry("door", ReadingValue::Text(String::from("open")))));
+    assert_eq!(Envelope::from_legacy_device_message(" claim "), Some(Envelope::new(Payload::ClaimRequest(ClaimRequestPayload {}))));
+
+    let envelope: Envelope = Envelope::from_legacy_device_message("temp=21.5@1760770800").unwrap();
+    assert_eq!(envelope.ts, Some(1_760_770_800_000));
+    assert_eq!(envelope.reading().unwrap().device_time, from_unix_timestamp(1_760_770_800));
+
+    assert_eq!(Envelope::from_legacy_device_message("hello"), None);
+    assert_eq!(Envelope::from_legacy_device_message("status=1"), None);
+  }
+
+  #[test]
+  fn legacy_user_messages() {
+    assert_eq!(
+      Envelope::from_legacy_user_message("led=abc,on"),
+      Some(Envelope::new(Payload::Command(CommandPayload { key: String::from("led"), value: String::from("on") })).with_device_id("abc"))
+    );
+    // Only the first comma splits the device ID from the value
+    assert_eq!(
+      Envelope::from_legacy_user_message("text=abc,a,b").map(|envelope| envelope.payload),
+      Some(Payload::Command(CommandPayload { key: String::from("text"), value: String::from("a,b") }))
+    );
+
+    assert_eq!(Envelope::from_legacy_user_message("led"), None);
+    assert_eq!(Envelope::from_legacy_user_message("led=on"), None);
+  }
+
+  #[test]
+  fn subprotocols() {
+    for protocol in [WireProtocol::Legacy, WireProtocol::JsonV1, WireProtocol::MessagePackV1] {
+      assert_eq!(WireProtocol::from_subprotocol(protocol.subprotocol()), Some(protocol));
+    }
+    assert_eq!(WireProtocol::from_subprotocol("wms.json.v2"), None);
+  }
+}